@@ -0,0 +1,13 @@
+CREATE TABLE audit_events(
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  actor_id UUID REFERENCES users(id) ON DELETE SET NULL,
+  action TEXT NOT NULL,
+  target TEXT,
+  ip TEXT,
+  user_agent TEXT,
+  details JSONB NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX audit_events_actor_id_created_at on audit_events (actor_id, created_at DESC);
+CREATE INDEX audit_events_action_created_at on audit_events (action, created_at DESC);
@@ -0,0 +1,9 @@
+CREATE TABLE connection_invitations(
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  from_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  email TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  CONSTRAINT unique_invitation_from_email UNIQUE (from_id, email)
+);
+
+CREATE INDEX connection_invitations_email on connection_invitations (email);
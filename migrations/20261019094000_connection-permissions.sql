@@ -0,0 +1,10 @@
+-- existing connections were all-or-nothing, so they keep every permission
+ALTER TABLE user_connection
+  ADD COLUMN permissions TEXT[] NOT NULL DEFAULT ARRAY['view', 'control', 'file_transfer', 'clipboard'],
+  ADD CONSTRAINT user_connection_permissions_check
+    CHECK (permissions <@ ARRAY['view', 'control', 'file_transfer', 'clipboard']);
+
+ALTER TABLE connection_invitations
+  ADD COLUMN permissions TEXT[] NOT NULL DEFAULT ARRAY['view', 'control', 'file_transfer', 'clipboard'],
+  ADD CONSTRAINT connection_invitations_permissions_check
+    CHECK (permissions <@ ARRAY['view', 'control', 'file_transfer', 'clipboard']);
@@ -0,0 +1,7 @@
+-- a connection without rows here still shares every device of its from_id
+CREATE TABLE device_grants(
+  connection_id UUID NOT NULL REFERENCES user_connection(id) ON DELETE CASCADE,
+  device_id TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (connection_id, device_id)
+);
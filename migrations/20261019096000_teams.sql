@@ -0,0 +1,43 @@
+CREATE TABLE teams(
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  name TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE team_members(
+  team_id UUID NOT NULL REFERENCES teams(id) ON DELETE CASCADE,
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  role TEXT NOT NULL DEFAULT 'member',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (team_id, user_id),
+  CONSTRAINT team_members_role_check CHECK (role IN ('owner', 'admin', 'member'))
+);
+
+CREATE INDEX team_members_user_id on team_members (user_id);
+
+CREATE TABLE team_invitations(
+  team_id UUID NOT NULL REFERENCES teams(id) ON DELETE CASCADE,
+  email TEXT NOT NULL,
+  role TEXT NOT NULL DEFAULT 'member',
+  invited_by UUID REFERENCES users(id) ON DELETE SET NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (team_id, email),
+  CONSTRAINT team_invitations_role_check CHECK (role IN ('admin', 'member'))
+);
+
+CREATE INDEX team_invitations_email on team_invitations (email);
+
+-- a member's device shared with the whole team; leaving the team drops its grants
+CREATE TABLE team_device_grants(
+  team_id UUID NOT NULL,
+  owner_id UUID NOT NULL,
+  device_id TEXT NOT NULL,
+  permissions TEXT[] NOT NULL DEFAULT ARRAY['view', 'control', 'file_transfer', 'clipboard'],
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (team_id, owner_id, device_id),
+  FOREIGN KEY (team_id, owner_id) REFERENCES team_members(team_id, user_id) ON DELETE CASCADE,
+  CONSTRAINT team_device_grants_permissions_check
+    CHECK (permissions <@ ARRAY['view', 'control', 'file_transfer', 'clipboard'])
+);
+
+CREATE INDEX team_device_grants_owner_id on team_device_grants (owner_id);
@@ -0,0 +1,31 @@
+-- share:  to_id may access from_id's devices (how every existing connection behaves)
+-- access: from_id may access to_id's devices
+-- mutual: both ways
+ALTER TABLE user_connection
+  ADD COLUMN mode TEXT NOT NULL DEFAULT 'share',
+  ADD COLUMN reverse_permissions TEXT[] NOT NULL DEFAULT ARRAY['view', 'control', 'file_transfer', 'clipboard'],
+  ADD CONSTRAINT user_connection_mode_check CHECK (mode IN ('share', 'access', 'mutual')),
+  ADD CONSTRAINT user_connection_reverse_permissions_check
+    CHECK (reverse_permissions <@ ARRAY['view', 'control', 'file_transfer', 'clipboard']);
+
+ALTER TABLE connection_invitations
+  ADD COLUMN mode TEXT NOT NULL DEFAULT 'share',
+  ADD CONSTRAINT connection_invitations_mode_check CHECK (mode IN ('share', 'access', 'mutual'));
+
+-- grants can now limit either side's devices
+ALTER TABLE device_grants ADD COLUMN owner_id UUID REFERENCES users(id) ON DELETE CASCADE;
+UPDATE device_grants dg SET owner_id = uc.from_id FROM user_connection uc WHERE dg.connection_id = uc.id;
+ALTER TABLE device_grants
+  ALTER COLUMN owner_id SET NOT NULL,
+  DROP CONSTRAINT device_grants_pkey,
+  ADD PRIMARY KEY (connection_id, owner_id, device_id);
+
+-- one row per direction in which an accepted connection gives access
+CREATE VIEW connection_access AS
+  SELECT id AS connection_id, from_id AS owner_id, to_id AS grantee_id, permissions
+  FROM user_connection
+  WHERE is_accepted AND mode IN ('share', 'mutual')
+  UNION ALL
+  SELECT id AS connection_id, to_id AS owner_id, from_id AS grantee_id, reverse_permissions AS permissions
+  FROM user_connection
+  WHERE is_accepted AND mode IN ('access', 'mutual');
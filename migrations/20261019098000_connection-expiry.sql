@@ -0,0 +1,40 @@
+-- optional end date and daily access window (UTC) for connections and invitations
+ALTER TABLE user_connection
+  ADD COLUMN expires_at TIMESTAMPTZ,
+  ADD COLUMN window_start TIME,
+  ADD COLUMN window_end TIME,
+  ADD CONSTRAINT user_connection_window_check CHECK ((window_start IS NULL) = (window_end IS NULL));
+
+CREATE INDEX user_connection_expires_at on user_connection (expires_at) WHERE expires_at IS NOT NULL;
+
+ALTER TABLE connection_invitations
+  ADD COLUMN expires_at TIMESTAMPTZ,
+  ADD COLUMN window_start TIME,
+  ADD COLUMN window_end TIME,
+  ADD CONSTRAINT connection_invitations_window_check CHECK ((window_start IS NULL) = (window_end IS NULL));
+
+-- a window whose end is before its start runs past midnight
+CREATE FUNCTION connection_active(expires_at TIMESTAMPTZ, window_start TIME, window_end TIME)
+RETURNS BOOLEAN LANGUAGE SQL STABLE AS $$
+  SELECT (expires_at IS NULL OR expires_at > NOW())
+    AND (
+      window_start IS NULL
+      OR CASE
+        WHEN window_start <= window_end
+          THEN (NOW() AT TIME ZONE 'UTC')::time BETWEEN window_start AND window_end
+        ELSE (NOW() AT TIME ZONE 'UTC')::time >= window_start
+          OR (NOW() AT TIME ZONE 'UTC')::time < window_end
+      END
+    )
+$$;
+
+CREATE OR REPLACE VIEW connection_access AS
+  SELECT id AS connection_id, from_id AS owner_id, to_id AS grantee_id, permissions
+  FROM user_connection
+  WHERE is_accepted AND mode IN ('share', 'mutual')
+  AND connection_active(expires_at, window_start, window_end)
+  UNION ALL
+  SELECT id AS connection_id, to_id AS owner_id, from_id AS grantee_id, reverse_permissions AS permissions
+  FROM user_connection
+  WHERE is_accepted AND mode IN ('access', 'mutual')
+  AND connection_active(expires_at, window_start, window_end);
@@ -0,0 +1,19 @@
+-- single-use access to one device for someone without an account
+CREATE TABLE guest_links(
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  device_id TEXT NOT NULL,
+  token TEXT NOT NULL UNIQUE,
+  code TEXT NOT NULL,
+  permissions TEXT[] NOT NULL DEFAULT ARRAY['view'],
+  expires_at TIMESTAMPTZ NOT NULL,
+  used_at TIMESTAMPTZ,
+  revoked_at TIMESTAMPTZ,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  CONSTRAINT guest_links_permissions_check
+    CHECK (permissions <@ ARRAY['view', 'control', 'file_transfer', 'clipboard'])
+);
+
+CREATE INDEX guest_links_owner_id on guest_links (owner_id);
+-- codes are short, so they only need to be unique among links that can still be redeemed
+CREATE UNIQUE INDEX guest_links_open_code on guest_links (code) WHERE used_at IS NULL AND revoked_at IS NULL;
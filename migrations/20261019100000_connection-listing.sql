@@ -0,0 +1,10 @@
+ALTER TABLE user_connection
+  ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  ADD COLUMN accepted_at TIMESTAMPTZ;
+
+UPDATE user_connection SET accepted_at = created_at WHERE is_accepted;
+
+CREATE INDEX user_connection_from_id_created on user_connection (from_id, created_at, id);
+CREATE INDEX user_connection_to_id_created on user_connection (to_id, created_at, id);
+
+ALTER TABLE users ADD COLUMN display_name TEXT;
@@ -0,0 +1,16 @@
+CREATE TABLE contact_nicknames(
+  owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  contact_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  nickname TEXT NOT NULL,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (owner_id, contact_id)
+);
+
+CREATE TABLE device_nicknames(
+  owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  device_owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  device_id TEXT NOT NULL,
+  nickname TEXT NOT NULL,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (owner_id, device_owner_id, device_id)
+);
@@ -0,0 +1,10 @@
+CREATE TABLE turn_usage(
+  user_email TEXT NOT NULL,
+  day DATE NOT NULL,
+  bytes_to_peer BIGINT NOT NULL DEFAULT 0,
+  bytes_from_peer BIGINT NOT NULL DEFAULT 0,
+  allocations BIGINT NOT NULL DEFAULT 0,
+  PRIMARY KEY (user_email, day)
+);
+
+CREATE INDEX idx_turn_usage_day ON turn_usage(day);
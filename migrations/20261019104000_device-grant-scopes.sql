@@ -0,0 +1,12 @@
+-- whether an owner shares every device on a connection; without a row it does, with
+-- all_devices = false only the devices in device_grants are shared, possibly none
+CREATE TABLE device_grant_scopes(
+  connection_id UUID NOT NULL REFERENCES user_connection(id) ON DELETE CASCADE,
+  owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  all_devices BOOLEAN NOT NULL,
+  PRIMARY KEY (connection_id, owner_id)
+);
+
+-- grants set so far were limits
+INSERT INTO device_grant_scopes (connection_id, owner_id, all_devices)
+SELECT DISTINCT connection_id, owner_id, false FROM device_grants;
@@ -0,0 +1,29 @@
+-- windows are [start, end): access ends at window_end in both the same day and the
+-- past midnight case
+CREATE OR REPLACE FUNCTION connection_active(expires_at TIMESTAMPTZ, window_start TIME, window_end TIME)
+RETURNS BOOLEAN LANGUAGE SQL STABLE AS $$
+  SELECT (expires_at IS NULL OR expires_at > NOW())
+    AND (
+      window_start IS NULL
+      OR CASE
+        WHEN window_start < window_end
+          THEN (NOW() AT TIME ZONE 'UTC')::time >= window_start
+            AND (NOW() AT TIME ZONE 'UTC')::time < window_end
+        ELSE (NOW() AT TIME ZONE 'UTC')::time >= window_start
+          OR (NOW() AT TIME ZONE 'UTC')::time < window_end
+      END
+    )
+$$;
+
+-- the last time the daily window ended, today or yesterday
+CREATE FUNCTION connection_window_closed_at(window_end TIME)
+RETURNS TIMESTAMPTZ LANGUAGE SQL STABLE AS $$
+  SELECT CASE
+    WHEN (NOW() AT TIME ZONE 'UTC')::time >= window_end
+      THEN ((NOW() AT TIME ZONE 'UTC')::date + window_end) AT TIME ZONE 'UTC'
+    ELSE ((NOW() AT TIME ZONE 'UTC')::date - 1 + window_end) AT TIME ZONE 'UTC'
+  END
+$$;
+
+-- when the users were last told that the window closed, so each close is reported once
+ALTER TABLE user_connection ADD COLUMN window_notified_at TIMESTAMPTZ;
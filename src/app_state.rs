@@ -1,12 +1,15 @@
 use axum::extract::ws::Message;
 use serde_json::Value;
 use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, Mutex, RwLock};
 
 use bb8_redis::RedisConnectionManager;
 use sqlx::PgPool;
 
-use crate::utils::{mail_service::mailer::Mailer, tera_service::tera_renderer::TeraRenderer};
+use crate::{
+    routes::socket::backpressure::SocketHandle,
+    utils::{mail_service::mailer::Mailer, tera_service::tera_renderer::TeraRenderer},
+};
 type RedisPool = bb8::Pool<RedisConnectionManager>;
 
 pub type Tx = mpsc::Sender<Message>;
@@ -21,10 +24,14 @@ pub struct AppState {
     pub user_index: Arc<RwLock<HashMap<String, HashMap<String, Value>>>>,
     // email_device_key -> tx (for backward compatibility during migration)
     pub socket_connections: Arc<RwLock<HashMap<String, Tx>>>,
-    // socket_id -> tx (new mapping)
-    pub socket_id_to_connection: Arc<RwLock<HashMap<String, Tx>>>,
+    // socket_id -> tx and its queue (new mapping)
+    pub socket_id_to_connection: Arc<RwLock<HashMap<String, SocketHandle>>>,
     // email -> { device_id -> socket_id }
     pub email_device_to_socket: Arc<RwLock<HashMap<String, HashMap<String, String>>>>,
+    // tells other pods which pod published a message, for delivery confirmations
+    pub pod_id: String,
+    // message_id -> sender waiting for another pod to confirm delivery
+    pub pending_deliveries: Arc<Mutex<HashMap<String, mpsc::Sender<bool>>>>,
 }
 
 impl AppState {
@@ -0,0 +1,175 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::prelude::FromRow;
+use uuid::Uuid;
+
+use crate::{app_state::AppState, utils::client_info::ClientInfo};
+
+pub const LOGIN: &str = "login";
+pub const LOGIN_FAILED: &str = "login_failed";
+pub const SIGNUP_REQUESTED: &str = "signup_requested";
+pub const SIGNUP_EMAIL_FAILED: &str = "signup_email_failed";
+pub const INVITE_EMAIL_FAILED: &str = "invite_email_failed";
+pub const PASSWORD_SETUP: &str = "password_setup";
+pub const CONNECTION_REQUEST: &str = "connection_request";
+pub const CONNECTION_INVITE: &str = "connection_invite";
+pub const CONNECTION_ACCEPT: &str = "connection_accept";
+pub const CONNECTION_REJECT: &str = "connection_reject";
+pub const CONNECTION_CANCEL: &str = "connection_cancel";
+pub const CONNECTION_REMOVE: &str = "connection_remove";
+pub const CONNECTION_PERMISSIONS: &str = "connection_permissions";
+pub const CONNECTION_EXPIRED: &str = "connection_expired";
+pub const DEVICE_GRANTS: &str = "device_grants";
+pub const DEVICE_PASSWORD: &str = "device_password";
+pub const USER_BLOCK: &str = "user_block";
+pub const USER_UNBLOCK: &str = "user_unblock";
+pub const SOCKET_REGISTER: &str = "socket_register";
+pub const SOCKET_REGISTER_FAILED: &str = "socket_register_failed";
+pub const SIGNALING_SESSION: &str = "signaling_session";
+pub const SESSION_PIN_FAILED: &str = "session_pin_failed";
+pub const SESSION_PIN_LOCKOUT: &str = "session_pin_lockout";
+pub const ADMIN_ACTION: &str = "admin_action";
+pub const TEAM_ACTION: &str = "team_action";
+pub const GUEST_LINK_CREATE: &str = "guest_link_create";
+pub const GUEST_LINK_REVOKE: &str = "guest_link_revoke";
+pub const GUEST_LINK_REDEEM: &str = "guest_link_redeem";
+pub const GUEST_LINK_REDEEM_FAILED: &str = "guest_link_redeem_failed";
+pub const PAIRING_CODE_ISSUE: &str = "pairing_code_issue";
+pub const PAIRING_CODE_REDEEM: &str = "pairing_code_redeem";
+pub const PAIRING_CODE_REDEEM_FAILED: &str = "pairing_code_redeem_failed";
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    pub actor_id: Option<Uuid>,
+    pub action: String,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub actor_id: Option<Uuid>,
+    pub actions: Option<Vec<String>>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+// an event waiting to be written; the actor can be given by id or resolved from an email
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    actor_id: Option<Uuid>,
+    actor_email: Option<String>,
+    action: String,
+    target: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    details: Value,
+}
+
+impl AuditEntry {
+    pub fn new(action: &str) -> Self {
+        Self {
+            actor_id: None,
+            actor_email: None,
+            action: action.to_string(),
+            target: None,
+            ip: None,
+            user_agent: None,
+            details: serde_json::json!({}),
+        }
+    }
+
+    pub fn actor(mut self, actor_id: Uuid) -> Self {
+        self.actor_id = Some(actor_id);
+        self
+    }
+
+    pub fn actor_email(mut self, email: &str) -> Self {
+        self.actor_email = Some(email.to_string());
+        self
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn client(mut self, client: &ClientInfo) -> Self {
+        self.ip = client.ip.clone();
+        self.user_agent = client.user_agent.clone();
+        self
+    }
+
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = details;
+        self
+    }
+
+    // audit writes never hold up or fail the request that triggered them
+    pub fn record(self, app_state: &AppState) {
+        let app_state = app_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = AuditEvent::create(self.clone(), app_state).await {
+                eprintln!("Failed to record audit event {:?}: {}", self, e);
+            }
+        });
+    }
+}
+
+impl AuditEvent {
+    pub async fn create(entry: AuditEntry, app_state: AppState) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO audit_events (actor_id, action, target, ip, user_agent, details)
+            VALUES (
+                COALESCE($1, (SELECT id FROM users WHERE email = $2)),
+                $3, $4, $5, $6, $7
+            )
+            "#,
+            entry.actor_id,
+            entry.actor_email,
+            entry.action,
+            entry.target,
+            entry.ip,
+            entry.user_agent,
+            entry.details
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn list(
+        filter: AuditFilter,
+        app_state: AppState,
+    ) -> Result<Vec<AuditEvent>, sqlx::Error> {
+        sqlx::query_as!(
+            AuditEvent,
+            r#"
+            SELECT id, actor_id, action, target, ip, user_agent, details, created_at
+            FROM audit_events
+            WHERE ($1::uuid IS NULL OR actor_id = $1)
+            AND ($2::text[] IS NULL OR action = ANY($2))
+            AND ($3::timestamptz IS NULL OR created_at >= $3)
+            AND ($4::timestamptz IS NULL OR created_at < $4)
+            ORDER BY created_at DESC
+            LIMIT $5 OFFSET $6
+            "#,
+            filter.actor_id,
+            filter.actions.as_deref(),
+            filter.from,
+            filter.to,
+            filter.limit,
+            filter.offset
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+}
@@ -0,0 +1,78 @@
+use uuid::Uuid;
+
+use crate::{app_state::AppState, db::models::user_connection::RequestTerms};
+
+// a connection request addressed to an email that has no account yet
+pub struct ConnectionInvitation;
+
+impl ConnectionInvitation {
+    // re-inviting the same email just refreshes the invitation
+    pub async fn create(
+        from_id: Uuid,
+        email: String,
+        terms: RequestTerms,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO connection_invitations
+                (from_id, email, mode, permissions, expires_at, window_start, window_end)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT ON CONSTRAINT unique_invitation_from_email
+            DO UPDATE SET
+                created_at = NOW(),
+                mode = EXCLUDED.mode,
+                permissions = EXCLUDED.permissions,
+                expires_at = EXCLUDED.expires_at,
+                window_start = EXCLUDED.window_start,
+                window_end = EXCLUDED.window_end
+            "#,
+            from_id,
+            email,
+            terms.mode,
+            &terms.permissions,
+            terms.expires_at,
+            terms.window_start,
+            terms.window_end
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        Ok(())
+    }
+
+    // called once the invitee has an account; pending invitations become regular requests
+    pub async fn convert_to_requests(
+        email: String,
+        app_state: AppState,
+    ) -> Result<u64, sqlx::Error> {
+        let mut tx = app_state.pg_pool.begin().await?;
+        let result = sqlx::query!(
+            r#"
+            WITH invitations AS (
+                DELETE FROM connection_invitations WHERE email = $1
+                RETURNING from_id, mode, permissions, expires_at, window_start, window_end
+            )
+            INSERT INTO user_connection
+                (from_id, to_id, is_accepted, mode, permissions, expires_at, window_start, window_end)
+            SELECT
+                i.from_id, u.id, false, i.mode, i.permissions,
+                i.expires_at, i.window_start, i.window_end
+            FROM invitations i
+            JOIN users u ON u.email = $1
+            ON CONFLICT ON CONSTRAINT unique_from_to DO NOTHING
+            "#,
+            email
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn delete_expired(app_state: &AppState) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!("DELETE FROM connection_invitations WHERE expires_at <= NOW()")
+            .execute(&app_state.pg_pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+use uuid::Uuid;
+
+use crate::app_state::AppState;
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct DeviceGrantView {
+    pub grantee_email: String,
+    pub all_devices: bool,
+    pub device_ids: Vec<String>,
+}
+
+// limits which of the owner's devices a connected user may see and reach; a connection
+// shares every device until the owner sets a scope for it
+pub struct DeviceGrant;
+
+impl DeviceGrant {
+    // replaces the whole scope: every device, or only the listed ones, so an empty
+    // list shares none
+    pub async fn set(
+        owner_id: Uuid,
+        grantee_email: String,
+        all_devices: bool,
+        device_ids: Vec<String>,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = app_state.pg_pool.begin().await?;
+        // every connection, pending or not, through which the owner's devices are shared
+        let connection_ids = sqlx::query_scalar!(
+            r#"
+            SELECT uc.id
+            FROM user_connection uc
+            JOIN users g ON g.email = $2
+            WHERE (uc.from_id = $1 AND uc.to_id = g.id AND uc.mode IN ('share', 'mutual'))
+            OR (uc.to_id = $1 AND uc.from_id = g.id AND uc.mode IN ('access', 'mutual'))
+            "#,
+            owner_id,
+            grantee_email
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        if connection_ids.is_empty() {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        sqlx::query!(
+            "DELETE FROM device_grants WHERE connection_id = ANY($1) AND owner_id = $2",
+            &connection_ids,
+            owner_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO device_grant_scopes (connection_id, owner_id, all_devices)
+            SELECT connection_id, $2, $3
+            FROM UNNEST($1::uuid[]) AS connection_id
+            ON CONFLICT (connection_id, owner_id) DO UPDATE SET all_devices = EXCLUDED.all_devices
+            "#,
+            &connection_ids,
+            owner_id,
+            all_devices
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO device_grants (connection_id, owner_id, device_id)
+            SELECT connection_id, $2, device_id
+            FROM UNNEST($1::uuid[]) AS connection_id
+            CROSS JOIN UNNEST($3::text[]) AS device_id
+            ON CONFLICT DO NOTHING
+            "#,
+            &connection_ids,
+            owner_id,
+            &device_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn list(
+        owner_id: Uuid,
+        app_state: AppState,
+    ) -> Result<Vec<DeviceGrantView>, sqlx::Error> {
+        sqlx::query_as!(
+            DeviceGrantView,
+            r#"
+            SELECT
+                u.email AS grantee_email,
+                bool_and(s.all_devices) AS "all_devices!",
+                array_remove(array_agg(DISTINCT dg.device_id), NULL) AS "device_ids!"
+            FROM device_grant_scopes s
+            JOIN user_connection uc ON s.connection_id = uc.id
+            JOIN users u ON u.id = CASE WHEN uc.from_id = $1 THEN uc.to_id ELSE uc.from_id END
+            LEFT JOIN device_grants dg
+                ON dg.connection_id = s.connection_id AND dg.owner_id = s.owner_id
+            WHERE s.owner_id = $1
+            GROUP BY u.email
+            ORDER BY u.email
+            "#,
+            owner_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    // owner email -> devices the grantee is limited to, only for owners that limited it;
+    // an empty list means none
+    pub async fn granted_devices(
+        grantee_id: Uuid,
+        app_state: &AppState,
+    ) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT
+                u.email AS owner_email,
+                array_remove(array_agg(DISTINCT dg.device_id), NULL) AS "device_ids!"
+            FROM device_grant_scopes s
+            JOIN user_connection uc ON s.connection_id = uc.id
+            JOIN users u ON s.owner_id = u.id
+            LEFT JOIN device_grants dg
+                ON dg.connection_id = s.connection_id AND dg.owner_id = s.owner_id
+            WHERE (uc.from_id = $1 OR uc.to_id = $1) AND s.owner_id <> $1 AND NOT s.all_devices
+            GROUP BY u.email
+            "#,
+            grantee_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.owner_email, row.device_ids))
+            .collect())
+    }
+
+    pub async fn is_device_allowed(
+        owner_email: &str,
+        grantee_email: &str,
+        device_id: &str,
+        app_state: &AppState,
+    ) -> Result<bool, sqlx::Error> {
+        let allowed = sqlx::query_scalar!(
+            r#"
+            SELECT NOT EXISTS (
+                SELECT 1
+                FROM device_grant_scopes s
+                JOIN user_connection uc ON s.connection_id = uc.id
+                JOIN users o ON s.owner_id = o.id
+                JOIN users g ON g.email = $2
+                WHERE o.email = $1 AND (uc.from_id = g.id OR uc.to_id = g.id)
+                AND NOT s.all_devices
+                AND NOT EXISTS (
+                    SELECT 1 FROM device_grants dg
+                    WHERE dg.connection_id = s.connection_id
+                    AND dg.owner_id = s.owner_id
+                    AND dg.device_id = $3
+                )
+            ) AS "allowed!"
+            "#,
+            owner_email,
+            grantee_email,
+            device_id
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await?;
+        Ok(allowed)
+    }
+}
@@ -0,0 +1,96 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+use uuid::Uuid;
+
+use crate::app_state::AppState;
+
+pub const MIN_DEVICE_PASSWORD_LENGTH: usize = 6;
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct DevicePasswordView {
+    pub device_id: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+// a standing access password for an unattended device, asked for instead of a session PIN
+pub struct DevicePassword;
+
+impl DevicePassword {
+    // None removes the password
+    pub async fn set(
+        owner_id: Uuid,
+        device_id: String,
+        password_hash: Option<String>,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        match password_hash {
+            Some(password_hash) => {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO device_passwords (owner_id, device_id, password_hash)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (owner_id, device_id)
+                    DO UPDATE SET password_hash = EXCLUDED.password_hash, updated_at = NOW()
+                    "#,
+                    owner_id,
+                    device_id,
+                    password_hash
+                )
+                .execute(&app_state.pg_pool)
+                .await?;
+            }
+            None => {
+                let result = sqlx::query!(
+                    "DELETE FROM device_passwords WHERE owner_id = $1 AND device_id = $2",
+                    owner_id,
+                    device_id
+                )
+                .execute(&app_state.pg_pool)
+                .await?;
+                if result.rows_affected() == 0 {
+                    return Err(sqlx::Error::RowNotFound);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // only says which devices have one, the hashes never leave the server
+    pub async fn list(
+        owner_id: Uuid,
+        app_state: AppState,
+    ) -> Result<Vec<DevicePasswordView>, sqlx::Error> {
+        sqlx::query_as!(
+            DevicePasswordView,
+            r#"
+            SELECT device_id, updated_at
+            FROM device_passwords
+            WHERE owner_id = $1
+            ORDER BY device_id
+            "#,
+            owner_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    pub async fn hash_for(
+        owner_email: &str,
+        device_id: &str,
+        app_state: &AppState,
+    ) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT dp.password_hash
+            FROM device_passwords dp
+            JOIN users u ON dp.owner_id = u.id
+            WHERE u.email = $1 AND dp.device_id = $2
+            "#,
+            owner_email,
+            device_id
+        )
+        .fetch_optional(&app_state.pg_pool)
+        .await
+    }
+}
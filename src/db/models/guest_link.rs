@@ -0,0 +1,153 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    utils::hash_service::hash_generator::{generate_hash, generate_numeric_code},
+};
+
+pub const GUEST_CODE_LENGTH: usize = 8;
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct GuestLinkView {
+    pub id: Uuid,
+    pub device_id: String,
+    pub permissions: Vec<String>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+// the secrets are only handed out once, right after creation
+#[derive(Debug, Serialize)]
+pub struct CreatedGuestLink {
+    pub id: Uuid,
+    pub token: String,
+    pub code: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+// what a redeemed link lets the guest reach
+#[derive(Debug, Clone, FromRow)]
+pub struct GuestGrant {
+    pub link_id: Uuid,
+    pub owner_email: String,
+    pub device_id: String,
+    pub permissions: Vec<String>,
+    // the guest's session ends with the link
+    pub expires_at: DateTime<Utc>,
+}
+
+pub struct GuestLink;
+
+impl GuestLink {
+    pub async fn create(
+        owner_id: Uuid,
+        device_id: String,
+        permissions: Vec<String>,
+        expires_at: DateTime<Utc>,
+        app_state: AppState,
+    ) -> Result<CreatedGuestLink, sqlx::Error> {
+        let token = generate_hash();
+        // a fresh code is drawn if it clashes with another open link
+        let mut attempts = 0;
+        loop {
+            let code = generate_numeric_code(GUEST_CODE_LENGTH);
+            let result = sqlx::query_scalar!(
+                r#"
+                INSERT INTO guest_links (owner_id, device_id, token, code, permissions, expires_at)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                RETURNING id
+                "#,
+                owner_id,
+                device_id,
+                token,
+                code,
+                &permissions,
+                expires_at
+            )
+            .fetch_one(&app_state.pg_pool)
+            .await;
+            match result {
+                Ok(id) => {
+                    return Ok(CreatedGuestLink {
+                        id,
+                        token,
+                        code,
+                        expires_at,
+                    });
+                }
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() && attempts < 5 => {
+                    attempts += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub async fn list(
+        owner_id: Uuid,
+        app_state: AppState,
+    ) -> Result<Vec<GuestLinkView>, sqlx::Error> {
+        sqlx::query_as!(
+            GuestLinkView,
+            r#"
+            SELECT id, device_id, permissions, expires_at, used_at, revoked_at, created_at
+            FROM guest_links
+            WHERE owner_id = $1
+            ORDER BY created_at DESC
+            "#,
+            owner_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    pub async fn revoke(
+        owner_id: Uuid,
+        link_id: Uuid,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE guest_links
+            SET revoked_at = NOW()
+            WHERE id = $1 AND owner_id = $2 AND revoked_at IS NULL
+            "#,
+            link_id,
+            owner_id
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    // marks the link used, so the token or code works exactly once
+    pub async fn redeem(secret: &str, app_state: &AppState) -> Result<GuestGrant, sqlx::Error> {
+        sqlx::query_as!(
+            GuestGrant,
+            r#"
+            UPDATE guest_links g
+            SET used_at = NOW()
+            FROM users u
+            WHERE u.id = g.owner_id
+            AND (g.token = $1 OR g.code = $1)
+            AND g.used_at IS NULL
+            AND g.revoked_at IS NULL
+            AND g.expires_at > NOW()
+            AND u.suspended_at IS NULL
+            RETURNING g.id AS link_id, u.email AS owner_email, g.device_id, g.permissions,
+                g.expires_at
+            "#,
+            secret
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await
+    }
+}
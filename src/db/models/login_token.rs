@@ -4,6 +4,7 @@ use uuid::Uuid;
 
 use crate::app_state::AppState;
 
+#[allow(dead_code)]
 pub struct LoginToken {
     id: Uuid,
     user_id: Uuid,
@@ -12,17 +13,24 @@ pub struct LoginToken {
 }
 
 impl LoginToken {
+    // keep in sync with the expires_at interval used in `create`
+    pub const TTL_SECONDS: u64 = 16 * 60 * 60;
+
+    // cached no longer than the token stays valid
     async fn cache_token(
         token_id: Uuid,
         user_id: Uuid,
+        ttl_seconds: u64,
         app_state: AppState,
     ) -> Result<(), RedisError> {
-        let key = format!("login_token:{}", token_id.clone());
-        let mut redis_connetion = app_state.redis_pool.get().await.unwrap();
-        let res = redis_connetion
-            .set_ex(key, user_id.to_string(), 24 * 60 * 60)
-            .await;
-        res
+        let key = format!("login_token:{}", token_id);
+        let mut redis_connetion =
+            app_state.redis_pool.get().await.map_err(|e| {
+                RedisError::from((redis::ErrorKind::Io, "pool error", e.to_string()))
+            })?;
+        redis_connetion
+            .set_ex(key, user_id.to_string(), ttl_seconds)
+            .await
     }
 
     pub async fn create(user_id: Uuid, app_state: AppState) -> Result<Uuid, sqlx::Error> {
@@ -38,27 +46,67 @@ impl LoginToken {
         .fetch_one(&mut *tx)
         .await;
         let token_id = rec.unwrap().id;
-        let _ = LoginToken::cache_token(token_id, user_id, app_state);
+        if let Err(e) =
+            LoginToken::cache_token(token_id, user_id, LoginToken::TTL_SECONDS, app_state).await
+        {
+            eprintln!("Failed to cache login token: {}", e);
+        }
         let _ = tx.commit().await;
 
         Ok(token_id)
     }
+    // a Redis problem counts as a cache miss, Postgres still answers
     pub async fn get_user_id(token_id: Uuid, app_state: AppState) -> Result<String, sqlx::Error> {
-        let key = format!("login_token:{}", token_id.clone());
-        let mut redis_connection = app_state.redis_pool.get().await.unwrap();
-        let cached_user_id: Option<String> = redis_connection.get(&key).await.unwrap();
+        let key = format!("login_token:{}", token_id);
+        let cached_user_id: Option<String> = match app_state.redis_pool.get().await {
+            Ok(mut redis_connection) => redis_connection.get(&key).await.unwrap_or(None),
+            Err(_) => None,
+        };
         if let Some(id) = cached_user_id {
-            Ok(id)
-        } else {
-            let mut tx = app_state.clone().pg_pool.begin().await?;
-            let row = sqlx::query!("SELECT user_id FROM user_tokens WHERE id = $1", token_id)
-                .fetch_one(&mut *tx)
-                .await?;
-            let _ = LoginToken::cache_token(token_id, row.user_id.clone(), app_state.clone())
+            return Ok(id);
+        }
+
+        let row = sqlx::query!(
+            "SELECT user_id, expires_at FROM user_tokens WHERE id = $1 AND expires_at > NOW()",
+            token_id
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await?;
+        let remaining = (row.expires_at - Utc::now()).num_seconds();
+        if remaining > 0
+            && let Err(e) =
+                LoginToken::cache_token(token_id, row.user_id, remaining as u64, app_state).await
+        {
+            eprintln!("Failed to cache login token: {}", e);
+        }
+        Ok(row.user_id.to_string())
+    }
+
+    // deletes every token of the user and evicts them from the cache
+    pub async fn revoke_all(user_id: Uuid, app_state: AppState) -> Result<u64, sqlx::Error> {
+        let token_ids = sqlx::query_scalar!(
+            "DELETE FROM user_tokens WHERE user_id = $1 RETURNING id",
+            user_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await?;
+
+        if !token_ids.is_empty() {
+            let keys: Vec<String> = token_ids
+                .iter()
+                .map(|id| format!("login_token:{}", id))
+                .collect();
+            let mut redis_connection = app_state
+                .redis_pool
+                .get()
+                .await
+                .map_err(|_| sqlx::Error::PoolClosed)?;
+            let _: () = redis_connection
+                .del(keys)
                 .await
-                .unwrap();
-            let _ = tx.commit().await;
-            Ok(row.user_id.to_string())
+                .map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
         }
+
+        Ok(token_ids.len() as u64)
     }
 }
@@ -1,3 +1,15 @@
+pub mod audit_event;
+pub mod connection_invitation;
+pub mod device_grant;
+pub mod device_password;
+pub mod guest_link;
 pub mod login_token;
+pub mod nickname;
+pub mod pairing_code;
+pub mod team;
+pub mod team_device_grant;
+pub mod team_invitation;
+pub mod turn_usage;
 pub mod user;
+pub mod user_block;
 pub mod user_connection;
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+use uuid::Uuid;
+
+use crate::app_state::AppState;
+
+pub const MAX_NICKNAME_LENGTH: usize = 100;
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct ContactNicknameView {
+    pub email: String,
+    pub nickname: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct DeviceNicknameView {
+    pub email: String,
+    pub device_id: String,
+    pub nickname: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+// private labels a user gives to contacts and their devices; nobody else sees them
+pub struct Nickname;
+
+impl Nickname {
+    // only users the owner is connected to, in any state, or shares a team with can be
+    // labeled; anyone else is missing, registered or not
+    async fn contact_id(
+        owner_id: Uuid,
+        contact_email: &str,
+        app_state: &AppState,
+    ) -> Result<Uuid, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT u.id
+            FROM users u
+            WHERE u.email = $2
+            AND (
+                EXISTS (
+                    SELECT 1 FROM user_connection uc
+                    WHERE (uc.from_id = $1 AND uc.to_id = u.id)
+                    OR (uc.from_id = u.id AND uc.to_id = $1)
+                )
+                OR EXISTS (
+                    SELECT 1 FROM team_members m1
+                    JOIN team_members m2 ON m1.team_id = m2.team_id
+                    WHERE m1.user_id = $1 AND m2.user_id = u.id
+                )
+            )
+            "#,
+            owner_id,
+            contact_email
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await
+    }
+
+    // None clears the label
+    pub async fn set_contact(
+        owner_id: Uuid,
+        contact_email: String,
+        nickname: Option<String>,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        let contact_id = Nickname::contact_id(owner_id, &contact_email, &app_state).await?;
+        match nickname {
+            Some(nickname) => {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO contact_nicknames (owner_id, contact_id, nickname)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (owner_id, contact_id)
+                    DO UPDATE SET nickname = EXCLUDED.nickname, updated_at = NOW()
+                    "#,
+                    owner_id,
+                    contact_id,
+                    nickname
+                )
+                .execute(&app_state.pg_pool)
+                .await?;
+            }
+            None => {
+                sqlx::query!(
+                    "DELETE FROM contact_nicknames WHERE owner_id = $1 AND contact_id = $2",
+                    owner_id,
+                    contact_id
+                )
+                .execute(&app_state.pg_pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    // None clears the label
+    pub async fn set_device(
+        owner_id: Uuid,
+        device_owner_email: String,
+        device_id: String,
+        nickname: Option<String>,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        let device_owner_id =
+            Nickname::contact_id(owner_id, &device_owner_email, &app_state).await?;
+        match nickname {
+            Some(nickname) => {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO device_nicknames (owner_id, device_owner_id, device_id, nickname)
+                    VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (owner_id, device_owner_id, device_id)
+                    DO UPDATE SET nickname = EXCLUDED.nickname, updated_at = NOW()
+                    "#,
+                    owner_id,
+                    device_owner_id,
+                    device_id,
+                    nickname
+                )
+                .execute(&app_state.pg_pool)
+                .await?;
+            }
+            None => {
+                sqlx::query!(
+                    r#"
+                    DELETE FROM device_nicknames
+                    WHERE owner_id = $1 AND device_owner_id = $2 AND device_id = $3
+                    "#,
+                    owner_id,
+                    device_owner_id,
+                    device_id
+                )
+                .execute(&app_state.pg_pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn list_contacts(
+        owner_id: Uuid,
+        app_state: &AppState,
+    ) -> Result<Vec<ContactNicknameView>, sqlx::Error> {
+        sqlx::query_as!(
+            ContactNicknameView,
+            r#"
+            SELECT u.email, n.nickname, n.updated_at
+            FROM contact_nicknames n
+            JOIN users u ON n.contact_id = u.id
+            WHERE n.owner_id = $1
+            ORDER BY u.email
+            "#,
+            owner_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    pub async fn list_devices(
+        owner_id: Uuid,
+        app_state: &AppState,
+    ) -> Result<Vec<DeviceNicknameView>, sqlx::Error> {
+        sqlx::query_as!(
+            DeviceNicknameView,
+            r#"
+            SELECT u.email, n.device_id, n.nickname, n.updated_at
+            FROM device_nicknames n
+            JOIN users u ON n.device_owner_id = u.id
+            WHERE n.owner_id = $1
+            ORDER BY u.email, n.device_id
+            "#,
+            owner_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    // contact email -> label, for merging into check
+    pub async fn contact_labels(
+        owner_id: Uuid,
+        app_state: &AppState,
+    ) -> Result<HashMap<String, String>, sqlx::Error> {
+        Ok(Nickname::list_contacts(owner_id, app_state)
+            .await?
+            .into_iter()
+            .map(|n| (n.email, n.nickname))
+            .collect())
+    }
+
+    // (device owner email, device id) -> label, for merging into check
+    pub async fn device_labels(
+        owner_id: Uuid,
+        app_state: &AppState,
+    ) -> Result<HashMap<(String, String), String>, sqlx::Error> {
+        Ok(Nickname::list_devices(owner_id, app_state)
+            .await?
+            .into_iter()
+            .map(|n| ((n.email, n.device_id), n.nickname))
+            .collect())
+    }
+}
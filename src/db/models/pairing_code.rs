@@ -0,0 +1,245 @@
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    db::models::{
+        user_block::UserBlock,
+        user_connection::{MODE_ACCESS, MODE_MUTUAL, MODE_SHARE},
+    },
+    utils::hash_service::hash_generator::generate_numeric_code,
+};
+
+pub const PAIRING_CODE_LENGTH: usize = 6;
+
+// failed code entries allowed per user before it has to wait for the window to pass
+const MAX_FAILED_ATTEMPTS: i64 = 5;
+// and from all users together, against guessing spread over many accounts
+const MAX_GLOBAL_FAILED_ATTEMPTS: i64 = 500;
+const FAILED_ATTEMPTS_WINDOW: i64 = 15 * 60;
+const GLOBAL_FAILED_ATTEMPTS_KEY: &str = "pairing:failed_attempts";
+
+// what the device asked for when it requested the code
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairingCode {
+    pub owner_id: Uuid,
+    pub owner_email: String,
+    pub device_id: String,
+    pub mode: String,
+    // what the user entering the code may do on the owner's devices
+    pub permissions: Vec<String>,
+    // leave the connection pending until the owner accepts it
+    pub require_confirmation: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IssuedPairingCode {
+    pub code: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PairingOutcome {
+    Connected,
+    // stored as a request from the entering user for the owner to accept
+    Pending,
+}
+
+#[derive(Debug)]
+pub enum PairingError {
+    // unknown, used or expired code; also covers self pairing and blocks
+    InvalidCode,
+    TooManyAttempts,
+    AlreadyConnected,
+    // the entering user's own grant names an unknown permission
+    InvalidPermissions,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for PairingError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::Database(db_error) if db_error.is_unique_violation() => {
+                PairingError::AlreadyConnected
+            }
+            e => PairingError::Database(e),
+        }
+    }
+}
+
+// the mode as seen from the other end of the connection
+fn reverse_mode(mode: &str) -> &str {
+    match mode {
+        MODE_SHARE => MODE_ACCESS,
+        MODE_ACCESS => MODE_SHARE,
+        _ => MODE_MUTUAL,
+    }
+}
+
+impl PairingCode {
+    fn code_key(code: &str) -> String {
+        format!("pairing:code:{}", code)
+    }
+
+    fn failed_attempts_key(user_id: Uuid) -> String {
+        format!("pairing:failed_attempts:{}", user_id)
+    }
+
+    pub fn ttl_seconds() -> u64 {
+        std::env::var("PAIRING_CODE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5 * 60)
+    }
+
+    // stores the code in redis until it is entered or runs out
+    pub async fn issue(self, app_state: &AppState) -> Result<IssuedPairingCode, sqlx::Error> {
+        let mut redis_connection = app_state
+            .redis_pool
+            .get()
+            .await
+            .map_err(|_| sqlx::Error::PoolClosed)?;
+        let value =
+            serde_json::to_string(&self).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+        let expires_in = PairingCode::ttl_seconds();
+        // a fresh code is drawn if it clashes with one still open
+        for _ in 0..5 {
+            let code = generate_numeric_code(PAIRING_CODE_LENGTH);
+            let stored: bool = redis::cmd("SET")
+                .arg(PairingCode::code_key(&code))
+                .arg(&value)
+                .arg("NX")
+                .arg("EX")
+                .arg(expires_in)
+                .query_async(&mut *redis_connection)
+                .await
+                .map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+            if stored {
+                return Ok(IssuedPairingCode { code, expires_in });
+            }
+        }
+        Err(sqlx::Error::Protocol(
+            "Could not allocate a pairing code".to_string(),
+        ))
+    }
+
+    // codes are single use, a wrong one counts against the user entering it and against
+    // everyone. `reverse_permissions` is what the entering user lets the code's owner do
+    // on its own devices, used by access and mutual codes; the code never decides that
+    pub async fn redeem(
+        code: &str,
+        user_id: Uuid,
+        reverse_permissions: &[String],
+        app_state: &AppState,
+    ) -> Result<(PairingCode, PairingOutcome), PairingError> {
+        let mut redis_connection = app_state
+            .redis_pool
+            .get()
+            .await
+            .map_err(|_| PairingError::Database(sqlx::Error::PoolClosed))?;
+        let attempts_key = PairingCode::failed_attempts_key(user_id);
+        let (failed, global_failed): (Option<i64>, Option<i64>) = redis_connection
+            .mget((&attempts_key, GLOBAL_FAILED_ATTEMPTS_KEY))
+            .await
+            .map_err(|e| PairingError::Database(sqlx::Error::Protocol(e.to_string())))?;
+        if failed.unwrap_or(0) >= MAX_FAILED_ATTEMPTS
+            || global_failed.unwrap_or(0) >= MAX_GLOBAL_FAILED_ATTEMPTS
+        {
+            return Err(PairingError::TooManyAttempts);
+        }
+
+        // the code is only used up once it is known to pair these two users, so a
+        // wrong user or an existing connection leaves it for the right one
+        let code_key = PairingCode::code_key(code.trim());
+        let stored: Option<String> = redis_connection.get(&code_key).await.unwrap_or(None);
+        let pairing = stored.and_then(|v| serde_json::from_str::<PairingCode>(&v).ok());
+        let pairing = match pairing {
+            Some(pairing)
+                if pairing.owner_id != user_id
+                    && !UserBlock::is_blocked_between_ids(pairing.owner_id, user_id, app_state)
+                        .await? =>
+            {
+                pairing
+            }
+            _ => {
+                let _: Result<(), _> = redis::pipe()
+                    .incr(&attempts_key, 1)
+                    .ignore()
+                    .expire(&attempts_key, FAILED_ATTEMPTS_WINDOW)
+                    .ignore()
+                    .incr(GLOBAL_FAILED_ATTEMPTS_KEY, 1)
+                    .ignore()
+                    .expire(GLOBAL_FAILED_ATTEMPTS_KEY, FAILED_ATTEMPTS_WINDOW)
+                    .ignore()
+                    .query_async(&mut *redis_connection)
+                    .await;
+                return Err(PairingError::InvalidCode);
+            }
+        };
+
+        // the unique constraint only covers one direction
+        let existing = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM user_connection
+                WHERE (from_id = $1 AND to_id = $2) OR (from_id = $2 AND to_id = $1)
+            ) AS "exists!"
+            "#,
+            pairing.owner_id,
+            user_id
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await?;
+        if existing {
+            return Err(PairingError::AlreadyConnected);
+        }
+
+        // whoever deletes it first gets to pair
+        let deleted: i64 = redis_connection
+            .del(&code_key)
+            .await
+            .map_err(|e| PairingError::Database(sqlx::Error::Protocol(e.to_string())))?;
+        if deleted == 0 {
+            return Err(PairingError::InvalidCode);
+        }
+
+        let outcome = if pairing.require_confirmation {
+            // the entering user becomes the requester so the owner can use accept_request;
+            // what the owner gets on the entering user's devices is what that user granted
+            sqlx::query!(
+                r#"
+                INSERT INTO user_connection
+                    (from_id, to_id, is_accepted, mode, permissions, reverse_permissions)
+                VALUES ($1, $2, false, $3, $4, $5)
+                "#,
+                user_id,
+                pairing.owner_id,
+                reverse_mode(&pairing.mode),
+                reverse_permissions,
+                &pairing.permissions
+            )
+            .execute(&app_state.pg_pool)
+            .await?;
+            PairingOutcome::Pending
+        } else {
+            sqlx::query!(
+                r#"
+                INSERT INTO user_connection
+                    (from_id, to_id, is_accepted, accepted_at, mode, permissions,
+                     reverse_permissions)
+                VALUES ($1, $2, true, NOW(), $3, $4, $5)
+                "#,
+                pairing.owner_id,
+                user_id,
+                pairing.mode,
+                &pairing.permissions,
+                reverse_permissions
+            )
+            .execute(&app_state.pg_pool)
+            .await?;
+            PairingOutcome::Connected
+        };
+        Ok((pairing, outcome))
+    }
+}
@@ -0,0 +1,179 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+use uuid::Uuid;
+
+use crate::app_state::AppState;
+
+pub const TEAM_OWNER: &str = "owner";
+pub const TEAM_ADMIN: &str = "admin";
+pub const TEAM_MEMBER: &str = "member";
+
+// owners and admins manage members and other members' device grants
+pub fn can_manage(role: &str) -> bool {
+    role == TEAM_OWNER || role == TEAM_ADMIN
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct TeamView {
+    pub id: Uuid,
+    pub name: String,
+    // the requesting user's role in the team
+    pub role: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct TeamMemberView {
+    pub user_id: Uuid,
+    pub email: String,
+    pub role: String,
+    pub created_at: DateTime<Utc>,
+}
+
+pub struct Team;
+
+impl Team {
+    // the creator becomes the team's first owner
+    pub async fn create(
+        owner_id: Uuid,
+        name: String,
+        app_state: AppState,
+    ) -> Result<TeamView, sqlx::Error> {
+        let mut tx = app_state.pg_pool.begin().await?;
+        let team = sqlx::query!(
+            "INSERT INTO teams (name) VALUES ($1) RETURNING id, name, created_at",
+            name
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO team_members (team_id, user_id, role) VALUES ($1, $2, $3)",
+            team.id,
+            owner_id,
+            TEAM_OWNER
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(TeamView {
+            id: team.id,
+            name: team.name,
+            role: TEAM_OWNER.to_string(),
+            created_at: team.created_at,
+        })
+    }
+
+    pub async fn list_for_user(
+        user_id: Uuid,
+        app_state: AppState,
+    ) -> Result<Vec<TeamView>, sqlx::Error> {
+        sqlx::query_as!(
+            TeamView,
+            r#"
+            SELECT t.id, t.name, m.role, t.created_at
+            FROM team_members m
+            JOIN teams t ON m.team_id = t.id
+            WHERE m.user_id = $1
+            ORDER BY t.name
+            "#,
+            user_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    pub async fn get_name(team_id: Uuid, app_state: &AppState) -> Result<String, sqlx::Error> {
+        sqlx::query_scalar!("SELECT name FROM teams WHERE id = $1", team_id)
+            .fetch_one(&app_state.pg_pool)
+            .await
+    }
+
+    pub async fn member_role(
+        team_id: Uuid,
+        user_id: Uuid,
+        app_state: &AppState,
+    ) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar!(
+            "SELECT role FROM team_members WHERE team_id = $1 AND user_id = $2",
+            team_id,
+            user_id
+        )
+        .fetch_optional(&app_state.pg_pool)
+        .await
+    }
+
+    pub async fn members(
+        team_id: Uuid,
+        app_state: AppState,
+    ) -> Result<Vec<TeamMemberView>, sqlx::Error> {
+        sqlx::query_as!(
+            TeamMemberView,
+            r#"
+            SELECT m.user_id, u.email, m.role, m.created_at
+            FROM team_members m
+            JOIN users u ON m.user_id = u.id
+            WHERE m.team_id = $1
+            ORDER BY m.created_at
+            "#,
+            team_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    pub async fn owner_count(team_id: Uuid, app_state: &AppState) -> Result<i64, sqlx::Error> {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM team_members WHERE team_id = $1 AND role = $2"#,
+            team_id,
+            TEAM_OWNER
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await?;
+        Ok(count)
+    }
+
+    // owners are left alone, the row is reported missing for them
+    pub async fn set_member_role(
+        team_id: Uuid,
+        user_id: Uuid,
+        role: &str,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE team_members SET role = $3
+            WHERE team_id = $1 AND user_id = $2 AND role <> $4
+            "#,
+            team_id,
+            user_id,
+            role,
+            TEAM_OWNER
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    // the member's team device grants go with it
+    pub async fn remove_member(
+        team_id: Uuid,
+        user_id: Uuid,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM team_members WHERE team_id = $1 AND user_id = $2",
+            team_id,
+            user_id
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+use uuid::Uuid;
+
+use crate::app_state::AppState;
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct TeamDeviceGrantView {
+    pub owner_email: String,
+    pub device_id: String,
+    pub permissions: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// a device another member shared with a team the user belongs to
+#[derive(Debug, FromRow)]
+pub struct TeamReachableDevice {
+    pub team_id: Uuid,
+    pub team_name: String,
+    pub owner_email: String,
+    pub device_id: String,
+    pub permissions: Vec<String>,
+}
+
+pub struct TeamDeviceGrant;
+
+impl TeamDeviceGrant {
+    // members share their own devices; sharing again updates the permissions
+    pub async fn set(
+        team_id: Uuid,
+        owner_id: Uuid,
+        device_id: String,
+        permissions: Vec<String>,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO team_device_grants (team_id, owner_id, device_id, permissions)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (team_id, owner_id, device_id)
+            DO UPDATE SET permissions = EXCLUDED.permissions
+            "#,
+            team_id,
+            owner_id,
+            device_id,
+            &permissions
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn remove(
+        team_id: Uuid,
+        owner_email: String,
+        device_id: String,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM team_device_grants
+            WHERE team_id = $1
+            AND owner_id = (SELECT id FROM users WHERE email = $2)
+            AND device_id = $3
+            "#,
+            team_id,
+            owner_email,
+            device_id
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    pub async fn list(
+        team_id: Uuid,
+        app_state: AppState,
+    ) -> Result<Vec<TeamDeviceGrantView>, sqlx::Error> {
+        sqlx::query_as!(
+            TeamDeviceGrantView,
+            r#"
+            SELECT u.email AS owner_email, g.device_id, g.permissions, g.created_at
+            FROM team_device_grants g
+            JOIN users u ON g.owner_id = u.id
+            WHERE g.team_id = $1
+            ORDER BY u.email, g.device_id
+            "#,
+            team_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    // devices other members shared with the user's teams, minus blocked owners
+    pub async fn reachable(
+        user_id: Uuid,
+        app_state: &AppState,
+    ) -> Result<Vec<TeamReachableDevice>, sqlx::Error> {
+        sqlx::query_as!(
+            TeamReachableDevice,
+            r#"
+            SELECT g.team_id, t.name AS team_name, u.email AS owner_email, g.device_id, g.permissions
+            FROM team_members m
+            JOIN team_device_grants g ON g.team_id = m.team_id
+            JOIN teams t ON g.team_id = t.id
+            JOIN users u ON g.owner_id = u.id
+            WHERE m.user_id = $1 AND g.owner_id <> $1
+            AND NOT EXISTS (
+                SELECT 1 FROM user_blocks b
+                WHERE (b.blocker_id = $1 AND b.blocked_id = g.owner_id)
+                OR (b.blocker_id = g.owner_id AND b.blocked_id = $1)
+            )
+            ORDER BY t.name, u.email, g.device_id
+            "#,
+            user_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    // everything grantee_email may do on the device through any shared team
+    pub async fn permissions_for(
+        owner_email: &str,
+        grantee_email: &str,
+        device_id: &str,
+        app_state: &AppState,
+    ) -> Result<Option<Vec<String>>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT array_agg(DISTINCT p) AS "permissions"
+            FROM team_device_grants g
+            JOIN team_members m ON m.team_id = g.team_id
+            JOIN users o ON g.owner_id = o.id
+            JOIN users u ON m.user_id = u.id
+            CROSS JOIN UNNEST(g.permissions) AS p
+            WHERE o.email = $1 AND u.email = $2 AND g.device_id = $3
+            "#,
+            owner_email,
+            grantee_email,
+            device_id
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await
+    }
+}
@@ -0,0 +1,114 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+use uuid::Uuid;
+
+use crate::app_state::AppState;
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct TeamInvitationView {
+    pub team_id: Uuid,
+    pub team_name: String,
+    pub role: String,
+    pub invited_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// team invitations are kept by email so they also work for people without an account
+pub struct TeamInvitation;
+
+impl TeamInvitation {
+    // inviting the same email again refreshes the invitation and its role
+    pub async fn create(
+        team_id: Uuid,
+        email: String,
+        role: String,
+        invited_by: Uuid,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO team_invitations (team_id, email, role, invited_by)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (team_id, email)
+            DO UPDATE SET role = EXCLUDED.role, invited_by = EXCLUDED.invited_by, created_at = NOW()
+            "#,
+            team_id,
+            email,
+            role,
+            invited_by
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn list_for_email(
+        email: String,
+        app_state: AppState,
+    ) -> Result<Vec<TeamInvitationView>, sqlx::Error> {
+        sqlx::query_as!(
+            TeamInvitationView,
+            r#"
+            SELECT i.team_id, t.name AS team_name, i.role, u.email AS "invited_by?", i.created_at
+            FROM team_invitations i
+            JOIN teams t ON i.team_id = t.id
+            LEFT JOIN users u ON i.invited_by = u.id
+            WHERE i.email = $1
+            ORDER BY i.created_at DESC
+            "#,
+            email
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    // turns the invitation into a membership with the role it was issued for
+    pub async fn accept(
+        team_id: Uuid,
+        user_id: Uuid,
+        email: String,
+        app_state: AppState,
+    ) -> Result<String, sqlx::Error> {
+        let mut tx = app_state.pg_pool.begin().await?;
+        let role = sqlx::query_scalar!(
+            "DELETE FROM team_invitations WHERE team_id = $1 AND email = $2 RETURNING role",
+            team_id,
+            email
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO team_members (team_id, user_id, role)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+            "#,
+            team_id,
+            user_id,
+            role
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(role)
+    }
+
+    pub async fn decline(
+        team_id: Uuid,
+        email: String,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM team_invitations WHERE team_id = $1 AND email = $2",
+            team_id,
+            email
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+}
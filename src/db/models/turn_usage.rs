@@ -0,0 +1,111 @@
+use chrono::NaiveDate;
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+
+use crate::app_state::AppState;
+
+// bytes the embedded TURN server relayed, per user and day; guests are keyed by
+// their guest email like everywhere else in signaling
+#[derive(Debug, FromRow, Serialize)]
+pub struct TurnUsageDay {
+    pub day: NaiveDate,
+    pub bytes_to_peer: i64,
+    pub bytes_from_peer: i64,
+    pub allocations: i64,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct TurnUsageTotal {
+    pub user_email: String,
+    pub bytes_to_peer: i64,
+    pub bytes_from_peer: i64,
+    pub allocations: i64,
+}
+
+pub struct TurnUsage;
+
+impl TurnUsage {
+    // adds to today's row, the relay flushes what it counted since the last flush
+    pub async fn record(
+        user_email: &str,
+        bytes_to_peer: i64,
+        bytes_from_peer: i64,
+        allocations: i64,
+        app_state: &AppState,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO turn_usage (user_email, day, bytes_to_peer, bytes_from_peer, allocations)
+            VALUES ($1, CURRENT_DATE, $2, $3, $4)
+            ON CONFLICT (user_email, day) DO UPDATE SET
+                bytes_to_peer = turn_usage.bytes_to_peer + EXCLUDED.bytes_to_peer,
+                bytes_from_peer = turn_usage.bytes_from_peer + EXCLUDED.bytes_from_peer,
+                allocations = turn_usage.allocations + EXCLUDED.allocations
+            "#,
+            user_email,
+            bytes_to_peer,
+            bytes_from_peer,
+            allocations
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn daily(
+        user_email: &str,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+        app_state: &AppState,
+    ) -> Result<Vec<TurnUsageDay>, sqlx::Error> {
+        sqlx::query_as!(
+            TurnUsageDay,
+            r#"
+            SELECT day, bytes_to_peer, bytes_from_peer, allocations
+            FROM turn_usage
+            WHERE user_email = $1
+            AND ($2::date IS NULL OR day >= $2)
+            AND ($3::date IS NULL OR day <= $3)
+            ORDER BY day DESC
+            LIMIT 366
+            "#,
+            user_email,
+            from,
+            to
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    // heaviest users first
+    pub async fn totals(
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+        limit: i64,
+        offset: i64,
+        app_state: &AppState,
+    ) -> Result<Vec<TurnUsageTotal>, sqlx::Error> {
+        sqlx::query_as!(
+            TurnUsageTotal,
+            r#"
+            SELECT
+                user_email,
+                SUM(bytes_to_peer)::BIGINT AS "bytes_to_peer!",
+                SUM(bytes_from_peer)::BIGINT AS "bytes_from_peer!",
+                SUM(allocations)::BIGINT AS "allocations!"
+            FROM turn_usage
+            WHERE ($1::date IS NULL OR day >= $1)
+            AND ($2::date IS NULL OR day <= $2)
+            GROUP BY user_email
+            ORDER BY SUM(bytes_to_peer + bytes_from_peer) DESC, user_email
+            LIMIT $3 OFFSET $4
+            "#,
+            from,
+            to,
+            limit,
+            offset
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+}
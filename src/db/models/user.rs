@@ -1,11 +1,12 @@
-use std::str::FromStr;
-
+use chrono::{DateTime, Utc};
 use redis::AsyncCommands;
+use serde::Serialize;
 use sqlx::prelude::FromRow;
 use uuid::Uuid;
 
 use crate::{app_state::AppState, utils::hash_service::bcrypt::verify_password};
 
+#[allow(dead_code)]
 #[derive(Debug, FromRow)]
 pub struct User {
     id: Uuid,
@@ -13,6 +14,24 @@ pub struct User {
     password_hash: String,
 }
 
+pub const ROLE_USER: &str = "user";
+pub const ROLE_ADMIN: &str = "admin";
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct UserAccount {
+    pub id: Uuid,
+    pub email: String,
+    pub role: String,
+    pub suspended_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl UserAccount {
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_at.is_some()
+    }
+}
+
 impl User {
     pub async fn cache_user(
         email: String,
@@ -45,15 +64,17 @@ impl User {
     pub async fn create(
         email: String,
         password_hash: String,
+        display_name: Option<String>,
         app_state: AppState,
     ) -> Result<(), sqlx::Error> {
         let mut tx = app_state.pg_pool.begin().await?;
         let user_id = sqlx::query_scalar!(
-            "INSERT INTO USERS (email,password_hash)
-            values ($1,$2)
+            "INSERT INTO USERS (email,password_hash,display_name)
+            values ($1,$2,$3)
             RETURNING id",
             email.clone(),
-            password_hash.clone()
+            password_hash.clone(),
+            display_name
         )
         .fetch_one(&mut *tx)
         .await?;
@@ -87,7 +108,7 @@ impl User {
                 .await
                 .map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
 
-            if verify_password(password.clone(), password_hash) {
+            if verify_password(password.clone(), password_hash).await {
                 return Ok(user_id);
             } else {
                 return Err(sqlx::Error::RowNotFound);
@@ -102,7 +123,7 @@ impl User {
         .fetch_one(&mut *tx)
         .await?;
 
-        if !verify_password(password.clone(), row.password_hash.clone()) {
+        if !verify_password(password.clone(), row.password_hash.clone()).await {
             return Err(sqlx::Error::RowNotFound);
         }
 
@@ -120,21 +141,86 @@ impl User {
         Ok(row.id.to_string())
     }
 
-    pub async fn get_user_email(
-        user_id: String,
-        app_state: AppState,
-    ) -> Result<String, sqlx::Error> {
-        let user_id = Uuid::from_str(user_id.as_str()).unwrap();
-        let row = sqlx::query!("SELECT email FROM users WHERE id = $1", user_id)
-            .fetch_one(&app_state.pg_pool)
-            .await?;
-
-        Ok(row.email)
-    }
     pub async fn get_user_id(email: String, app_state: AppState) -> Result<Uuid, sqlx::Error> {
         let row = sqlx::query!("SELECT id FROM users WHERE email = $1", email)
             .fetch_one(&app_state.pg_pool)
             .await?;
         Ok(row.id)
     }
+
+    pub async fn get_account(
+        user_id: Uuid,
+        app_state: AppState,
+    ) -> Result<UserAccount, sqlx::Error> {
+        sqlx::query_as!(
+            UserAccount,
+            "SELECT id, email, role, suspended_at, created_at FROM users WHERE id = $1",
+            user_id
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await
+    }
+
+    pub async fn list_accounts(
+        search: Option<String>,
+        limit: i64,
+        offset: i64,
+        app_state: AppState,
+    ) -> Result<Vec<UserAccount>, sqlx::Error> {
+        sqlx::query_as!(
+            UserAccount,
+            r#"
+            SELECT id, email, role, suspended_at, created_at
+            FROM users
+            WHERE $1::text IS NULL OR email ILIKE '%' || $1 || '%'
+            ORDER BY created_at DESC
+            LIMIT $2 OFFSET $3
+            "#,
+            search,
+            limit,
+            offset
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    pub async fn set_suspended(
+        user_id: Uuid,
+        suspended: bool,
+        app_state: AppState,
+    ) -> Result<UserAccount, sqlx::Error> {
+        sqlx::query_as!(
+            UserAccount,
+            r#"
+            UPDATE users
+            SET suspended_at = CASE WHEN $2 THEN COALESCE(suspended_at, NOW()) END
+            WHERE id = $1
+            RETURNING id, email, role, suspended_at, created_at
+            "#,
+            user_id,
+            suspended
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await
+    }
+
+    pub async fn set_role(
+        user_id: Uuid,
+        role: String,
+        app_state: AppState,
+    ) -> Result<UserAccount, sqlx::Error> {
+        sqlx::query_as!(
+            UserAccount,
+            r#"
+            UPDATE users
+            SET role = $2
+            WHERE id = $1
+            RETURNING id, email, role, suspended_at, created_at
+            "#,
+            user_id,
+            role
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await
+    }
 }
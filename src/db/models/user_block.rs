@@ -0,0 +1,139 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+use uuid::Uuid;
+
+use crate::app_state::AppState;
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct BlockedUserView {
+    pub email: String,
+    pub created_at: DateTime<Utc>,
+}
+
+pub struct UserBlock;
+
+impl UserBlock {
+    // blocking also drops every request or connection between the two users
+    pub async fn block(
+        blocker_id: Uuid,
+        blocked_email: String,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = app_state.pg_pool.begin().await?;
+        let blocked_id =
+            sqlx::query_scalar!("SELECT id FROM users WHERE email = $1", blocked_email)
+                .fetch_one(&mut *tx)
+                .await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO user_blocks (blocker_id, blocked_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+            blocker_id,
+            blocked_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            DELETE FROM user_connection
+            WHERE (from_id = $1 AND to_id = $2) OR (from_id = $2 AND to_id = $1)
+            "#,
+            blocker_id,
+            blocked_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn unblock(
+        blocker_id: Uuid,
+        blocked_email: String,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM user_blocks
+            WHERE blocker_id = $1
+            AND blocked_id = (SELECT id FROM users WHERE email = $2)
+            "#,
+            blocker_id,
+            blocked_email
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    pub async fn list(
+        blocker_id: Uuid,
+        app_state: AppState,
+    ) -> Result<Vec<BlockedUserView>, sqlx::Error> {
+        sqlx::query_as!(
+            BlockedUserView,
+            r#"
+            SELECT u.email, b.created_at
+            FROM user_blocks b
+            JOIN users u ON b.blocked_id = u.id
+            WHERE b.blocker_id = $1
+            ORDER BY b.created_at DESC
+            "#,
+            blocker_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    // true when either user has blocked the other
+    pub async fn is_blocked_between(
+        email: &str,
+        other_email: &str,
+        app_state: &AppState,
+    ) -> Result<bool, sqlx::Error> {
+        let blocked = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1
+                FROM user_blocks b
+                JOIN users u1 ON b.blocker_id = u1.id
+                JOIN users u2 ON b.blocked_id = u2.id
+                WHERE (u1.email = $1 AND u2.email = $2)
+                OR (u1.email = $2 AND u2.email = $1)
+            ) AS "blocked!"
+            "#,
+            email,
+            other_email
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await?;
+        Ok(blocked)
+    }
+
+    pub async fn is_blocked_between_ids(
+        user_id: Uuid,
+        other_id: Uuid,
+        app_state: &AppState,
+    ) -> Result<bool, sqlx::Error> {
+        let blocked = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM user_blocks
+                WHERE (blocker_id = $1 AND blocked_id = $2)
+                OR (blocker_id = $2 AND blocked_id = $1)
+            ) AS "blocked!"
+            "#,
+            user_id,
+            other_id
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await?;
+        Ok(blocked)
+    }
+}
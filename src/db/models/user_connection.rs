@@ -1,36 +1,271 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, NaiveTime, Utc};
+use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 use uuid::Uuid;
 
-use crate::app_state::AppState;
+use crate::{
+    app_state::AppState,
+    db::models::{connection_invitation::ConnectionInvitation, user_block::UserBlock},
+};
 
+pub const PERMISSION_VIEW: &str = "view";
+pub const PERMISSION_CONTROL: &str = "control";
+pub const PERMISSION_FILE_TRANSFER: &str = "file_transfer";
+pub const PERMISSION_CLIPBOARD: &str = "clipboard";
+pub const ALL_PERMISSIONS: [&str; 4] = [
+    PERMISSION_VIEW,
+    PERMISSION_CONTROL,
+    PERMISSION_FILE_TRANSFER,
+    PERMISSION_CLIPBOARD,
+];
+
+pub fn is_permission(name: &str) -> bool {
+    ALL_PERMISSIONS.contains(&name)
+}
+
+// to_id may access from_id's devices
+pub const MODE_SHARE: &str = "share";
+// from_id may access to_id's devices
+pub const MODE_ACCESS: &str = "access";
+// both may access each other's devices
+pub const MODE_MUTUAL: &str = "mutual";
+
+pub fn is_mode(name: &str) -> bool {
+    [MODE_SHARE, MODE_ACCESS, MODE_MUTUAL].contains(&name)
+}
+
+#[allow(dead_code)]
 #[derive(Debug, FromRow)]
 pub struct UserConnection {
     id: Uuid,
     from_id: Uuid,
     to_id: Uuid,
     is_accepted: bool,
+    mode: String,
+    // what to_id may do on from_id's devices, chosen by from_id
+    permissions: Vec<String>,
+    // what from_id may do on to_id's devices, chosen by to_id
+    reverse_permissions: Vec<String>,
+    expires_at: Option<DateTime<Utc>>,
+    // daily access window in UTC
+    window_start: Option<NaiveTime>,
+    window_end: Option<NaiveTime>,
+}
+
+// what the sender asks for when creating a request or invitation
+#[derive(Debug, Clone)]
+pub struct RequestTerms {
+    pub mode: String,
+    pub permissions: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub window_start: Option<NaiveTime>,
+    pub window_end: Option<NaiveTime>,
+}
+
+// an accepted connection removed by the expiry sweep
+#[derive(Debug, FromRow)]
+pub struct ExpiredConnection {
+    pub from_email: String,
+    pub to_email: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RequestOutcome {
+    Requested,
+    // the email has no account yet, an invitation was stored instead
+    Invited,
+    // the target rejected this sender recently, or the sender invited someone else
+    // too recently
+    CoolingDown,
+    // one side blocked the other; callers answer as if it was sent
+    Blocked,
+    // the email is the sender's own account
+    ToSelf,
 }
 
 #[derive(Debug, FromRow, Serialize, Deserialize)]
 pub struct UserConnectionView {
+    pub id: Uuid,
     pub from_email: String,
+    pub from_display_name: Option<String>,
     pub to_email: String,
+    pub to_display_name: Option<String>,
+    // the viewer's own label for the other user
+    pub nickname: Option<String>,
     pub is_accepted: bool,
+    pub mode: String,
+    pub permissions: Vec<String>,
+    pub reverse_permissions: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub window_start: Option<NaiveTime>,
+    pub window_end: Option<NaiveTime>,
+    pub created_at: DateTime<Utc>,
+    pub accepted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionSort {
+    Created,
+    // pending requests fall back to their creation time
+    Accepted,
+}
+
+// where the previous page stopped; handed to clients as an opaque string
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionCursor {
+    pub at: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl ConnectionCursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{}|{}", self.at.to_rfc3339(), self.id))
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        let text = String::from_utf8(bytes).ok()?;
+        let (at, id) = text.split_once('|')?;
+        Some(ConnectionCursor {
+            at: DateTime::parse_from_rfc3339(at).ok()?.with_timezone(&Utc),
+            id: Uuid::parse_str(id).ok()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionListFilter {
+    // matched against the other user's email, display name and nickname
+    pub search: Option<String>,
+    pub sort: ConnectionSort,
+    pub ascending: bool,
+    pub after: Option<ConnectionCursor>,
+    pub limit: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionPage {
+    pub items: Vec<UserConnectionView>,
+    pub next_cursor: Option<String>,
+}
+
+// an accepted connection seen from one of its users
+#[derive(Debug, FromRow, Serialize)]
+pub struct ConnectionSummary {
+    pub email: String,
+    pub display_name: Option<String>,
+    // this user's own label for the other user
+    pub nickname: Option<String>,
+    pub mode: String,
+    // what this user may do on the other user's devices, null without access
+    pub my_permissions: Option<Vec<String>>,
+    // what the other user may do on this user's devices, null without access
+    pub their_permissions: Option<Vec<String>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub window_start: Option<NaiveTime>,
+    pub window_end: Option<NaiveTime>,
+}
+
+#[derive(Debug, FromRow)]
+pub struct AccessibleOwner {
+    pub email: String,
+    pub permissions: Vec<String>,
 }
 
 impl UserConnection {
-    // called after accepting the request
+    fn cooldown_key(from_id: Uuid, to_id: Uuid) -> String {
+        format!("connection:cooldown:{}:{}", from_id, to_id)
+    }
+
+    fn cooldown_seconds() -> u64 {
+        std::env::var("CONNECTION_REQUEST_COOLDOWN_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(7 * 24 * 60 * 60)
+    }
+
+    // invitation mail goes to addresses without an account, so it is limited per sender
+    fn invite_cooldown_key(from_id: Uuid) -> String {
+        format!("connection:invite_cooldown:{}", from_id)
+    }
+
+    fn invite_cooldown_seconds() -> u64 {
+        std::env::var("INVITE_COOLDOWN_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60)
+    }
+
+    // starts the sender's cooldown, false while the previous one is still running
+    async fn take_invite_slot(from_id: Uuid, app_state: &AppState) -> Result<bool, sqlx::Error> {
+        let seconds = UserConnection::invite_cooldown_seconds();
+        if seconds == 0 {
+            return Ok(true);
+        }
+        let mut redis_connection = app_state
+            .redis_pool
+            .get()
+            .await
+            .map_err(|_| sqlx::Error::PoolClosed)?;
+        let taken: Option<String> = redis::cmd("SET")
+            .arg(UserConnection::invite_cooldown_key(from_id))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(seconds)
+            .query_async(&mut *redis_connection)
+            .await
+            .map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+        Ok(taken.is_some())
+    }
+
+    async fn in_cooldown(from_id: Uuid, to_id: Uuid, app_state: &AppState) -> bool {
+        let Ok(mut redis_connection) = app_state.redis_pool.get().await else {
+            return false;
+        };
+        redis_connection
+            .exists(UserConnection::cooldown_key(from_id, to_id))
+            .await
+            .unwrap_or(false)
+    }
+
+    // called after accepting the request; the accepting side may choose what the
+    // requester gets on its devices for `access` and `mutual` connections
+    // false when the request was accepted before; accepted_at, which expiry and the
+    // daily window count from, is left alone then
     pub async fn add_connection(
         from_id: Uuid,
         to_email: String,
+        reverse_permissions: Option<Vec<String>>,
         app_state: AppState,
-    ) -> Result<(), sqlx::Error> {
-        let mut tx = app_state.pg_pool.begin().await?;
-        sqlx::query!(
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
             r#"
                 UPDATE user_connection
-                SET is_accepted = true
+                SET is_accepted = true,
+                accepted_at = NOW(),
+                reverse_permissions = COALESCE($3, reverse_permissions)
+                WHERE from_id = (
+                    SELECT id FROM users WHERE email = $2
+                )
+                AND to_id = $1
+                AND is_accepted = false
+            "#,
+            from_id,
+            to_email,
+            reverse_permissions.as_deref()
+        )
+        .execute(&app_state.pg_pool)
+        .await?;
+        if result.rows_affected() > 0 {
+            return Ok(true);
+        }
+
+        let accepted = sqlx::query_scalar!(
+            r#"
+                SELECT is_accepted FROM user_connection
                 WHERE from_id = (
                     SELECT id FROM users WHERE email = $2
                 )
@@ -39,123 +274,507 @@ impl UserConnection {
             from_id,
             to_email
         )
+        .fetch_optional(&app_state.pg_pool)
+        .await?;
+        match accepted {
+            Some(true) => Ok(false),
+            _ => Err(sqlx::Error::RowNotFound),
+        }
+    }
+
+    // for sending the request
+    pub async fn add_request(
+        from_id: Uuid,
+        to_email: String,
+        terms: RequestTerms,
+        app_state: AppState,
+    ) -> Result<RequestOutcome, sqlx::Error> {
+        let to_id = sqlx::query_scalar!("SELECT id FROM users WHERE email = $1", to_email)
+            .fetch_optional(&app_state.pg_pool)
+            .await?;
+        let Some(to_id) = to_id else {
+            if !UserConnection::take_invite_slot(from_id, &app_state).await? {
+                return Ok(RequestOutcome::CoolingDown);
+            }
+            ConnectionInvitation::create(from_id, to_email, terms, app_state).await?;
+            return Ok(RequestOutcome::Invited);
+        };
+
+        if to_id == from_id {
+            return Ok(RequestOutcome::ToSelf);
+        }
+        if UserBlock::is_blocked_between_ids(from_id, to_id, &app_state).await? {
+            return Ok(RequestOutcome::Blocked);
+        }
+
+        if UserConnection::in_cooldown(from_id, to_id, &app_state).await {
+            return Ok(RequestOutcome::CoolingDown);
+        }
+
+        // a second request to the same user fails on unique_from_to
+        let mut tx = app_state.pg_pool.begin().await?;
+        sqlx::query!(
+            r#"
+            INSERT into user_connection
+                (from_id, to_id, is_accepted, mode, permissions, expires_at, window_start, window_end)
+            values ($1, $2, false, $3, $4, $5, $6, $7)
+            "#,
+            from_id,
+            to_id,
+            terms.mode,
+            &terms.permissions,
+            terms.expires_at,
+            terms.window_start,
+            terms.window_end
+        )
         .execute(&mut *tx)
         .await?;
         tx.commit().await?;
+        Ok(RequestOutcome::Requested)
+    }
+
+    // drops requests and connections past their expires_at; only accepted ones are
+    // returned since those are the ones where someone loses access
+    pub async fn delete_expired(
+        app_state: &AppState,
+    ) -> Result<Vec<ExpiredConnection>, sqlx::Error> {
+        sqlx::query_as!(
+            ExpiredConnection,
+            r#"
+            WITH expired AS (
+                DELETE FROM user_connection
+                WHERE expires_at <= NOW()
+                RETURNING from_id, to_id, is_accepted
+            )
+            SELECT u1.email AS from_email, u2.email AS to_email
+            FROM expired e
+            JOIN users u1 ON e.from_id = u1.id
+            JOIN users u2 ON e.to_id = u2.id
+            WHERE e.is_accepted = true
+            "#
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    // accepted connections whose daily window ended since the last sweep that saw it;
+    // each close is returned once, whichever pod sweeps first
+    pub async fn close_windows(
+        app_state: &AppState,
+    ) -> Result<Vec<ExpiredConnection>, sqlx::Error> {
+        sqlx::query_as!(
+            ExpiredConnection,
+            r#"
+            WITH closed AS (
+                UPDATE user_connection
+                SET window_notified_at = NOW()
+                WHERE is_accepted = true
+                AND window_end IS NOT NULL
+                AND (expires_at IS NULL OR expires_at > NOW())
+                AND connection_window_closed_at(window_end)
+                    > COALESCE(window_notified_at, accepted_at, created_at)
+                RETURNING from_id, to_id
+            )
+            SELECT u1.email AS from_email, u2.email AS to_email
+            FROM closed c
+            JOIN users u1 ON c.from_id = u1.id
+            JOIN users u2 ON c.to_id = u2.id
+            "#
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    // declines a pending request and blocks new ones from that sender for a while
+    pub async fn reject_request(
+        to_id: Uuid,
+        from_email: String,
+        app_state: AppState,
+    ) -> Result<(), sqlx::Error> {
+        let from_id = sqlx::query_scalar!(
+            r#"
+            DELETE FROM user_connection
+            WHERE from_id = (SELECT id FROM users WHERE email = $2)
+            AND to_id = $1
+            AND is_accepted = false
+            RETURNING from_id
+            "#,
+            to_id,
+            from_email
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await?;
+
+        let mut redis_connection = app_state
+            .redis_pool
+            .get()
+            .await
+            .map_err(|_| sqlx::Error::PoolClosed)?;
+        let _: () = redis_connection
+            .set_ex(
+                UserConnection::cooldown_key(from_id, to_id),
+                true,
+                UserConnection::cooldown_seconds(),
+            )
+            .await
+            .map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
         Ok(())
     }
 
-    // for sending the request
-    pub async fn add_request(
+    // withdraws a pending request, or an invitation if the email never signed up
+    pub async fn cancel_request(
         from_id: Uuid,
         to_email: String,
         app_state: AppState,
     ) -> Result<(), sqlx::Error> {
         let mut tx = app_state.pg_pool.begin().await?;
-        sqlx::query!(
-            "INSERT into user_connection (from_id, to_id, is_accepted) select $1,id,false from users where email = $2",
+        let requests = sqlx::query!(
+            r#"
+            DELETE FROM user_connection
+            WHERE from_id = $1
+            AND to_id = (SELECT id FROM users WHERE email = $2)
+            AND is_accepted = false
+            "#,
+            from_id,
+            to_email
+        )
+        .execute(&mut *tx)
+        .await?;
+        let invitations = sqlx::query!(
+            "DELETE FROM connection_invitations WHERE from_id = $1 AND email = $2",
             from_id,
             to_email
         )
         .execute(&mut *tx)
         .await?;
         tx.commit().await?;
+
+        if requests.rows_affected() + invitations.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
         Ok(())
     }
 
-    pub async fn get_sent_requests(
-        from_id: Uuid,
+    // severs an accepted connection, whichever side sent the original request
+    pub async fn remove_connection(
+        user_id: Uuid,
+        other_email: String,
         app_state: AppState,
-    ) -> Result<Vec<UserConnectionView>, sqlx::Error> {
-        let rows = sqlx::query_as!(
-            UserConnectionView,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
             r#"
-            SELECT 
-                u1.email AS from_email, 
-                u2.email AS to_email, 
-                uc.is_accepted
-            FROM user_connection uc
-            JOIN users u1 ON uc.from_id = u1.id
-            JOIN users u2 ON uc.to_id = u2.id
-            WHERE uc.from_id = $1 AND uc.is_accepted = false
-        "#,
-            from_id
+            DELETE FROM user_connection uc
+            USING users other
+            WHERE other.email = $2
+            AND uc.is_accepted = true
+            AND (
+                (uc.from_id = $1 AND uc.to_id = other.id)
+                OR (uc.from_id = other.id AND uc.to_id = $1)
+            )
+            "#,
+            user_id,
+            other_email
         )
-        .fetch_all(&app_state.pg_pool)
+        .execute(&app_state.pg_pool)
         .await?;
 
-        Ok(rows)
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
     }
 
-    pub async fn get_recieved_requests(
-        to_id: Uuid,
+    // only the side whose devices are shared can change what the other may do on them
+    pub async fn update_permissions(
+        owner_id: Uuid,
+        grantee_email: String,
+        permissions: Vec<String>,
         app_state: AppState,
-    ) -> Result<Vec<UserConnectionView>, sqlx::Error> {
-        let rows = sqlx::query_as!(
-            UserConnectionView,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = app_state.pg_pool.begin().await?;
+        let shared = sqlx::query!(
             r#"
-            SELECT 
-                u1.email AS from_email, 
-                u2.email AS to_email, 
-                uc.is_accepted
-            FROM user_connection uc
-            JOIN users u1 ON uc.from_id = u1.id
-            JOIN users u2 ON uc.to_id = u2.id
-            WHERE uc.to_id = $1 AND uc.is_accepted = false
-        "#,
-            to_id
+            UPDATE user_connection
+            SET permissions = $3
+            WHERE from_id = $1
+            AND to_id = (SELECT id FROM users WHERE email = $2)
+            AND mode IN ('share', 'mutual')
+            "#,
+            owner_id,
+            grantee_email,
+            &permissions
         )
-        .fetch_all(&app_state.pg_pool)
+        .execute(&mut *tx)
+        .await?;
+        let accessed = sqlx::query!(
+            r#"
+            UPDATE user_connection
+            SET reverse_permissions = $3
+            WHERE to_id = $1
+            AND from_id = (SELECT id FROM users WHERE email = $2)
+            AND mode IN ('access', 'mutual')
+            "#,
+            owner_id,
+            grantee_email,
+            &permissions
+        )
+        .execute(&mut *tx)
         .await?;
+        tx.commit().await?;
 
-        Ok(rows)
+        if shared.rows_affected() + accessed.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
     }
 
-    pub async fn connected_to(
-        to_id: Uuid,
+    // what grantee_email may do on owner_email's devices; None if they are not connected
+    pub async fn granted_permissions(
+        owner_email: &str,
+        grantee_email: &str,
+        app_state: &AppState,
+    ) -> Result<Option<Vec<String>>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT array_agg(DISTINCT p) AS "permissions"
+            FROM connection_access ca
+            JOIN users o ON ca.owner_id = o.id
+            JOIN users g ON ca.grantee_id = g.id
+            CROSS JOIN UNNEST(ca.permissions) AS p
+            WHERE o.email = $1 AND g.email = $2
+            "#,
+            owner_email,
+            grantee_email
+        )
+        .fetch_one(&app_state.pg_pool)
+        .await
+    }
+
+    // users whose devices grantee_id may access, whichever side sent the request
+    pub async fn accessible_owners(
+        grantee_id: Uuid,
+        app_state: &AppState,
+    ) -> Result<Vec<AccessibleOwner>, sqlx::Error> {
+        sqlx::query_as!(
+            AccessibleOwner,
+            r#"
+            SELECT u.email, array_agg(DISTINCT p) AS "permissions!"
+            FROM connection_access ca
+            JOIN users u ON ca.owner_id = u.id
+            CROSS JOIN UNNEST(ca.permissions) AS p
+            WHERE ca.grantee_id = $1
+            GROUP BY u.email
+            ORDER BY u.email
+            "#,
+            grantee_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
+    }
+
+    // every accepted connection of the user, in both directions
+    pub async fn connections(
+        user_id: Uuid,
         app_state: AppState,
-    ) -> Result<Vec<UserConnectionView>, sqlx::Error> {
-        let rows = sqlx::query_as!(
-            UserConnectionView,
+    ) -> Result<Vec<ConnectionSummary>, sqlx::Error> {
+        sqlx::query_as!(
+            ConnectionSummary,
             r#"
-            SELECT 
-                u1.email AS from_email, 
-                u2.email AS to_email, 
-                uc.is_accepted
+            SELECT
+                u.email,
+                u.display_name,
+                cn.nickname AS "nickname?",
+                uc.mode,
+                CASE
+                    WHEN uc.from_id = $1 AND uc.mode IN ('access', 'mutual') THEN uc.reverse_permissions
+                    WHEN uc.to_id = $1 AND uc.mode IN ('share', 'mutual') THEN uc.permissions
+                END AS my_permissions,
+                CASE
+                    WHEN uc.from_id = $1 AND uc.mode IN ('share', 'mutual') THEN uc.permissions
+                    WHEN uc.to_id = $1 AND uc.mode IN ('access', 'mutual') THEN uc.reverse_permissions
+                END AS their_permissions,
+                uc.expires_at,
+                uc.window_start,
+                uc.window_end
             FROM user_connection uc
-            JOIN users u1 ON uc.from_id = u1.id
-            JOIN users u2 ON uc.to_id = u2.id
-            WHERE uc.to_id = $1 AND uc.is_accepted = true 
-        "#,
-            to_id
+            JOIN users u ON u.id = CASE WHEN uc.from_id = $1 THEN uc.to_id ELSE uc.from_id END
+            LEFT JOIN contact_nicknames cn ON cn.owner_id = $1 AND cn.contact_id = u.id
+            WHERE (uc.from_id = $1 OR uc.to_id = $1) AND uc.is_accepted = true
+            ORDER BY u.email
+            "#,
+            user_id
         )
         .fetch_all(&app_state.pg_pool)
-        .await?;
+        .await
+    }
+
+    pub async fn get_sent_requests(
+        from_id: Uuid,
+        filter: ConnectionListFilter,
+        app_state: AppState,
+    ) -> Result<ConnectionPage, sqlx::Error> {
+        UserConnection::list(from_id, true, false, filter, app_state).await
+    }
 
-        Ok(rows)
+    pub async fn get_recieved_requests(
+        to_id: Uuid,
+        filter: ConnectionListFilter,
+        app_state: AppState,
+    ) -> Result<ConnectionPage, sqlx::Error> {
+        UserConnection::list(to_id, false, false, filter, app_state).await
+    }
+
+    pub async fn connected_to(
+        to_id: Uuid,
+        filter: ConnectionListFilter,
+        app_state: AppState,
+    ) -> Result<ConnectionPage, sqlx::Error> {
+        UserConnection::list(to_id, false, true, filter, app_state).await
     }
 
     pub async fn connected_from(
         from_id: Uuid,
+        filter: ConnectionListFilter,
+        app_state: AppState,
+    ) -> Result<ConnectionPage, sqlx::Error> {
+        UserConnection::list(from_id, true, true, filter, app_state).await
+    }
+
+    // keyset pagination over (sort time, id); the search only looks at the other user,
+    // including the viewer's nickname for them
+    async fn list(
+        user_id: Uuid,
+        as_sender: bool,
+        is_accepted: bool,
+        filter: ConnectionListFilter,
         app_state: AppState,
-    ) -> Result<Vec<UserConnectionView>, sqlx::Error> {
-        let rows = sqlx::query_as!(
+    ) -> Result<ConnectionPage, sqlx::Error> {
+        let by_accepted = filter.sort == ConnectionSort::Accepted;
+        let mut items = sqlx::query_as!(
             UserConnectionView,
             r#"
-            SELECT 
-                u1.email AS from_email, 
-                u2.email AS to_email, 
-                uc.is_accepted
+            SELECT
+                uc.id,
+                u1.email AS from_email,
+                u1.display_name AS from_display_name,
+                u2.email AS to_email,
+                u2.display_name AS to_display_name,
+                cn.nickname AS "nickname?",
+                uc.is_accepted,
+                uc.mode,
+                uc.permissions,
+                uc.reverse_permissions,
+                uc.expires_at,
+                uc.window_start,
+                uc.window_end,
+                uc.created_at,
+                uc.accepted_at
             FROM user_connection uc
             JOIN users u1 ON uc.from_id = u1.id
             JOIN users u2 ON uc.to_id = u2.id
-            WHERE uc.from_id = $1 AND uc.is_accepted = true
-        "#,
-            from_id
+            LEFT JOIN contact_nicknames cn
+                ON cn.owner_id = $1
+                AND cn.contact_id = (CASE WHEN $2 THEN uc.to_id ELSE uc.from_id END)
+            WHERE (($2 AND uc.from_id = $1) OR (NOT $2 AND uc.to_id = $1))
+            AND uc.is_accepted = $3
+            AND (
+                $4::text IS NULL
+                OR (CASE WHEN $2 THEN u2.email ELSE u1.email END) ILIKE '%' || $4 || '%'
+                OR (CASE WHEN $2 THEN u2.display_name ELSE u1.display_name END)
+                    ILIKE '%' || $4 || '%'
+                OR cn.nickname ILIKE '%' || $4 || '%'
+            )
+            AND (
+                $6::timestamptz IS NULL
+                OR ($7 AND (
+                    (CASE WHEN $5 THEN COALESCE(uc.accepted_at, uc.created_at) ELSE uc.created_at END),
+                    uc.id
+                ) > ($6, $8))
+                OR (NOT $7 AND (
+                    (CASE WHEN $5 THEN COALESCE(uc.accepted_at, uc.created_at) ELSE uc.created_at END),
+                    uc.id
+                ) < ($6, $8))
+            )
+            ORDER BY
+                CASE WHEN $7 THEN
+                    (CASE WHEN $5 THEN COALESCE(uc.accepted_at, uc.created_at) ELSE uc.created_at END)
+                END ASC,
+                CASE WHEN $7 THEN uc.id END ASC,
+                CASE WHEN NOT $7 THEN
+                    (CASE WHEN $5 THEN COALESCE(uc.accepted_at, uc.created_at) ELSE uc.created_at END)
+                END DESC,
+                CASE WHEN NOT $7 THEN uc.id END DESC
+            LIMIT $9
+            "#,
+            user_id,
+            as_sender,
+            is_accepted,
+            filter.search,
+            by_accepted,
+            filter.after.map(|c| c.at),
+            filter.ascending,
+            filter.after.map(|c| c.id),
+            filter.limit + 1
         )
         .fetch_all(&app_state.pg_pool)
         .await?;
 
-        Ok(rows)
+        // one extra row was fetched to tell whether another page follows
+        let next_cursor = if items.len() as i64 > filter.limit {
+            items.truncate(filter.limit as usize);
+            items.last().map(|last| {
+                ConnectionCursor {
+                    at: if by_accepted {
+                        last.accepted_at.unwrap_or(last.created_at)
+                    } else {
+                        last.created_at
+                    },
+                    id: last.id,
+                }
+                .encode()
+            })
+        } else {
+            None
+        };
+        Ok(ConnectionPage { items, next_cursor })
+    }
+
+    // users whose `check` lists this user's devices, i.e. who get its presence events
+    pub async fn presence_watchers(
+        email: &str,
+        device_id: &str,
+        app_state: &AppState,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        // members of the teams the device is shared with watch it too
+        sqlx::query_scalar!(
+            r#"
+            SELECT u2.email AS "email!"
+            FROM connection_access ca
+            JOIN users u1 ON ca.owner_id = u1.id
+            JOIN users u2 ON ca.grantee_id = u2.id
+            WHERE u1.email = $1
+            AND NOT EXISTS (
+                SELECT 1 FROM user_blocks b
+                WHERE (b.blocker_id = u1.id AND b.blocked_id = u2.id)
+                OR (b.blocker_id = u2.id AND b.blocked_id = u1.id)
+            )
+            UNION
+            SELECT u2.email AS "email!"
+            FROM team_device_grants g
+            JOIN users u1 ON g.owner_id = u1.id
+            JOIN team_members m ON m.team_id = g.team_id AND m.user_id <> g.owner_id
+            JOIN users u2 ON m.user_id = u2.id
+            WHERE u1.email = $1 AND g.device_id = $2
+            AND NOT EXISTS (
+                SELECT 1 FROM user_blocks b
+                WHERE (b.blocker_id = u1.id AND b.blocked_id = u2.id)
+                OR (b.blocker_id = u2.id AND b.blocked_id = u1.id)
+            )
+            "#,
+            email,
+            device_id
+        )
+        .fetch_all(&app_state.pg_pool)
+        .await
     }
 }
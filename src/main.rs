@@ -1,11 +1,16 @@
 use serde_json::Value;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
 use dotenv::dotenv;
 
 use crate::app_state::Tx;
+use crate::routes::socket::backpressure::SocketHandle;
+use crate::routes::user_connection::expiry::start_expiry_sweeper;
+use crate::utils::ice_service::stun_server::start_stun_server;
+use crate::utils::ice_service::turn_server::start_turn_server;
 use crate::utils::mail_service::mailer::Mailer;
 use crate::utils::tera_service::tera_renderer::TeraRenderer;
 use crate::{app_state::AppState, db::connect_db::connect_db};
@@ -24,7 +29,7 @@ async fn main() {
         Arc::new(RwLock::new(HashMap::new()));
     let socket_connections: Arc<RwLock<HashMap<String, Tx>>> =
         Arc::new(RwLock::new(HashMap::new()));
-    let socket_id_to_connection: Arc<RwLock<HashMap<String, Tx>>> =
+    let socket_id_to_connection: Arc<RwLock<HashMap<String, SocketHandle>>> =
         Arc::new(RwLock::new(HashMap::new()));
     let email_device_to_socket: Arc<RwLock<HashMap<String, HashMap<String, String>>>> =
         Arc::new(RwLock::new(HashMap::new()));
@@ -38,6 +43,8 @@ async fn main() {
         socket_connections,
         socket_id_to_connection,
         email_device_to_socket,
+        pod_id: uuid::Uuid::new_v4().to_string(),
+        pending_deliveries: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
     };
 
     sqlx::migrate!("./migrations")
@@ -45,7 +52,16 @@ async fn main() {
         .await
         .unwrap();
 
+    start_stun_server().await;
+    start_turn_server(app_state.clone()).await;
+    start_expiry_sweeper(app_state.clone());
+
     let router = routes::app_router::app_router(app_state);
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, router).await.unwrap()
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap()
 }
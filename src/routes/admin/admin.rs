@@ -0,0 +1,298 @@
+use axum::{
+    Extension, Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    db::models::{
+        audit_event::{ADMIN_ACTION, AuditEntry},
+        login_token::LoginToken,
+        turn_usage::TurnUsage,
+        user::{ROLE_ADMIN, ROLE_USER, User, UserAccount},
+    },
+    routes::{
+        audit::audit::{AuditQuery, events_response},
+        ice::ice::UsageQuery,
+        socket::{
+            backpressure::queue_stats,
+            redis_manager::{force_disconnect, get_user_devices},
+        },
+    },
+    utils::{auth_middleware::AuthUser, client_info::ClientInfo},
+};
+
+#[derive(Deserialize, Debug)]
+pub struct UserSearchQuery {
+    search: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RoleBody {
+    role: String,
+}
+
+pub fn admin(state: AppState) -> Router {
+    Router::new()
+        .route("/users", get(list_users).with_state(state.clone()))
+        .route("/users/{user_id}", get(get_user).with_state(state.clone()))
+        .route(
+            "/users/{user_id}/suspend",
+            post(suspend_user).with_state(state.clone()),
+        )
+        .route(
+            "/users/{user_id}/unsuspend",
+            post(unsuspend_user).with_state(state.clone()),
+        )
+        .route(
+            "/users/{user_id}/role",
+            post(set_role).with_state(state.clone()),
+        )
+        .route(
+            "/users/{user_id}/revoke_tokens",
+            post(revoke_tokens).with_state(state.clone()),
+        )
+        .route(
+            "/users/{user_id}/devices",
+            get(list_devices).with_state(state.clone()),
+        )
+        .route(
+            "/users/{user_id}/devices/{device_id}/disconnect",
+            post(disconnect_device).with_state(state.clone()),
+        )
+        .route("/audit/events", get(audit_events).with_state(state.clone()))
+        .route("/turn/usage", get(turn_usage).with_state(state.clone()))
+        .route("/sockets", get(socket_queues).with_state(state.clone()))
+}
+
+fn record_admin_action(
+    state: &AppState,
+    auth_user: &AuthUser,
+    client: &ClientInfo,
+    target: Uuid,
+    operation: &str,
+    details: Value,
+) {
+    let mut details = details;
+    details["operation"] = json!(operation);
+    AuditEntry::new(ADMIN_ACTION)
+        .actor(auth_user.id)
+        .target(target.to_string())
+        .client(client)
+        .details(details)
+        .record(state);
+}
+
+fn account_response(result: Result<UserAccount, sqlx::Error>) -> (StatusCode, Json<Value>) {
+    match result {
+        Ok(account) => (StatusCode::OK, Json(json!({"res": account}))),
+        Err(sqlx::Error::RowNotFound) => (StatusCode::NOT_FOUND, Json(json!({"res": null}))),
+        Err(e) => {
+            println!("{e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"res": null})),
+            )
+        }
+    }
+}
+
+pub async fn list_users(
+    State(state): State<AppState>,
+    Query(query): Query<UserSearchQuery>,
+) -> impl IntoResponse {
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let search = query.search.filter(|s| !s.is_empty());
+    match User::list_accounts(search, limit, offset, state).await {
+        Ok(accounts) => (StatusCode::OK, Json(json!({"res": accounts}))),
+        Err(e) => {
+            println!("{e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"res": []})))
+        }
+    }
+}
+
+pub async fn get_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    account_response(User::get_account(user_id, state).await)
+}
+
+pub async fn suspend_user(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    if user_id == auth_user.id {
+        return (StatusCode::BAD_REQUEST, Json(json!({"res": null})));
+    }
+    let result = User::set_suspended(user_id, true, state.clone()).await;
+    if let Ok(account) = &result {
+        record_admin_action(&state, &auth_user, &client, user_id, "suspend", json!({}));
+        // a suspended account loses its sessions and live sockets right away
+        if let Err(e) = LoginToken::revoke_all(user_id, state.clone()).await {
+            println!("{e}");
+        }
+        if let Err(e) = force_disconnect(&state, &account.email, "*", "account suspended").await {
+            eprintln!("Failed to disconnect suspended user: {}", e);
+        }
+    }
+    account_response(result)
+}
+
+pub async fn unsuspend_user(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let result = User::set_suspended(user_id, false, state.clone()).await;
+    if result.is_ok() {
+        record_admin_action(&state, &auth_user, &client, user_id, "unsuspend", json!({}));
+    }
+    account_response(result)
+}
+
+pub async fn set_role(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<RoleBody>,
+) -> impl IntoResponse {
+    if user_id == auth_user.id || ![ROLE_USER, ROLE_ADMIN].contains(&payload.role.as_str()) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"res": null})));
+    }
+    let result = User::set_role(user_id, payload.role.clone(), state.clone()).await;
+    if result.is_ok() {
+        let details = json!({"role": payload.role});
+        record_admin_action(&state, &auth_user, &client, user_id, "set_role", details);
+    }
+    account_response(result)
+}
+
+pub async fn revoke_tokens(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match LoginToken::revoke_all(user_id, state.clone()).await {
+        Ok(revoked) => {
+            let details = json!({"revoked": revoked});
+            record_admin_action(
+                &state,
+                &auth_user,
+                &client,
+                user_id,
+                "revoke_tokens",
+                details,
+            );
+            (StatusCode::OK, Json(json!({"res": {"revoked": revoked}})))
+        }
+        Err(e) => {
+            println!("{e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"res": null})),
+            )
+        }
+    }
+}
+
+pub async fn list_devices(
+    State(state): State<AppState>,
+    Path(user_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let account = match User::get_account(user_id, state.clone()).await {
+        Ok(account) => account,
+        Err(e) => return account_response(Err(e)),
+    };
+    match get_user_devices(&state, &account.email).await {
+        Ok(devices) => (StatusCode::OK, Json(json!({"res": devices}))),
+        Err(e) => {
+            eprintln!("{e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"res": []})))
+        }
+    }
+}
+
+pub async fn disconnect_device(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path((user_id, device_id)): Path<(Uuid, String)>,
+) -> impl IntoResponse {
+    let account = match User::get_account(user_id, state.clone()).await {
+        Ok(account) => account,
+        Err(e) => return account_response(Err(e)).0,
+    };
+    match force_disconnect(
+        &state,
+        &account.email,
+        &device_id,
+        "disconnected by administrator",
+    )
+    .await
+    {
+        Ok(_) => {
+            let details = json!({"device_id": device_id});
+            record_admin_action(
+                &state,
+                &auth_user,
+                &client,
+                user_id,
+                "disconnect_device",
+                details,
+            );
+            StatusCode::ACCEPTED
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+pub async fn audit_events(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> impl IntoResponse {
+    let actor_id = query.actor_id;
+    events_response(query.into_filter(actor_id), state).await
+}
+
+// relayed bytes per user over the range, heaviest first
+pub async fn turn_usage(
+    State(state): State<AppState>,
+    Query(query): Query<UsageQuery>,
+) -> impl IntoResponse {
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let offset = query.offset.unwrap_or(0).max(0);
+    match TurnUsage::totals(query.from, query.to, limit, offset, &state).await {
+        Ok(totals) => (StatusCode::OK, Json(json!({"res": totals}))),
+        Err(e) => {
+            println!("{e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"res": []})))
+        }
+    }
+}
+
+// sockets on the pod that answered, with how far behind their readers are
+pub async fn socket_queues(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(json!({"res": {"pod_id": state.pod_id, "sockets": queue_stats(&state).await}})),
+    )
+}
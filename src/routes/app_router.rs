@@ -3,11 +3,11 @@ use axum::{Router, routing::get};
 use crate::{
     app_state::AppState,
     routes::{
-        auth::auth_router::auth_router,
-        socket::socket::ws_route,
-        user_connection::user_connection::user_connection,
+        admin::admin::admin, audit::audit::audit, auth::auth_router::auth_router,
+        guest_link::guest_link::guest_link, ice::ice::ice, socket::socket::ws_route,
+        team::team::team, user_connection::user_connection::user_connection,
     },
-    utils::auth_middleware::auth_middleware,
+    utils::auth_middleware::{admin_middleware, auth_middleware},
 };
 
 pub fn app_router(state: AppState) -> Router {
@@ -25,5 +25,42 @@ fn home(state: AppState) -> Router {
                 auth_middleware,
             )),
         )
+        .nest(
+            "/teams",
+            team(state.clone()).layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                auth_middleware,
+            )),
+        )
+        .nest(
+            "/guest-links",
+            guest_link(state.clone()).layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                auth_middleware,
+            )),
+        )
+        .nest(
+            "/ice",
+            ice(state.clone()).layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                auth_middleware,
+            )),
+        )
+        .nest(
+            "/audit",
+            audit(state.clone()).layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                auth_middleware,
+            )),
+        )
+        .nest(
+            "/admin",
+            admin(state.clone())
+                .layer(axum::middleware::from_fn(admin_middleware))
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    auth_middleware,
+                )),
+        )
         .nest("/ws", ws_route(state.clone()))
 }
@@ -0,0 +1,73 @@
+use axum::{
+    Extension, Json, Router,
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    db::models::audit_event::{AuditEvent, AuditFilter},
+    utils::auth_middleware::AuthUser,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct AuditQuery {
+    // only honoured on the admin endpoint
+    pub actor_id: Option<Uuid>,
+    // comma separated, e.g. `login,login_failed`
+    pub action: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl AuditQuery {
+    pub fn into_filter(self, actor_id: Option<Uuid>) -> AuditFilter {
+        let actions = self
+            .action
+            .map(|a| {
+                a.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|a| !a.is_empty());
+        AuditFilter {
+            actor_id,
+            actions,
+            from: self.from,
+            to: self.to,
+            limit: self.limit.unwrap_or(50).clamp(1, 500),
+            offset: self.offset.unwrap_or(0).max(0),
+        }
+    }
+}
+
+pub fn audit(state: AppState) -> Router {
+    Router::new().route("/events", get(my_events).with_state(state.clone()))
+}
+
+pub async fn events_response(filter: AuditFilter, state: AppState) -> (StatusCode, Json<Value>) {
+    match AuditEvent::list(filter, state).await {
+        Ok(events) => (StatusCode::OK, Json(json!({"res": events}))),
+        Err(e) => {
+            println!("{e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"res": []})))
+        }
+    }
+}
+
+pub async fn my_events(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> impl IntoResponse {
+    events_response(query.into_filter(Some(auth_user.id)), state).await
+}
@@ -1,16 +1,22 @@
-use axum::http::StatusCode;
+use axum::http::{HeaderValue, StatusCode, header};
 use axum::{Json, Router, extract::State, response::IntoResponse, routing::post};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::app_state::AppState;
+use crate::db::models::audit_event::{AuditEntry, LOGIN, LOGIN_FAILED};
 use crate::db::models::login_token::LoginToken;
 use crate::db::models::user::User;
+use crate::utils::auth_middleware::SESSION_COOKIE;
+use crate::utils::client_info::ClientInfo;
 
 #[derive(Deserialize, Debug)]
 struct LoginRequest {
     email: String,
     password: String,
+    // browser clients ask for an HttpOnly session cookie instead of handling the token
+    #[serde(default)]
+    cookie: bool,
 }
 
 #[derive(Serialize)]
@@ -24,22 +30,78 @@ pub fn login(state: AppState) -> Router {
         .with_state(state)
 }
 
+// the session cookie is only sent over https unless a plain http deployment opts out
+fn secure_cookies() -> bool {
+    std::env::var("SESSION_COOKIE_SECURE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(true)
+}
+
 async fn login_handler(
     State(state): State<AppState>,
+    client: ClientInfo,
     Json(payload): Json<LoginRequest>,
 ) -> impl IntoResponse {
-    let user_id: Uuid = match User::validate_login(payload.email, payload.password, state.clone())
-        .await
+    // failed attempts are attributed to the targeted account, when it exists
+    let failed = |reason: &str| {
+        AuditEntry::new(LOGIN_FAILED)
+            .actor_email(&payload.email)
+            .target(payload.email.clone())
+            .client(&client)
+            .details(serde_json::json!({"reason": reason}))
+            .record(&state);
+    };
+
+    let user_id: Uuid = match User::validate_login(
+        payload.email.clone(),
+        payload.password.clone(),
+        state.clone(),
+    )
+    .await
     {
         Ok(id) => Uuid::parse_str(id.as_str()).unwrap(),
-        Err(_) => return (StatusCode::UNAUTHORIZED, "Invalid email or password").into_response(),
+        Err(_) => {
+            failed("invalid_credentials");
+            return (StatusCode::UNAUTHORIZED, "Invalid email or password").into_response();
+        }
     };
 
-    match LoginToken::create(user_id, state).await {
-        Ok(token) => Json(LoginResponse {
-            token: token.to_string(),
-        })
-        .into_response(),
+    match User::get_account(user_id, state.clone()).await {
+        Ok(account) if account.is_suspended() => {
+            failed("suspended");
+            return (StatusCode::FORBIDDEN, "Account suspended").into_response();
+        }
+        Ok(_) => {}
+        Err(_) => return (StatusCode::UNAUTHORIZED, "Invalid email or password").into_response(),
+    }
+
+    match LoginToken::create(user_id, state.clone()).await {
+        Ok(token) => {
+            AuditEntry::new(LOGIN)
+                .actor(user_id)
+                .client(&client)
+                .details(serde_json::json!({"cookie": payload.cookie}))
+                .record(&state);
+            let mut response = Json(LoginResponse {
+                token: token.to_string(),
+            })
+            .into_response();
+            if payload.cookie {
+                let secure = if secure_cookies() { "; Secure" } else { "" };
+                let cookie = format!(
+                    "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}{}",
+                    SESSION_COOKIE,
+                    token,
+                    LoginToken::TTL_SECONDS,
+                    secure
+                );
+                if let Ok(value) = HeaderValue::from_str(&cookie) {
+                    response.headers_mut().insert(header::SET_COOKIE, value);
+                }
+            }
+            response
+        }
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create token").into_response(),
     }
 }
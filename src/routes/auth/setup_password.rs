@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use axum::{
     Form, Router,
     extract::{Query, State},
-    http::{HeaderMap, StatusCode},
+    http::StatusCode,
     response::Html,
     routing::{get, post},
 };
@@ -13,12 +13,20 @@ use serde_json::json;
 
 use crate::{
     app_state::AppState,
-    db::models::user::User,
-    utils::{hash_service::bcrypt::hash_password, resolve_base_url::resolve_base_url},
+    db::models::{
+        audit_event::{AuditEntry, PASSWORD_SETUP},
+        connection_invitation::ConnectionInvitation,
+        user::User,
+    },
+    utils::{
+        client_info::ClientInfo, hash_service::bcrypt::hash_password, resolve_base_url::BaseUrl,
+    },
 };
 #[derive(Deserialize, Debug)]
 pub struct UserPassword {
     password: String,
+    #[serde(default)]
+    display_name: String,
 }
 
 pub fn setup_password(state: AppState) -> Router {
@@ -29,11 +37,13 @@ pub fn setup_password(state: AppState) -> Router {
 
 async fn password_setup_confirmation(
     State(state): State<AppState>,
+    client: ClientInfo,
     Query(params): Query<HashMap<String, String>>,
     Form(payload): Form<UserPassword>,
 ) -> Html<String> {
     let token = params.get("token").unwrap();
     let password = payload.password;
+    let display_name = Some(payload.display_name.trim().to_string()).filter(|n| !n.is_empty());
     let connection = state
         .redis_pool
         .get()
@@ -47,9 +57,18 @@ async fn password_setup_confirmation(
 
     match email_value {
         Ok(email) => {
-            let password_hash = hash_password(password.to_string());
-            match User::create(email, password_hash, state.clone()).await {
+            let password_hash = hash_password(password.to_string()).await;
+            match User::create(email.clone(), password_hash, display_name, state.clone()).await {
                 Ok(_) => {
+                    AuditEntry::new(PASSWORD_SETUP)
+                        .actor_email(&email)
+                        .client(&client)
+                        .record(&state);
+                    if let Err(e) =
+                        ConnectionInvitation::convert_to_requests(email, state.clone()).await
+                    {
+                        println!("{e}");
+                    }
                     let html = state
                         .tera_renderer
                         .render("pages/password-setup-success.html", json!({}))
@@ -71,7 +90,7 @@ async fn password_setup_confirmation(
 
 async fn password_setup(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    base_url: BaseUrl,
     Query(params): Query<HashMap<String, String>>,
 ) -> Html<String> {
     let token = params.get("token").unwrap();
@@ -88,11 +107,7 @@ async fn password_setup(
 
     match email_value {
         Ok(email) => {
-            let password_setup_url = format!(
-                "{}/auth/setup-password?token={}",
-                resolve_base_url(&headers),
-                token
-            );
+            let password_setup_url = format!("{}/auth/setup-password?token={}", base_url.0, token);
             let context = json!({ "password_setup_url": password_setup_url,"email": email});
             let html = state
                 .tera_renderer
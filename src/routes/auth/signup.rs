@@ -1,18 +1,13 @@
-use axum::{
-    Json, Router,
-    extract::State,
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
-    routing::post,
-};
+use axum::{Json, Router, extract::State, http::StatusCode, response::IntoResponse, routing::post};
 use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     app_state::AppState,
+    db::models::audit_event::{AuditEntry, SIGNUP_EMAIL_FAILED, SIGNUP_REQUESTED},
     utils::{
-        hash_service::hash_generator::generate_hash, mail_service::mail_data::MailData,
-        resolve_base_url::resolve_base_url,
+        client_info::ClientInfo, hash_service::hash_generator::generate_hash,
+        mail_service::mail_data::MailData, resolve_base_url::BaseUrl,
     },
 };
 
@@ -32,43 +27,79 @@ pub fn signup(state: AppState) -> Router {
         .with_state(state)
 }
 
-async fn signup_handler(
-    State(state): State<AppState>,
-    headers: HeaderMap,
-    Json(payload): Json<SignupRequest>,
-) -> impl IntoResponse {
-    // todo: make a time based email sending limiter
+pub const SIGNUP_LINK_TTL: u64 = 600;
+
+// stores a password setup token for the email and returns the link that redeems it
+pub async fn create_signup_link(
+    state: &AppState,
+    base_url: &BaseUrl,
+    email: &str,
+    ttl_seconds: u64,
+) -> Result<String, StatusCode> {
     let token = generate_hash();
-    let signup_url = format!(
-        "{}/auth/setup-password?token={}",
-        resolve_base_url(&headers),
-        token.clone()
-    );
+    let signup_url = format!("{}/auth/setup-password?token={}", base_url.0, token.clone());
 
-    let conn = state
+    let mut conn = state
         .redis_pool
         .get()
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let _: () = conn
-        .unwrap()
-        .set_ex(token, payload.email.clone(), 600)
+        .set_ex(token, email.to_string(), ttl_seconds)
         .await
-        .unwrap();
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(signup_url)
+}
+
+// sends in the background; failures end up in the audit log under `failed_action`
+// instead of being lost
+pub fn send_mail_in_background(
+    state: AppState,
+    mail: MailData,
+    client: ClientInfo,
+    failed_action: &'static str,
+) {
+    tokio::spawn(async move {
+        let res = state.mailer.send(&state.tera_renderer, mail.clone()).await;
+        if let Err(e) = res {
+            eprintln!("{:?} email could not be sent: {}", mail, e);
+            AuditEntry::new(failed_action)
+                .target(mail.to.clone())
+                .client(&client)
+                .details(serde_json::json!({"error": e.to_string(), "subject": mail.subject}))
+                .record(&state);
+        }
+    });
+}
+
+async fn signup_handler(
+    State(state): State<AppState>,
+    base_url: BaseUrl,
+    client: ClientInfo,
+    Json(payload): Json<SignupRequest>,
+) -> impl IntoResponse {
+    // todo: make a time based email sending limiter
+    let signup_url =
+        match create_signup_link(&state, &base_url, &payload.email, SIGNUP_LINK_TTL).await {
+            Ok(signup_url) => signup_url,
+            Err(status) => return status.into_response(),
+        };
 
     let mail = MailData::with_template(
-        String::from(payload.email.clone()),
+        payload.email.clone(),
         "Setup Password".into(),
         "mails/signup.html".into(),
         serde_json::json!({ "signup_url":signup_url }),
     );
 
-    tokio::spawn(async move {
-        let res = state.mailer.send(&state.tera_renderer, mail.clone()).await;
-        res.expect(&format!("{:?} email could not be sent", mail.clone()))
-        // todo: save it in paper trails or make it safe in some way
-    });
+    AuditEntry::new(SIGNUP_REQUESTED)
+        .target(payload.email.clone())
+        .client(&client)
+        .record(&state);
+
+    send_mail_in_background(state, mail, client, SIGNUP_EMAIL_FAILED);
 
     (
         StatusCode::CREATED,
@@ -76,4 +107,5 @@ async fn signup_handler(
             email: payload.email,
         }),
     )
+        .into_response()
 }
@@ -0,0 +1,145 @@
+use axum::{
+    Extension, Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    db::models::{
+        audit_event::{AuditEntry, GUEST_LINK_CREATE, GUEST_LINK_REVOKE},
+        guest_link::GuestLink,
+        user_connection::{PERMISSION_VIEW, is_permission},
+    },
+    routes::socket::{events::guest::guest_email, redis_manager::force_disconnect},
+    utils::{auth_middleware::AuthUser, client_info::ClientInfo, resolve_base_url::BaseUrl},
+};
+
+const DEFAULT_GUEST_LINK_TTL: i64 = 15 * 60;
+const MAX_GUEST_LINK_TTL: i64 = 24 * 60 * 60;
+
+#[derive(Deserialize, Debug)]
+pub struct CreateGuestLinkBody {
+    device_id: String,
+    // view only when omitted
+    permissions: Option<Vec<String>>,
+    ttl_seconds: Option<i64>,
+}
+
+pub fn guest_link(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/",
+            get(list_guest_links)
+                .post(create_guest_link)
+                .with_state(state.clone()),
+        )
+        .route(
+            "/{link_id}/revoke",
+            post(revoke_guest_link).with_state(state.clone()),
+        )
+}
+
+pub async fn create_guest_link(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    base_url: BaseUrl,
+    client: ClientInfo,
+    Json(payload): Json<CreateGuestLinkBody>,
+) -> impl IntoResponse {
+    let permissions = payload
+        .permissions
+        .unwrap_or_else(|| vec![PERMISSION_VIEW.to_string()]);
+    let ttl = payload.ttl_seconds.unwrap_or(DEFAULT_GUEST_LINK_TTL);
+    if payload.device_id.is_empty()
+        || permissions.is_empty()
+        || !permissions.iter().all(|p| is_permission(p))
+        || !(1..=MAX_GUEST_LINK_TTL).contains(&ttl)
+    {
+        return (StatusCode::BAD_REQUEST, Json(json!({"res": null})));
+    }
+
+    let expires_at = Utc::now() + Duration::seconds(ttl);
+    match GuestLink::create(
+        auth_user.id,
+        payload.device_id.clone(),
+        permissions.clone(),
+        expires_at,
+        state.clone(),
+    )
+    .await
+    {
+        Ok(link) => {
+            AuditEntry::new(GUEST_LINK_CREATE)
+                .actor(auth_user.id)
+                .target(link.id.to_string())
+                .client(&client)
+                .details(json!({"device_id": payload.device_id, "permissions": permissions}))
+                .record(&state);
+            let url = format!("{}/guest?token={}", base_url.0, link.token);
+            (
+                StatusCode::CREATED,
+                Json(json!({"res": {
+                    "id": link.id,
+                    "url": url,
+                    "token": link.token,
+                    "code": link.code,
+                    "expires_at": link.expires_at,
+                }})),
+            )
+        }
+        Err(e) => {
+            println!("{e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"res": null})),
+            )
+        }
+    }
+}
+
+pub async fn list_guest_links(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    if let Ok(links) = GuestLink::list(auth_user.id, state).await {
+        (StatusCode::OK, Json(json!({"res": links})))
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"res": []})))
+    }
+}
+
+// also ends the guest's socket session if the link was already used
+pub async fn revoke_guest_link(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path(link_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match GuestLink::revoke(auth_user.id, link_id, state.clone()).await {
+        Ok(_) => {
+            AuditEntry::new(GUEST_LINK_REVOKE)
+                .actor(auth_user.id)
+                .target(link_id.to_string())
+                .client(&client)
+                .record(&state);
+            if let Err(e) =
+                force_disconnect(&state, &guest_email(link_id), "*", "guest link revoked").await
+            {
+                eprintln!("Failed to disconnect guest session: {}", e);
+            }
+            StatusCode::NO_CONTENT
+        }
+        Err(sqlx::Error::RowNotFound) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            println!("{e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
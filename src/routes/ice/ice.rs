@@ -0,0 +1,62 @@
+use axum::{
+    Extension, Json, Router,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+};
+
+use chrono::NaiveDate;
+use serde::Deserialize;
+
+use crate::{
+    app_state::AppState,
+    db::models::turn_usage::TurnUsage,
+    utils::{
+        auth_middleware::AuthUser,
+        ice_service::ice_config::{ice_config, ice_host},
+    },
+};
+
+#[derive(Deserialize, Debug)]
+pub struct UsageQuery {
+    // inclusive days, e.g. 2026-10-01
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    // only honoured on the admin endpoint
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+pub fn ice(state: AppState) -> Router {
+    Router::new()
+        .route("/servers", get(ice_servers).with_state(state.clone()))
+        .route("/usage", get(my_usage).with_state(state.clone()))
+}
+
+// fresh TURN credentials on every call; clients refetch before `ttl` runs out
+pub async fn ice_servers(
+    Extension(auth_user): Extension<AuthUser>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let config = ice_config(&auth_user.email, ice_host(&headers).as_deref());
+    (StatusCode::OK, Json(serde_json::json!({"res": config})))
+}
+
+// what the embedded TURN server relayed for the caller, per day
+pub async fn my_usage(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    Query(query): Query<UsageQuery>,
+) -> impl IntoResponse {
+    match TurnUsage::daily(&auth_user.email, query.from, query.to, &state).await {
+        Ok(days) => (StatusCode::OK, Json(serde_json::json!({"res": days}))),
+        Err(e) => {
+            println!("{e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({"res": []})),
+            )
+        }
+    }
+}
@@ -1,4 +1,9 @@
+pub mod admin;
 pub mod app_router;
+pub mod audit;
 pub mod auth;
+pub mod guest_link;
+pub mod ice;
 pub mod socket;
+pub mod team;
 pub mod user_connection;
@@ -0,0 +1,128 @@
+use axum::extract::ws::Message;
+use serde::Serialize;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use tokio::sync::{Notify, mpsc::error::TrySendError};
+
+use crate::app_state::{AppState, Tx};
+
+// messages waiting for a socket's writer
+pub const SOCKET_BUFFER: usize = 100;
+// from this depth on, messages that can be lost without breaking the call are dropped
+// so the rest of the buffer stays free for the ones that can't
+const DROP_DEPTH: usize = SOCKET_BUFFER * 3 / 4;
+// close code for a socket whose buffer ran full, from the private use range
+pub const CLOSE_SLOW_CONSUMER: u16 = 4008;
+
+// what is delivered to a socket by other sockets and the Redis subscriber, never awaited
+// so one slow reader can't hold up the sender
+#[derive(Clone)]
+pub struct SocketHandle {
+    pub tx: Tx,
+    pub queue: Arc<SocketQueue>,
+}
+
+#[derive(Default)]
+pub struct SocketQueue {
+    dropped: AtomicU64,
+    peak_depth: AtomicUsize,
+    overflowed: AtomicBool,
+    // wakes the socket's writer to close it
+    close: Notify,
+}
+
+impl SocketQueue {
+    pub async fn closed(&self) {
+        self.close.notified().await
+    }
+}
+
+pub enum Delivery {
+    Sent,
+    Dropped,
+    // the socket is gone or is being closed for falling behind
+    Closed,
+}
+
+// trickled candidates, like relay data, can be lost: the peers recover from missing some
+pub fn droppable(event: &str) -> bool {
+    event == "ice_candidate"
+}
+
+fn depth(tx: &Tx) -> usize {
+    tx.max_capacity() - tx.capacity()
+}
+
+pub fn deliver(handle: &SocketHandle, message: Message, droppable: bool) -> Delivery {
+    let queue = &handle.queue;
+    if queue.overflowed.load(Ordering::Relaxed) {
+        return Delivery::Closed;
+    }
+    if droppable && depth(&handle.tx) >= DROP_DEPTH {
+        queue.dropped.fetch_add(1, Ordering::Relaxed);
+        return Delivery::Dropped;
+    }
+    match handle.tx.try_send(message) {
+        Ok(()) => {
+            queue
+                .peak_depth
+                .fetch_max(depth(&handle.tx), Ordering::Relaxed);
+            Delivery::Sent
+        }
+        Err(TrySendError::Full(_)) if droppable => {
+            queue.dropped.fetch_add(1, Ordering::Relaxed);
+            Delivery::Dropped
+        }
+        Err(TrySendError::Full(_)) => {
+            queue.overflowed.store(true, Ordering::Relaxed);
+            queue.close.notify_one();
+            Delivery::Closed
+        }
+        Err(TrySendError::Closed(_)) => Delivery::Closed,
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SocketQueueStats {
+    pub socket_id: String,
+    pub email: Option<String>,
+    pub device_id: Option<String>,
+    pub depth: usize,
+    pub capacity: usize,
+    pub peak_depth: usize,
+    pub dropped: u64,
+    pub overflowed: bool,
+}
+
+// this pod's sockets, fullest first
+pub async fn queue_stats(app_state: &AppState) -> Vec<SocketQueueStats> {
+    let mut owners = std::collections::HashMap::new();
+    for (email, devices) in app_state.email_device_to_socket.read().await.iter() {
+        for (device_id, socket_id) in devices {
+            owners.insert(socket_id.clone(), (email.clone(), device_id.clone()));
+        }
+    }
+    let mut stats: Vec<SocketQueueStats> = app_state
+        .socket_id_to_connection
+        .read()
+        .await
+        .iter()
+        .map(|(socket_id, handle)| {
+            let owner = owners.remove(socket_id);
+            SocketQueueStats {
+                socket_id: socket_id.clone(),
+                email: owner.as_ref().map(|(email, _)| email.clone()),
+                device_id: owner.map(|(_, device_id)| device_id),
+                depth: depth(&handle.tx),
+                capacity: handle.tx.max_capacity(),
+                peak_depth: handle.queue.peak_depth.load(Ordering::Relaxed),
+                dropped: handle.queue.dropped.load(Ordering::Relaxed),
+                overflowed: handle.queue.overflowed.load(Ordering::Relaxed),
+            }
+        })
+        .collect();
+    stats.sort_by(|a, b| b.depth.cmp(&a.depth).then(b.dropped.cmp(&a.dropped)));
+    stats
+}
@@ -1,58 +1,136 @@
+use std::collections::HashMap;
+
 use serde_json::Value;
 
 use crate::{
     app_state::AppState,
-    db::models::{user::User, user_connection::UserConnection},
+    db::models::{
+        device_grant::DeviceGrant, nickname::Nickname, team_device_grant::TeamDeviceGrant,
+        user::User, user_connection::UserConnection,
+    },
     routes::socket::{
         redis_manager::get_user_devices,
-        types::{DeviceInfo, UserDevicesResponse},
+        types::{DeviceInfo, SOURCE_DIRECT, SOURCE_TEAM, TeamRef, UserDevicesResponse},
     },
 };
 
+// online devices of a user, from Redis or this pod's index when Redis is down
+async fn online_devices(app_state: &AppState, email: &str) -> Vec<DeviceInfo> {
+    match get_user_devices(app_state, email).await {
+        Ok(devices) => devices,
+        Err(_) => {
+            // If Redis fails, try local fallback
+            let user_index = app_state.user_index.read().await;
+            user_index
+                .get(email)
+                .map(|device_map| {
+                    device_map
+                        .values()
+                        .filter_map(|value| {
+                            serde_json::from_value::<DeviceInfo>(value.clone()).ok()
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+    }
+}
+
 pub async fn check_users(from_email: String, app_state: AppState) -> Vec<UserDevicesResponse> {
     let Ok(user_uuid) = User::get_user_id(from_email, app_state.clone()).await else {
         return Vec::new();
     };
 
-    // Get connected users from database
-    let connected_users_result = UserConnection::connected_to(user_uuid, app_state.clone()).await;
+    // Get the users whose devices this user may access from database
+    let connected_users_result = UserConnection::accessible_owners(user_uuid, &app_state).await;
 
     let connected_users = match connected_users_result {
         Ok(users) => users,
         Err(_) => return Vec::new(),
     };
 
+    let Ok(granted_devices) = DeviceGrant::granted_devices(user_uuid, &app_state).await else {
+        return Vec::new();
+    };
+
+    let Ok(team_devices) = TeamDeviceGrant::reachable(user_uuid, &app_state).await else {
+        return Vec::new();
+    };
+
     let mut responses = Vec::new();
+    let mut online: HashMap<String, Vec<DeviceInfo>> = HashMap::new();
 
     // For each connected user, get their devices from Redis
     for user in connected_users {
-        let email = user.from_email;
-
-        match get_user_devices(&app_state, &email).await {
-            Ok(devices) => {
-                if !devices.is_empty() {
-                    responses.push(UserDevicesResponse { email, devices });
-                }
-            }
-            Err(_) => {
-                // If Redis fails, try local fallback
-                let user_index = app_state.user_index.read().await;
-                if let Some(device_map) = user_index.get(&email) {
-                    let local_devices: Vec<DeviceInfo> = device_map
-                        .iter()
-                        .filter_map(|(_device_id, value)| {
-                            serde_json::from_value::<DeviceInfo>(value.clone()).ok()
-                        })
-                        .collect();
-
-                    if !local_devices.is_empty() {
-                        responses.push(UserDevicesResponse {
-                            email,
-                            devices: local_devices,
-                        });
-                    }
-                }
-            }
+        let email = user.email;
+        let allowed = granted_devices.get(&email);
+        let user_devices = online_devices(&app_state, &email).await;
+        let devices: Vec<DeviceInfo> = user_devices
+            .iter()
+            .filter(|device| allowed.is_none_or(|ids| ids.contains(&device.device_id)))
+            .cloned()
+            .collect();
+        online.insert(email.clone(), user_devices);
+
+        if !devices.is_empty() {
+            responses.push(UserDevicesResponse {
+                email,
+                nickname: None,
+                devices,
+                permissions: user.permissions,
+                source: SOURCE_DIRECT.to_string(),
+                team: None,
+            });
+        }
+    }
+
+    // Devices shared with one of the user's teams, one entry per team, owner and permissions
+    for grant in team_devices {
+        if !online.contains_key(&grant.owner_email) {
+            let devices = online_devices(&app_state, &grant.owner_email).await;
+            online.insert(grant.owner_email.clone(), devices);
+        }
+        let Some(device) = online
+            .get(&grant.owner_email)
+            .and_then(|devices| devices.iter().find(|d| d.device_id == grant.device_id))
+        else {
+            continue;
+        };
+
+        let existing = responses.iter_mut().find(|r| {
+            r.email == grant.owner_email
+                && r.permissions == grant.permissions
+                && r.team.as_ref().is_some_and(|t| t.id == grant.team_id)
+        });
+        match existing {
+            Some(response) => response.devices.push(device.clone()),
+            None => responses.push(UserDevicesResponse {
+                email: grant.owner_email,
+                nickname: None,
+                devices: vec![device.clone()],
+                permissions: grant.permissions,
+                source: SOURCE_TEAM.to_string(),
+                team: Some(TeamRef {
+                    id: grant.team_id,
+                    name: grant.team_name,
+                }),
+            }),
+        }
+    }
+
+    // labels are cosmetic, check still answers if they can't be loaded
+    let contact_labels = Nickname::contact_labels(user_uuid, &app_state)
+        .await
+        .unwrap_or_default();
+    let device_labels = Nickname::device_labels(user_uuid, &app_state)
+        .await
+        .unwrap_or_default();
+    for response in responses.iter_mut() {
+        response.nickname = contact_labels.get(&response.email).cloned();
+        for device in response.devices.iter_mut() {
+            device.nickname = device_labels
+                .get(&(response.email.clone(), device.device_id.clone()))
+                .cloned();
         }
     }
 
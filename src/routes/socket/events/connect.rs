@@ -3,14 +3,16 @@ use tokio::sync::mpsc;
 
 use crate::{
     app_state::AppState,
-    routes::socket::types::SocketMessage,
+    routes::socket::{events::session::mark_connected, types::SocketMessage},
 };
 
-pub async fn on_connect(
-    _message: SocketMessage,
-    _state: AppState,
-    tx: &mpsc::Sender<Message>,
-) {
+pub async fn on_connect(message: SocketMessage, state: AppState, tx: &mpsc::Sender<Message>) {
+    // With a session id the peer connection of that session is up
+    if message.session_id.is_some() {
+        mark_connected(&message, &state, tx).await;
+        return;
+    }
+
     // Acknowledge connection
     let response = Message::Text(
         serde_json::json!({
@@ -1,43 +1,102 @@
 use crate::{
-    app_state::AppState,
-    routes::socket::redis_manager::{broadcast_user_left, remove_device_presence},
+    app_state::{AppState, Tx},
+    db::models::user_connection::UserConnection,
+    routes::socket::{
+        events::{
+            offline_queue::{mark_last_seen, queue_window_secs},
+            session::end_device_sessions,
+        },
+        redis_manager::{broadcast_user_left, registered_socket, remove_device_presence},
+    },
 };
 
+// the device's sessions survive a reconnect within the queue window, not a longer absence.
+// It is back when it registered with another socket than the one that went away
+fn end_sessions_unless_back(email: String, device: String, socket_id: String, state: AppState) {
+    tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_secs(queue_window_secs())).await;
+        if let Ok(Some(registered)) = registered_socket(&state, &email, &device).await
+            && registered != socket_id
+        {
+            return;
+        }
+        end_device_sessions(&email, &device, &state).await;
+    });
+}
+
 pub async fn disconnect_user(
     email: String,
     device: String,
     socket_id: String,
+    tx: &Tx,
     state: AppState,
 ) {
-    // Remove from Redis
-    if let Err(e) = remove_device_presence(&state, &email, &device).await {
-        eprintln!("Failed to remove device presence from Redis: {}", e);
-    } else {
-        // Broadcast to other pods that user left
-        if let Err(e) = broadcast_user_left(&state, &email, &device).await {
-            eprintln!("Failed to broadcast user left: {}", e);
+    // Remove from Redis, unless the device registered again before this cleanup ran
+    let removed = remove_device_presence(&state, &email, &device, &socket_id).await;
+    match &removed {
+        Ok(true) => {
+            // Broadcast to other pods that user left
+            let watchers = UserConnection::presence_watchers(&email, &device, &state)
+                .await
+                .unwrap_or_default();
+            if let Err(e) = broadcast_user_left(&state, &email, &device, &watchers).await {
+                eprintln!("Failed to broadcast user left: {}", e);
+            }
         }
+        Ok(false) => {}
+        Err(e) => eprintln!("Failed to remove device presence from Redis: {}", e),
     }
 
-    // Remove from local mappings
-    let key = format!("{}{}", email, device);
-    state.socket_connections.write().await.remove(&key);
+    // messages sent while it reconnects wait for it
+    if !matches!(removed, Ok(false)) {
+        if let Err(e) = mark_last_seen(&state, &email, &device).await {
+            eprintln!("Failed to mark device as last seen: {}", e);
+        }
+        end_sessions_unless_back(
+            email.clone(),
+            device.clone(),
+            socket_id.clone(),
+            state.clone(),
+        );
+    }
 
     // Remove from socket_id mapping
-    state.socket_id_to_connection.write().await.remove(&socket_id);
+    state
+        .socket_id_to_connection
+        .write()
+        .await
+        .remove(&socket_id);
+
+    // the other local mappings only while they still point at this socket
+    let key = format!("{}{}", email, device);
+    let mut socket_connections = state.socket_connections.write().await;
+    if socket_connections
+        .get(&key)
+        .is_some_and(|existing| existing.same_channel(tx))
+    {
+        socket_connections.remove(&key);
+    }
+    drop(socket_connections);
 
     // Remove from email_device mapping
     let mut email_device_map = state.email_device_to_socket.write().await;
-    if let Some(device_map) = email_device_map.get_mut(&email) {
+    if let Some(device_map) = email_device_map.get_mut(&email)
+        && device_map.get(&device) == Some(&socket_id)
+    {
         device_map.remove(&device);
         if device_map.is_empty() {
             email_device_map.remove(&email);
         }
     }
+    drop(email_device_map);
 
     // Remove from user_index
     let mut user_index = state.user_index.write().await;
-    if let Some(user_devices) = user_index.get_mut(&email) {
+    if let Some(user_devices) = user_index.get_mut(&email)
+        && user_devices
+            .get(&device)
+            .is_some_and(|info| info["socket_id"].as_str() == Some(socket_id.as_str()))
+    {
         user_devices.remove(&device);
         if user_devices.is_empty() {
             user_index.remove(&email);
@@ -3,80 +3,97 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::{Mutex, mpsc};
+use uuid::Uuid;
 
 use crate::{
     app_state::AppState,
+    db::models::{
+        device_grant::DeviceGrant,
+        team_device_grant::TeamDeviceGrant,
+        user_block::UserBlock,
+        user_connection::{PERMISSION_VIEW, UserConnection, is_permission},
+    },
     routes::socket::{
-        redis_manager::publish_message,
+        backpressure::{Delivery, deliver, droppable},
+        events::{
+            offline_queue::queue_or_not_found,
+            session::{answer_session, open_session, signal_allowed},
+        },
+        redis_manager::{device_online, local_socket, publish_message},
         types::{ErrorResponse, RedisMessage, SocketMessage},
     },
 };
 
-// Track pending messages waiting for delivery confirmation
+// messages waiting for another pod to confirm delivery, see AppState::pending_deliveries
 pub type PendingMessages = Arc<Mutex<HashMap<String, mpsc::Sender<bool>>>>;
 
 pub async fn forward_to_peer(
-    message: SocketMessage,
+    mut message: SocketMessage,
     state: AppState,
     tx: &mpsc::Sender<Message>,
-    pending_messages: &PendingMessages,
 ) {
-    // First, try to find locally
-    let local_found = {
-        let email_device_map = state.email_device_to_socket.read().await;
-
-        if let Some(device_map) = email_device_map.get(&message.to_email) {
-            if let Some(socket_id) = device_map.get(&message.to_device) {
-                let socket_connections = state.socket_id_to_connection.read().await;
-
-                if let Some(target_tx) = socket_connections.get(socket_id) {
-                    let msg_text = serde_json::to_string(&message).unwrap_or_default();
-                    let _ = target_tx.send(Message::Text(msg_text.into())).await;
-                    true
-                } else {
-                    false
-                }
-            } else {
-                false
-            }
-        } else {
-            false
-        }
+    let allowed = match message.event.as_str() {
+        "try_connect" => match session_permitted(&message, &state, tx).await {
+            Some(session_type) => open_session(&mut message, &session_type, &state, tx).await,
+            None => false,
+        },
+        "session_accept" | "session_reject" => answer_session(&mut message, &state, tx).await,
+        _ => signal_allowed(&message, &state, tx).await,
     };
+    if !allowed {
+        return;
+    }
+
+    deliver_to_peer(message, state, tx).await;
+}
+
+// routes the message to the target device on this or another pod, without any access checks;
+// a device that just went away gets it queued instead
+pub async fn deliver_to_peer(message: SocketMessage, state: AppState, tx: &mpsc::Sender<Message>) {
+    // First, try to find locally; a target that can't keep up is not waited for
+    if let Some(target) = local_socket(&state, &message.to_email, &message.to_device).await {
+        let msg_text = serde_json::to_string(&message).unwrap_or_default();
+        match deliver(
+            &target,
+            Message::Text(msg_text.into()),
+            droppable(&message.event),
+        ) {
+            Delivery::Sent | Delivery::Dropped => {}
+            // it is being disconnected, the message waits for it to come back
+            Delivery::Closed => queue_or_not_found(&message, &state, tx).await,
+        }
+        return;
+    }
 
-    if local_found {
+    // no pod has the device, so nobody would confirm
+    if let Ok(false) = device_online(&state, &message.to_email, &message.to_device).await {
+        queue_or_not_found(&message, &state, tx).await;
         return;
     }
 
     // If not found locally, publish to Redis for other pods
-    let message_id = format!(
-        "{}_{}_{}_{}_{}",
-        message.from_email,
-        message.from_device,
-        message.to_email,
-        message.to_device,
-        chrono::Utc::now().timestamp_millis()
-    );
+    let message_id = Uuid::new_v4().to_string();
 
     let redis_message = RedisMessage {
         target_email: message.to_email.clone(),
         target_device: message.to_device.clone(),
         socket_message: message.clone(),
-        sender_pod: None,
+        sender_pod: Some(state.pod_id.clone()),
         timestamp: Some(chrono::Utc::now().timestamp_millis() as u64),
+        message_id: Some(message_id.clone()),
     };
 
     // Create channel for delivery confirmation
     let (confirm_tx, mut confirm_rx) = mpsc::channel::<bool>(1);
     {
-        let mut pending = pending_messages.lock().await;
+        let mut pending = state.pending_deliveries.lock().await;
         pending.insert(message_id.clone(), confirm_tx);
     }
 
     if let Err(e) = publish_message(&state, &redis_message).await {
         eprintln!("Failed to publish message to Redis: {}", e);
         // Remove from pending and send error
-        let mut pending = pending_messages.lock().await;
+        let mut pending = state.pending_deliveries.lock().await;
         pending.remove(&message_id);
 
         let error_response = ErrorResponse {
@@ -95,38 +112,162 @@ pub async fn forward_to_peer(
         return;
     }
 
-    // Wait for delivery confirmation or timeout
-    let timeout = tokio::time::timeout(Duration::from_secs(5), confirm_rx.recv()).await;
+    // Wait for delivery confirmation or timeout, off the socket's loop so the
+    // sender's next messages are not held up
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let timeout = tokio::time::timeout(Duration::from_secs(5), confirm_rx.recv()).await;
 
-    // Clean up pending
-    let mut pending = pending_messages.lock().await;
-    pending.remove(&message_id);
+        // Clean up pending
+        state.pending_deliveries.lock().await.remove(&message_id);
 
-    match timeout {
-        Ok(Some(true)) => {
-            // Message was delivered successfully on another pod
-            // No action needed, target received it
+        match timeout {
+            Ok(Some(true)) => {
+                // Message was delivered successfully on another pod
+                // No action needed, target received it
+            }
+            _ => {
+                // Timeout or no confirmation - the device left in the meantime
+                queue_or_not_found(&message, &state, &tx).await;
+            }
         }
-        _ => {
-            // Timeout or no confirmation - target not found
-            let error_response = ErrorResponse {
-                event: "target_not_found".to_string(),
-                error: format!(
-                    "User {} with device {} is not online",
-                    message.to_email, message.to_device
+    });
+}
+
+// a session may only be started with a permission the device owner granted;
+// replies to the sender itself when it is not, otherwise gives the session type
+async fn session_permitted(
+    message: &SocketMessage,
+    state: &AppState,
+    tx: &mpsc::Sender<Message>,
+) -> Option<String> {
+    let session_type = message
+        .payload
+        .get("session_type")
+        .and_then(|v| v.as_str())
+        .unwrap_or(PERMISSION_VIEW);
+    if !is_permission(session_type) {
+        send_error(
+            tx,
+            "error",
+            format!("Unknown session_type: {}", session_type),
+            message,
+        )
+        .await;
+        return None;
+    }
+
+    match reachable_permissions(
+        &message.from_email,
+        &message.to_email,
+        &message.to_device,
+        state,
+    )
+    .await
+    {
+        Ok(Some(permissions)) if permissions.iter().any(|p| p == session_type) => {
+            Some(session_type.to_string())
+        }
+        Ok(Some(_)) => {
+            send_error(
+                tx,
+                "permission_denied",
+                format!(
+                    "User {} has not granted {} access",
+                    message.to_email, session_type
                 ),
-                target_email: Some(message.to_email.clone()),
-                target_device: Some(message.to_device.clone()),
-            };
-            let _ = tx
-                .send(Message::Text(
-                    serde_json::to_string(&error_response)
-                        .unwrap_or_default()
-                        .into(),
-                ))
-                .await;
+                message,
+            )
+            .await;
+            None
         }
+        // strangers can't tell whether the target exists
+        Ok(None) => {
+            send_target_not_found(message, tx).await;
+            None
+        }
+        Err(e) => {
+            println!("{e}");
+            send_error(
+                tx,
+                "error",
+                "Could not verify permissions".to_string(),
+                message,
+            )
+            .await;
+            None
+        }
+    }
+}
+
+// everything the sender may do on the target device, through a direct connection
+// or a shared team; None when the device is not reachable for the sender at all
+pub async fn reachable_permissions(
+    from_email: &str,
+    to_email: &str,
+    to_device: &str,
+    state: &AppState,
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    // blocked pairs look exactly like an offline target to the sender, team or not
+    if UserBlock::is_blocked_between(from_email, to_email, state).await? {
+        return Ok(None);
     }
+
+    let mut permissions: Option<Vec<String>> = None;
+
+    // devices left out of the owner's grants look offline, as they do in `check`
+    if let Some(direct) = UserConnection::granted_permissions(to_email, from_email, state).await?
+        && DeviceGrant::is_device_allowed(to_email, from_email, to_device, state).await?
+    {
+        permissions = Some(direct);
+    }
+
+    if let Some(team) =
+        TeamDeviceGrant::permissions_for(to_email, from_email, to_device, state).await?
+    {
+        let merged = permissions.get_or_insert_with(Vec::new);
+        for permission in team {
+            if !merged.contains(&permission) {
+                merged.push(permission);
+            }
+        }
+    }
+
+    Ok(permissions)
+}
+
+pub async fn send_error(
+    tx: &mpsc::Sender<Message>,
+    event: &str,
+    error: String,
+    message: &SocketMessage,
+) {
+    let error_response = ErrorResponse {
+        event: event.to_string(),
+        error,
+        target_email: Some(message.to_email.clone()),
+        target_device: Some(message.to_device.clone()),
+    };
+    let _ = tx
+        .send(Message::Text(
+            serde_json::to_string(&error_response)
+                .unwrap_or_default()
+                .into(),
+        ))
+        .await;
+}
+
+pub async fn send_target_not_found(message: &SocketMessage, tx: &mpsc::Sender<Message>) {
+    send_error(
+        tx,
+        "target_not_found",
+        format!(
+            "User {} with device {} is not online",
+            message.to_email, message.to_device
+        ),
+        message,
+    )
+    .await;
 }
 
 pub async fn confirm_message_delivery(
@@ -0,0 +1,227 @@
+use axum::extract::ws::Message;
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use std::ops::ControlFlow;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    db::models::{
+        guest_link::GuestLink,
+        user_connection::{PERMISSION_VIEW, is_permission},
+    },
+    routes::socket::{
+        events::{
+            forwarder::{deliver_to_peer, send_error},
+            heartbeat::handle_heartbeat,
+            relay::set_relay,
+            session::{end_session, mark_connected, open_session, signal_allowed},
+            session_pin::verify_pin,
+        },
+        redis_manager::force_disconnect,
+        types::SocketMessage,
+    },
+    utils::client_info::ClientInfo,
+};
+
+pub const GUEST_DEVICE_ID: &str = "guest";
+
+// failed redemptions allowed per ip before it has to wait for the window to pass
+const MAX_FAILED_ATTEMPTS: i64 = 10;
+// and from all ips together, against guessing spread over many addresses
+const MAX_GLOBAL_FAILED_ATTEMPTS: i64 = 500;
+const FAILED_ATTEMPTS_WINDOW: i64 = 10 * 60;
+
+// a socket opened with a guest link; it can only talk to the one shared device
+#[derive(Debug, Clone)]
+pub struct GuestSession {
+    pub link_id: Uuid,
+    pub owner_email: String,
+    pub owner_device: String,
+    pub permissions: Vec<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl GuestSession {
+    pub fn email(&self) -> String {
+        guest_email(self.link_id)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+}
+
+// the guest is disconnected when its link expires, like when the link is revoked
+pub fn disconnect_when_expired(session: &GuestSession, state: &AppState) {
+    let email = session.email();
+    let remaining = (session.expires_at - Utc::now())
+        .to_std()
+        .unwrap_or_default();
+    let state = state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(remaining).await;
+        if let Err(e) = force_disconnect(&state, &email, "*", "guest link expired").await {
+            eprintln!("Failed to disconnect guest session: {}", e);
+        }
+    });
+}
+
+// guests are addressed by their link, so owners can answer them like any other peer
+pub fn guest_email(link_id: Uuid) -> String {
+    format!("guest:{}", link_id)
+}
+
+fn failed_attempts_key(ip: &str) -> String {
+    format!("guest:failed_attempts:{}", ip)
+}
+
+const GLOBAL_FAILED_ATTEMPTS_KEY: &str = "guest:failed_attempts";
+
+pub async fn register_guest(
+    message: &SocketMessage,
+    client: &ClientInfo,
+    app_state: &AppState,
+) -> Result<GuestSession, String> {
+    // the peer address, or the client behind a trusted proxy; without one there is
+    // nothing to count attempts against
+    let Some(ip) = client.ip.clone() else {
+        return Err("Guest access is unavailable".to_string());
+    };
+    let mut redis_connection = app_state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|_| "Guest access is unavailable".to_string())?;
+    let (failed, global_failed): (Option<i64>, Option<i64>) = redis_connection
+        .mget((failed_attempts_key(&ip), GLOBAL_FAILED_ATTEMPTS_KEY))
+        .await
+        .map_err(|_| "Guest access is unavailable".to_string())?;
+    if failed.unwrap_or(0) >= MAX_FAILED_ATTEMPTS
+        || global_failed.unwrap_or(0) >= MAX_GLOBAL_FAILED_ATTEMPTS
+    {
+        return Err("Too many attempts, try again later".to_string());
+    }
+
+    let secret = message
+        .payload
+        .get("token")
+        .or_else(|| message.payload.get("code"))
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    match GuestLink::redeem(secret, app_state).await {
+        Ok(grant) => Ok(GuestSession {
+            link_id: grant.link_id,
+            owner_email: grant.owner_email,
+            owner_device: grant.device_id,
+            permissions: grant.permissions,
+            expires_at: grant.expires_at,
+        }),
+        Err(_) => {
+            let key = failed_attempts_key(&ip);
+            let _: Result<(), _> = redis::pipe()
+                .incr(&key, 1)
+                .ignore()
+                .expire(&key, FAILED_ATTEMPTS_WINDOW)
+                .ignore()
+                .incr(GLOBAL_FAILED_ATTEMPTS_KEY, 1)
+                .ignore()
+                .expire(GLOBAL_FAILED_ATTEMPTS_KEY, FAILED_ATTEMPTS_WINDOW)
+                .ignore()
+                .query_async(&mut *redis_connection)
+                .await;
+            Err("Invalid, used or expired guest link".to_string())
+        }
+    }
+}
+
+// everything a guest sends goes through here instead of the regular event handling
+pub async fn handle_guest_message(
+    mut message: SocketMessage,
+    session: &GuestSession,
+    state: AppState,
+    tx: &mpsc::Sender<Message>,
+    client: &ClientInfo,
+) -> ControlFlow<(), ()> {
+    // nothing goes through once the link expired, even before the disconnect lands
+    if session.is_expired() {
+        send_error(tx, "error", "The guest link expired".to_string(), &message).await;
+        return ControlFlow::Break(());
+    }
+    match message.event.as_str() {
+        "ping" => {
+            handle_heartbeat(message, state, tx).await;
+        }
+        "disconnect" => return ControlFlow::Break(()),
+        "connect" | "session_end" | "session_pin" | "relay_open" | "relay_close" => {
+            message.from_email = session.email();
+            message.from_device = GUEST_DEVICE_ID.to_string();
+            match message.event.as_str() {
+                "connect" => mark_connected(&message, &state, tx).await,
+                "session_pin" => verify_pin(&message, client, &state, tx).await,
+                "relay_open" | "relay_close" => set_relay(&message, &state, tx).await,
+                _ => end_session(message, &state, tx).await,
+            }
+        }
+        "try_connect" | "sdp_offer" | "sdp_answer" | "ice_candidate" => {
+            if message.to_email != session.owner_email || message.to_device != session.owner_device
+            {
+                send_error(
+                    tx,
+                    "error",
+                    "Guest sessions can only reach the shared device".to_string(),
+                    &message,
+                )
+                .await;
+                return ControlFlow::Continue(());
+            }
+            if message.event == "try_connect" {
+                let session_type = message
+                    .payload
+                    .get("session_type")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or(PERMISSION_VIEW);
+                if !is_permission(session_type)
+                    || !session.permissions.iter().any(|p| p == session_type)
+                {
+                    send_error(
+                        tx,
+                        "permission_denied",
+                        format!("This guest link does not allow {} access", session_type),
+                        &message,
+                    )
+                    .await;
+                    return ControlFlow::Continue(());
+                }
+            }
+            // the guest can't pose as anyone else
+            message.from_email = session.email();
+            message.from_device = GUEST_DEVICE_ID.to_string();
+            let allowed = if message.event == "try_connect" {
+                let session_type = message
+                    .payload
+                    .get("session_type")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or(PERMISSION_VIEW)
+                    .to_string();
+                open_session(&mut message, &session_type, &state, tx).await
+            } else {
+                signal_allowed(&message, &state, tx).await
+            };
+            if allowed {
+                deliver_to_peer(message, state, tx).await;
+            }
+        }
+        _ => {
+            let error_response = serde_json::json!({
+                "event": "error",
+                "error": format!("{} is not available in guest sessions", message.event)
+            });
+            let _ = tx
+                .send(Message::Text(error_response.to_string().into()))
+                .await;
+        }
+    }
+    ControlFlow::Continue(())
+}
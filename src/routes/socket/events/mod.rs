@@ -2,5 +2,11 @@ pub mod check;
 pub mod connect;
 pub mod disconnect;
 pub mod forwarder;
+pub mod guest;
 pub mod heartbeat;
+pub mod offline_queue;
+pub mod pairing;
 pub mod register;
+pub mod relay;
+pub mod session;
+pub mod session_pin;
@@ -0,0 +1,195 @@
+use axum::extract::ws::Message;
+use redis::AsyncCommands;
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::{
+    app_state::AppState,
+    routes::socket::{
+        events::forwarder::{send_error, send_target_not_found},
+        redis_manager::RedisManagerError,
+        types::SocketMessage,
+    },
+};
+
+// a device that went away less than the window ago gets its signaling messages kept
+// until it registers again, e.g. while it reconnects after a network change
+const PUSH_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return 0
+end
+if redis.call('LLEN', KEYS[2]) >= tonumber(ARGV[2]) then
+    return -1
+end
+redis.call('RPUSH', KEYS[2], ARGV[1])
+redis.call('EXPIRE', KEYS[2], ARGV[3])
+return 1
+"#;
+
+enum QueueOutcome {
+    Queued,
+    // never seen or not within the window
+    NotRecent,
+    Full,
+}
+
+fn last_seen_key(email: &str, device_id: &str) -> String {
+    format!("socket:last_seen:{}:{}", email, device_id)
+}
+
+fn queue_key(email: &str, device_id: &str) -> String {
+    format!("socket:queue:{}:{}", email, device_id)
+}
+
+// 0 turns queueing off
+pub fn queue_window_secs() -> u64 {
+    std::env::var("SIGNALING_QUEUE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30)
+}
+
+fn queue_max() -> usize {
+    std::env::var("SIGNALING_QUEUE_MAX")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(100)
+}
+
+// starts the window in which messages for the device are kept
+pub async fn mark_last_seen(
+    app_state: &AppState,
+    email: &str,
+    device_id: &str,
+) -> Result<(), RedisManagerError> {
+    let window = queue_window_secs();
+    if window == 0 {
+        return Ok(());
+    }
+    let mut conn = app_state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+    let _: () = conn
+        .set_ex(last_seen_key(email, device_id), 1, window)
+        .await?;
+    Ok(())
+}
+
+async fn queue_message(
+    app_state: &AppState,
+    message: &SocketMessage,
+) -> Result<QueueOutcome, RedisManagerError> {
+    let window = queue_window_secs();
+    if window == 0 {
+        return Ok(QueueOutcome::NotRecent);
+    }
+    let mut conn = app_state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+    let json = serde_json::to_string(message)
+        .map_err(|e| RedisManagerError::SerializationError(e.to_string()))?;
+    let pushed: i64 = redis::Script::new(PUSH_SCRIPT)
+        .key(last_seen_key(&message.to_email, &message.to_device))
+        .key(queue_key(&message.to_email, &message.to_device))
+        .arg(json)
+        .arg(queue_max())
+        .arg(window)
+        .invoke_async(&mut *conn)
+        .await?;
+    Ok(match pushed {
+        1 => QueueOutcome::Queued,
+        -1 => QueueOutcome::Full,
+        _ => QueueOutcome::NotRecent,
+    })
+}
+
+// everything kept for the device, oldest first; the queue is gone afterwards
+async fn take_queued(
+    app_state: &AppState,
+    email: &str,
+    device_id: &str,
+) -> Result<Vec<SocketMessage>, RedisManagerError> {
+    let mut conn = app_state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+    let key = queue_key(email, device_id);
+    let (queued,): (Vec<String>,) = redis::pipe()
+        .atomic()
+        .lrange(&key, 0, -1)
+        .del(&key)
+        .ignore()
+        .del(last_seen_key(email, device_id))
+        .ignore()
+        .query_async(&mut *conn)
+        .await?;
+    Ok(queued
+        .iter()
+        .filter_map(|json| serde_json::from_str(json).ok())
+        .collect())
+}
+
+// sent to a device right after it registered again
+pub async fn deliver_queued(
+    app_state: &AppState,
+    email: &str,
+    device_id: &str,
+    tx: &mpsc::Sender<Message>,
+) {
+    let queued = match take_queued(app_state, email, device_id).await {
+        Ok(queued) => queued,
+        Err(e) => {
+            eprintln!("Failed to read queued messages: {}", e);
+            return;
+        }
+    };
+    for message in queued {
+        let msg_text = serde_json::to_string(&message).unwrap_or_default();
+        if tx.send(Message::Text(msg_text.into())).await.is_err() {
+            return;
+        }
+    }
+}
+
+// the sender's answer when the target was offline; the message waits when it can
+pub async fn queue_or_not_found(
+    message: &SocketMessage,
+    app_state: &AppState,
+    tx: &mpsc::Sender<Message>,
+) {
+    match queue_message(app_state, message).await {
+        Ok(QueueOutcome::Queued) => {
+            let response = json!({
+                "event": "queued",
+                "message_event": message.event,
+                "session_id": message.session_id,
+                "target_email": message.to_email,
+                "target_device": message.to_device,
+                "expires_in": queue_window_secs(),
+            });
+            let _ = tx.send(Message::Text(response.to_string().into())).await;
+        }
+        Ok(QueueOutcome::Full) => {
+            send_error(
+                tx,
+                "queue_full",
+                format!(
+                    "Too many messages are waiting for device {} of {}",
+                    message.to_device, message.to_email
+                ),
+                message,
+            )
+            .await;
+        }
+        Ok(QueueOutcome::NotRecent) => send_target_not_found(message, tx).await,
+        Err(e) => {
+            eprintln!("Failed to queue message: {}", e);
+            send_target_not_found(message, tx).await;
+        }
+    }
+}
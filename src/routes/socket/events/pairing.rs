@@ -0,0 +1,231 @@
+use axum::extract::ws::Message;
+use serde_json::json;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    db::models::{
+        audit_event::{
+            AuditEntry, PAIRING_CODE_ISSUE, PAIRING_CODE_REDEEM, PAIRING_CODE_REDEEM_FAILED,
+        },
+        pairing_code::{IssuedPairingCode, PairingCode, PairingError, PairingOutcome},
+        user::User,
+        user_connection::{MODE_SHARE, PERMISSION_VIEW, is_mode, is_permission},
+    },
+    routes::socket::{redis_manager::notify_user, types::SocketMessage},
+    utils::client_info::ClientInfo,
+};
+
+// sent to the code owner's devices once someone entered the code
+pub const PAIRED_EVENT: &str = "paired";
+
+// what a device asks for with its code; by default the other user may only view, and only
+// once the owner confirmed
+pub struct PairingRequest {
+    pub device_id: String,
+    pub mode: Option<String>,
+    pub permissions: Option<Vec<String>>,
+    pub require_confirmation: bool,
+}
+
+impl PairingRequest {
+    fn from_payload(device_id: String, payload: &serde_json::Value) -> Self {
+        PairingRequest {
+            device_id,
+            mode: payload
+                .get("mode")
+                .and_then(|v| v.as_str())
+                .map(str::to_string),
+            permissions: payload
+                .get("permissions")
+                .and_then(|v| serde_json::from_value(v.clone()).ok()),
+            require_confirmation: payload
+                .get("require_confirmation")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(true),
+        }
+    }
+}
+
+pub async fn issue_pairing_code(
+    owner_id: Uuid,
+    owner_email: &str,
+    request: PairingRequest,
+    client: &ClientInfo,
+    app_state: &AppState,
+) -> Result<IssuedPairingCode, String> {
+    let mode = request.mode.unwrap_or_else(|| MODE_SHARE.to_string());
+    if !is_mode(&mode) {
+        return Err(format!("Unknown mode: {}", mode));
+    }
+    let permissions = request
+        .permissions
+        .unwrap_or_else(|| vec![PERMISSION_VIEW.to_string()]);
+    if permissions.is_empty() || !permissions.iter().all(|p| is_permission(p)) {
+        return Err("Invalid permissions".to_string());
+    }
+
+    let pairing = PairingCode {
+        owner_id,
+        owner_email: owner_email.to_string(),
+        device_id: request.device_id.clone(),
+        mode: mode.clone(),
+        permissions: permissions.clone(),
+        require_confirmation: request.require_confirmation,
+    };
+    let issued = pairing.issue(app_state).await.map_err(|e| {
+        println!("{e}");
+        "Could not create a pairing code".to_string()
+    })?;
+    AuditEntry::new(PAIRING_CODE_ISSUE)
+        .actor(owner_id)
+        .target(request.device_id)
+        .client(client)
+        .details(json!({
+            "mode": mode,
+            "permissions": permissions,
+            "require_confirmation": request.require_confirmation,
+        }))
+        .record(app_state);
+    Ok(issued)
+}
+
+// redeems the code and lets the owner's devices know who paired. The entering user
+// grants the owner nothing on its own devices unless it lists `reverse_permissions`
+pub async fn pair_with_code(
+    code: &str,
+    user_id: Uuid,
+    user_email: &str,
+    reverse_permissions: Option<Vec<String>>,
+    client: &ClientInfo,
+    app_state: &AppState,
+) -> Result<(PairingCode, PairingOutcome), PairingError> {
+    let reverse_permissions = reverse_permissions.unwrap_or_default();
+    if !reverse_permissions.iter().all(|p| is_permission(p)) {
+        return Err(PairingError::InvalidPermissions);
+    }
+    match PairingCode::redeem(code, user_id, &reverse_permissions, app_state).await {
+        Ok((pairing, outcome)) => {
+            let status = match outcome {
+                PairingOutcome::Connected => "connected",
+                PairingOutcome::Pending => "pending",
+            };
+            AuditEntry::new(PAIRING_CODE_REDEEM)
+                .actor(user_id)
+                .target(pairing.owner_email.clone())
+                .client(client)
+                .details(json!({
+                    "device_id": pairing.device_id,
+                    "status": status,
+                    "reverse_permissions": reverse_permissions,
+                }))
+                .record(app_state);
+            let _ = notify_user(
+                app_state,
+                &pairing.owner_email,
+                PAIRED_EVENT,
+                json!({
+                    "email": user_email,
+                    "device_id": pairing.device_id,
+                    "status": status,
+                }),
+            )
+            .await;
+            Ok((pairing, outcome))
+        }
+        Err(e) => {
+            if !matches!(e, PairingError::Database(_)) {
+                AuditEntry::new(PAIRING_CODE_REDEEM_FAILED)
+                    .actor(user_id)
+                    .client(client)
+                    .details(json!({"error": format!("{:?}", e)}))
+                    .record(app_state);
+            }
+            Err(e)
+        }
+    }
+}
+
+pub fn pairing_error_message(e: &PairingError) -> &'static str {
+    match e {
+        PairingError::InvalidCode => "Invalid or expired pairing code",
+        PairingError::TooManyAttempts => "Too many attempts, try again later",
+        PairingError::AlreadyConnected => "Already connected to this user",
+        PairingError::InvalidPermissions => "Invalid permissions",
+        PairingError::Database(_) => "Pairing is unavailable",
+    }
+}
+
+// "pairing_code": the registered device asks for a code to show on screen
+pub async fn handle_pairing_code(
+    message: SocketMessage,
+    user_email: &str,
+    device_id: &str,
+    client: &ClientInfo,
+    app_state: &AppState,
+    tx: &mpsc::Sender<Message>,
+) {
+    let request = PairingRequest::from_payload(device_id.to_string(), &message.payload);
+    let result = match User::get_user_id(user_email.to_string(), app_state.clone()).await {
+        Ok(owner_id) => issue_pairing_code(owner_id, user_email, request, client, app_state).await,
+        Err(_) => Err("Unknown user".to_string()),
+    };
+    let response = match result {
+        Ok(issued) => json!({
+            "event": "pairing_code",
+            "status": "ok",
+            "code": issued.code,
+            "expires_in": issued.expires_in,
+        }),
+        Err(e) => json!({"event": "pairing_code", "status": "error", "error": e}),
+    };
+    let _ = tx.send(Message::Text(response.to_string().into())).await;
+}
+
+// "pair": the registered user enters a code shown on another device
+pub async fn handle_pair(
+    message: SocketMessage,
+    user_email: &str,
+    client: &ClientInfo,
+    app_state: &AppState,
+    tx: &mpsc::Sender<Message>,
+) {
+    let code = message
+        .payload
+        .get("code")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    let reverse_permissions = message
+        .payload
+        .get("reverse_permissions")
+        .and_then(|v| serde_json::from_value(v.clone()).ok());
+    let result = match User::get_user_id(user_email.to_string(), app_state.clone()).await {
+        Ok(user_id) => {
+            pair_with_code(
+                code,
+                user_id,
+                user_email,
+                reverse_permissions,
+                client,
+                app_state,
+            )
+            .await
+        }
+        Err(e) => Err(PairingError::Database(e)),
+    };
+    let response = match result {
+        Ok((pairing, outcome)) => json!({
+            "event": "pair",
+            "status": if outcome == PairingOutcome::Connected { "connected" } else { "pending" },
+            "email": pairing.owner_email,
+            "device_id": pairing.device_id,
+        }),
+        Err(e) => json!({
+            "event": "pair",
+            "status": "error",
+            "error": pairing_error_message(&e),
+        }),
+    };
+    let _ = tx.send(Message::Text(response.to_string().into())).await;
+}
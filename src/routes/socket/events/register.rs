@@ -3,7 +3,7 @@ use uuid::Uuid;
 
 use crate::{
     app_state::AppState,
-    db::models::{login_token::LoginToken, user::User},
+    db::models::{login_token::LoginToken, user::User, user_connection::UserConnection},
     routes::socket::{
         redis_manager::{broadcast_user_joined, store_device_presence},
         types::{DeviceInfo, SocketMessage},
@@ -27,41 +27,49 @@ pub async fn register_user(
         Err(_) => return Err("Invalid or expired token".to_string()),
     };
 
+    let Ok(user_id) = Uuid::parse_str(&user_id) else {
+        return Err("Invalid or expired token".to_string());
+    };
+    let account = match User::get_account(user_id, app_state.clone()).await {
+        Ok(account) => account,
+        Err(_) => return Err("Invalid or expired token".to_string()),
+    };
+
     // Verify the email matches the token
-    if User::get_user_email(user_id, app_state.clone())
-        .await
-        .unwrap()
-        != message.from_email
-    {
+    if account.email != message.from_email {
         return Err("Email does not match token".to_string());
     }
 
+    if account.is_suspended() {
+        return Err("Account suspended".to_string());
+    }
+
     // Check if this device is already registered (same device reconnecting)
     // Clean up old connection if exists
     {
         let email_device_map = app_state.email_device_to_socket.read().await;
-        if let Some(device_map) = email_device_map.get(&message.from_email) {
-            if let Some(old_socket_id) = device_map.get(&message.from_device) {
-                // Device already exists, clean up old socket
-                let old_socket_id = old_socket_id.clone();
-                drop(email_device_map);
+        if let Some(device_map) = email_device_map.get(&message.from_email)
+            && let Some(old_socket_id) = device_map.get(&message.from_device)
+        {
+            // Device already exists, clean up old socket
+            let old_socket_id = old_socket_id.clone();
+            drop(email_device_map);
 
-                // Remove old socket connection
-                app_state
-                    .socket_id_to_connection
-                    .write()
-                    .await
-                    .remove(&old_socket_id);
+            // Remove old socket connection
+            app_state
+                .socket_id_to_connection
+                .write()
+                .await
+                .remove(&old_socket_id);
 
-                // Remove from old socket_connections map
-                let old_key = format!("{}{}", message.from_email, message.from_device);
-                app_state.socket_connections.write().await.remove(&old_key);
+            // Remove from old socket_connections map
+            let old_key = format!("{}{}", message.from_email, message.from_device);
+            app_state.socket_connections.write().await.remove(&old_key);
 
-                println!(
-                    "Cleaned up old socket {} for device {}",
-                    old_socket_id, message.from_device
-                );
-            }
+            println!(
+                "Cleaned up old socket {} for device {}",
+                old_socket_id, message.from_device
+            );
         }
     }
 
@@ -82,6 +90,7 @@ pub async fn register_user(
         device_name,
         device_type,
         device_id,
+        nickname: None,
     };
 
     // Store in Redis for cross-pod visibility
@@ -97,8 +106,20 @@ pub async fn register_user(
         // Continue in local-only mode
     } else {
         // Broadcast to other pods that user joined
-        if let Err(e) =
-            broadcast_user_joined(&app_state, &message.from_email, &message.from_device).await
+        let watchers = UserConnection::presence_watchers(
+            &message.from_email,
+            &message.from_device,
+            &app_state,
+        )
+        .await
+        .unwrap_or_default();
+        if let Err(e) = broadcast_user_joined(
+            &app_state,
+            &message.from_email,
+            &message.from_device,
+            &watchers,
+        )
+        .await
         {
             eprintln!("Failed to broadcast user joined: {}", e);
         }
@@ -0,0 +1,316 @@
+use axum::extract::ws::Message;
+use redis::AsyncCommands;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, mpsc};
+
+use crate::{
+    app_state::AppState,
+    routes::socket::{
+        backpressure::{Delivery, deliver},
+        events::{
+            forwarder::send_error,
+            session::{
+                SIGNALING_STATES, SignalingSession, load_session, session_key, session_not_found,
+            },
+        },
+        redis_manager::{device_pod, local_socket, notify_device, publish_relay_frame},
+        types::SocketMessage,
+    },
+};
+
+// binary websocket frames for the relay: the tag byte, the 36 character session id,
+// then the data; anything else binary is still read as a JSON message
+pub const RELAY_FRAME_TAG: u8 = 0x01;
+const SESSION_ID_LENGTH: usize = 36;
+const MAX_RELAY_FRAME: usize = 64 * 1024;
+
+// counters go to the session and the session is looked at again this often
+const RELAY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+pub const RELAY_OPEN_EVENT: &str = "relay_open";
+
+// what a socket knows about the relays it sends on, by session id
+pub type RelayStreams = Arc<Mutex<HashMap<String, RelayStream>>>;
+
+pub struct RelayStream {
+    peer_email: String,
+    peer_device: String,
+    tokens: f64,
+    refilled_at: Instant,
+    checked_at: Instant,
+    unflushed_bytes: i64,
+    unflushed_dropped: i64,
+}
+
+// per session and direction, 0 is unlimited
+fn bandwidth_bytes_per_sec() -> f64 {
+    let kbps: f64 = std::env::var("WS_RELAY_BANDWIDTH_KBPS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2_000.0);
+    kbps * 1000.0 / 8.0
+}
+
+impl RelayStream {
+    fn new(peer_email: String, peer_device: String) -> Self {
+        let now = Instant::now();
+        RelayStream {
+            peer_email,
+            peer_device,
+            tokens: bandwidth_bytes_per_sec(),
+            refilled_at: now,
+            checked_at: now,
+            unflushed_bytes: 0,
+            unflushed_dropped: 0,
+        }
+    }
+
+    // one second worth of burst, frames over the rate are dropped
+    fn take(&mut self, bytes: usize) -> bool {
+        let rate = bandwidth_bytes_per_sec();
+        if rate == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled_at = now;
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+}
+
+pub fn is_relay_frame(data: &[u8]) -> bool {
+    data.first() == Some(&RELAY_FRAME_TAG)
+}
+
+// the device may relay on the session: it is one of its two devices, the session is
+// accepted, its PIN (if any) was given and someone opened the relay
+fn relay_peer(
+    session: &SignalingSession,
+    email: &str,
+    device: &str,
+) -> Result<(String, String), &'static str> {
+    let peer = session.peer_of(email, device).ok_or("session_not_found")?;
+    if !SIGNALING_STATES.contains(&session.status.as_str()) {
+        return Err("session_not_accepted");
+    }
+    if session.pin_required && !session.pin_verified {
+        return Err("pin_required");
+    }
+    if !session.relay {
+        return Err("relay_not_open");
+    }
+    Ok(peer)
+}
+
+async fn flush_counters(session_id: &str, stream: &mut RelayStream, state: &AppState) {
+    if stream.unflushed_bytes == 0 && stream.unflushed_dropped == 0 {
+        return;
+    }
+    let Ok(mut conn) = state.redis_pool.get().await else {
+        return;
+    };
+    let key = session_key(session_id);
+    let _: Result<i64, _> = conn
+        .hincr(&key, "relay_bytes", stream.unflushed_bytes)
+        .await;
+    let _: Result<i64, _> = conn
+        .hincr(&key, "relay_dropped", stream.unflushed_dropped)
+        .await;
+    stream.unflushed_bytes = 0;
+    stream.unflushed_dropped = 0;
+}
+
+async fn relay_error(tx: &mpsc::Sender<Message>, code: &str, session_id: &str) {
+    let response = json!({"event": code, "session_id": session_id});
+    let _ = tx.send(Message::Text(response.to_string().into())).await;
+}
+
+// a binary relay frame from a registered socket; it goes to the other device of the
+// session as is, on this pod or through redis
+pub async fn relay_frame(
+    frame: &[u8],
+    email: &str,
+    device: &str,
+    state: &AppState,
+    tx: &mpsc::Sender<Message>,
+    streams: &RelayStreams,
+) {
+    let session_id = match frame.get(1..1 + SESSION_ID_LENGTH) {
+        Some(id) => String::from_utf8_lossy(id).to_string(),
+        None => return,
+    };
+    if frame.len() > MAX_RELAY_FRAME {
+        relay_error(tx, "relay_frame_too_large", &session_id).await;
+        return;
+    }
+
+    let mut streams = streams.lock().await;
+    let recheck = streams
+        .get(&session_id)
+        .is_none_or(|stream| stream.checked_at.elapsed() >= RELAY_CHECK_INTERVAL);
+    if recheck {
+        let checked = match SignalingSession::get(&session_id, state).await {
+            Ok(Some(session)) => relay_peer(&session, email, device),
+            Ok(None) => Err("session_not_found"),
+            Err(_) => Err("relay_unavailable"),
+        };
+        match (checked, streams.get_mut(&session_id)) {
+            (Ok(_), Some(stream)) => {
+                stream.checked_at = Instant::now();
+                flush_counters(&session_id, stream, state).await;
+            }
+            (Ok((peer_email, peer_device)), None) => {
+                streams.insert(
+                    session_id.clone(),
+                    RelayStream::new(peer_email, peer_device),
+                );
+            }
+            (Err(code), _) => {
+                if let Some(mut stream) = streams.remove(&session_id) {
+                    flush_counters(&session_id, &mut stream, state).await;
+                }
+                relay_error(tx, code, &session_id).await;
+                return;
+            }
+        }
+    }
+    let Some(stream) = streams.get_mut(&session_id) else {
+        return;
+    };
+
+    if !stream.take(frame.len()) {
+        stream.unflushed_dropped += 1;
+        return;
+    }
+    // bytes count once the peer's socket or pod took the frame
+    let delivered = match local_socket(state, &stream.peer_email, &stream.peer_device).await {
+        Some(peer) => matches!(
+            deliver(&peer, Message::Binary(frame.to_vec().into()), true),
+            Delivery::Sent
+        ),
+        None => match device_pod(state, &stream.peer_email, &stream.peer_device).await {
+            Ok(Some(pod_id)) => {
+                match publish_relay_frame(
+                    state,
+                    &pod_id,
+                    &stream.peer_email,
+                    &stream.peer_device,
+                    frame,
+                )
+                .await
+                {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("Failed to publish relay frame: {}", e);
+                        false
+                    }
+                }
+            }
+            // the peer is offline
+            Ok(None) => false,
+            Err(e) => {
+                eprintln!("Failed to look up relay peer: {}", e);
+                false
+            }
+        },
+    };
+    if delivered {
+        stream.unflushed_bytes += frame.len() as i64;
+    } else {
+        stream.unflushed_dropped += 1;
+    }
+}
+
+// the socket went away, whatever it counted still belongs to the sessions
+pub async fn flush_relay_streams(streams: &RelayStreams, state: &AppState) {
+    let mut streams = streams.lock().await;
+    for (session_id, stream) in streams.iter_mut() {
+        flush_counters(session_id, stream, state).await;
+    }
+    streams.clear();
+}
+
+// `relay_open` / `relay_close` from either device of an accepted session
+pub async fn set_relay(message: &SocketMessage, state: &AppState, tx: &mpsc::Sender<Message>) {
+    let Some(session) = load_session(message, state, tx).await else {
+        return;
+    };
+    let open = message.event == RELAY_OPEN_EVENT;
+    let peer = if open {
+        // the flag itself is what is being set, so don't require it yet
+        let mut opened = session.clone();
+        opened.relay = true;
+        relay_peer(&opened, &message.from_email, &message.from_device)
+    } else {
+        session
+            .peer_of(&message.from_email, &message.from_device)
+            .ok_or("session_not_found")
+    };
+    let (peer_email, peer_device) = match peer {
+        Ok(peer) => peer,
+        Err("session_not_found") => {
+            session_not_found(&session.id, message, tx).await;
+            return;
+        }
+        Err(code) => {
+            send_error(
+                tx,
+                code,
+                format!("Session {} can't relay yet", session.id),
+                message,
+            )
+            .await;
+            return;
+        }
+    };
+
+    let stored: Result<(), redis::RedisError> = match state.redis_pool.get().await {
+        Ok(mut conn) => {
+            conn.hset(
+                session_key(&session.id),
+                "relay",
+                if open { "1" } else { "0" },
+            )
+            .await
+        }
+        Err(e) => Err(redis::RedisError::from(std::io::Error::other(
+            e.to_string(),
+        ))),
+    };
+    if let Err(e) = stored {
+        eprintln!("Failed to update relay: {}", e);
+        send_error(
+            tx,
+            "error",
+            "Could not update the relay".to_string(),
+            message,
+        )
+        .await;
+        return;
+    }
+
+    let response = json!({"event": message.event, "status": "ok", "session_id": session.id});
+    let _ = tx.send(Message::Text(response.to_string().into())).await;
+    let _ = notify_device(
+        state,
+        &peer_email,
+        &peer_device,
+        &message.event,
+        json!({
+            "session_id": session.id,
+            "email": message.from_email,
+            "device_id": message.from_device,
+        }),
+    )
+    .await;
+}
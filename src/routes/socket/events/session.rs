@@ -0,0 +1,769 @@
+use axum::extract::ws::Message;
+use redis::AsyncCommands;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    routes::socket::{
+        events::forwarder::{deliver_to_peer, reachable_permissions, send_error},
+        events::session_pin::{is_locked_out, pin_fields},
+        redis_manager::{RedisManagerError, notify_device},
+        types::SocketMessage,
+    },
+};
+
+// requested -> accepted -> negotiating -> connected, and ended from any of them
+pub const SESSION_REQUESTED: &str = "requested";
+pub const SESSION_ACCEPTED: &str = "accepted";
+pub const SESSION_NEGOTIATING: &str = "negotiating";
+pub const SESSION_CONNECTED: &str = "connected";
+pub const SESSION_ENDED: &str = "ended";
+
+pub const OPEN_STATES: [&str; 4] = [
+    SESSION_REQUESTED,
+    SESSION_ACCEPTED,
+    SESSION_NEGOTIATING,
+    SESSION_CONNECTED,
+];
+// states in which SDP and ICE may flow
+pub const SIGNALING_STATES: [&str; 3] = [SESSION_ACCEPTED, SESSION_NEGOTIATING, SESSION_CONNECTED];
+
+// why a session ended
+pub const END_REJECTED: &str = "rejected";
+pub const END_TIMEOUT: &str = "timeout";
+pub const END_HANGUP: &str = "hangup";
+// the requester ran out of PIN attempts
+pub const END_PIN_LOCKED: &str = "pin_locked";
+// the requester lost the access the session was started with
+pub const END_REVOKED: &str = "revoked";
+// one of the devices left and did not come back within the queue window
+pub const END_DISCONNECTED: &str = "disconnected";
+
+// sent to both sides when the target never answered a try_connect
+pub const SESSION_TIMEOUT_EVENT: &str = "session_timeout";
+
+// how long a session that got past the request keeps forwarding SDP and ICE
+const OPEN_SESSION_TTL: i64 = 24 * 60 * 60;
+// ended sessions linger briefly so late messages get a clear error
+const ENDED_SESSION_TTL: i64 = 60;
+
+// moves the status only when it is one of the allowed ones, and stamps the time
+// KEYS[1] session; ARGV: new status, timestamp field, now, ttl, allowed statuses...
+// ARGV: status, its timestamp field and value, ttl, the number of allowed states, the
+// allowed states, then field/value pairs written along with the move
+const TRANSITION_SCRIPT: &str = r#"
+local status = redis.call('HGET', KEYS[1], 'status')
+local allowed = tonumber(ARGV[5])
+for i = 6, 5 + allowed do
+    if status == ARGV[i] then
+        redis.call('HSET', KEYS[1], 'status', ARGV[1], ARGV[2], ARGV[3])
+        for j = 6 + allowed, #ARGV, 2 do
+            redis.call('HSET', KEYS[1], ARGV[j], ARGV[j + 1])
+        end
+        redis.call('EXPIRE', KEYS[1], ARGV[4])
+        return 1
+    end
+end
+return 0
+"#;
+
+// a call between two devices, kept in Redis so every pod sees the same state
+#[derive(Debug, Clone, Serialize)]
+pub struct SignalingSession {
+    pub id: String,
+    pub from_email: String,
+    pub from_device: String,
+    pub to_email: String,
+    pub to_device: String,
+    pub session_type: String,
+    pub status: String,
+    pub end_reason: Option<String>,
+    pub ended_by: Option<String>,
+    // unix millis
+    pub requested_at: Option<i64>,
+    pub accepted_at: Option<i64>,
+    pub negotiating_at: Option<i64>,
+    pub connected_at: Option<i64>,
+    pub ended_at: Option<i64>,
+    // the target asked for a PIN or has a device password, see session_pin
+    pub pin_required: bool,
+    pub pin_verified: bool,
+    // a websocket relay was opened for the session, see relay; bytes it forwarded and
+    // frames it dropped over the bandwidth cap
+    pub relay: bool,
+    pub relay_bytes: i64,
+    pub relay_dropped: i64,
+}
+
+pub fn session_key(id: &str) -> String {
+    format!("signaling:session:{}", id)
+}
+
+// ids of the sessions a user takes part in, on either side; stale ids are dropped on read
+fn user_sessions_key(email: &str) -> String {
+    format!("signaling:sessions:{}", email)
+}
+
+fn consent_timeout() -> Duration {
+    let seconds = std::env::var("SESSION_CONSENT_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    Duration::from_secs(seconds)
+}
+
+impl SignalingSession {
+    async fn create(
+        message: &SocketMessage,
+        session_type: &str,
+        app_state: &AppState,
+    ) -> Result<Self, RedisManagerError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let session = SignalingSession {
+            id: Uuid::new_v4().to_string(),
+            from_email: message.from_email.clone(),
+            from_device: message.from_device.clone(),
+            to_email: message.to_email.clone(),
+            to_device: message.to_device.clone(),
+            session_type: session_type.to_string(),
+            status: SESSION_REQUESTED.to_string(),
+            end_reason: None,
+            ended_by: None,
+            requested_at: Some(now),
+            accepted_at: None,
+            negotiating_at: None,
+            connected_at: None,
+            ended_at: None,
+            pin_required: false,
+            pin_verified: false,
+            relay: false,
+            relay_bytes: 0,
+            relay_dropped: 0,
+        };
+        let mut conn = app_state
+            .redis_pool
+            .get()
+            .await
+            .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+        let key = session_key(&session.id);
+        let requested_at = now.to_string();
+        let _: () = conn
+            .hset_multiple(
+                &key,
+                &[
+                    ("from_email", session.from_email.as_str()),
+                    ("from_device", session.from_device.as_str()),
+                    ("to_email", session.to_email.as_str()),
+                    ("to_device", session.to_device.as_str()),
+                    ("session_type", session.session_type.as_str()),
+                    ("status", session.status.as_str()),
+                    ("requested_at", requested_at.as_str()),
+                ],
+            )
+            .await?;
+        // outlives the consent timeout so a late answer still finds the session
+        let _: () = conn
+            .expire(&key, consent_timeout().as_secs() as i64 + 60)
+            .await?;
+        for email in [&session.from_email, &session.to_email] {
+            let _: () = redis::pipe()
+                .sadd(user_sessions_key(email), &session.id)
+                .ignore()
+                // the sessions in it restart their ttl on every transition
+                .expire(user_sessions_key(email), 2 * OPEN_SESSION_TTL)
+                .ignore()
+                .query_async(&mut *conn)
+                .await?;
+        }
+        Ok(session)
+    }
+
+    pub async fn get(id: &str, app_state: &AppState) -> Result<Option<Self>, RedisManagerError> {
+        let mut conn = app_state
+            .redis_pool
+            .get()
+            .await
+            .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+        let mut fields: HashMap<String, String> = conn.hgetall(session_key(id)).await?;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        let mut take = |name: &str| fields.remove(name);
+        let number = |value: Option<String>| value.and_then(|v| v.parse().ok());
+        Ok(Some(SignalingSession {
+            id: id.to_string(),
+            from_email: take("from_email").unwrap_or_default(),
+            from_device: take("from_device").unwrap_or_default(),
+            to_email: take("to_email").unwrap_or_default(),
+            to_device: take("to_device").unwrap_or_default(),
+            session_type: take("session_type").unwrap_or_default(),
+            status: take("status").unwrap_or_default(),
+            end_reason: take("end_reason"),
+            ended_by: take("ended_by"),
+            requested_at: number(take("requested_at")),
+            accepted_at: number(take("accepted_at")),
+            negotiating_at: number(take("negotiating_at")),
+            connected_at: number(take("connected_at")),
+            ended_at: number(take("ended_at")),
+            pin_required: take("pin_required").is_some_and(|v| v == "1"),
+            pin_verified: take("pin_verified").is_some_and(|v| v == "1"),
+            relay: take("relay").is_some_and(|v| v == "1"),
+            relay_bytes: number(take("relay_bytes")).unwrap_or(0),
+            relay_dropped: number(take("relay_dropped")).unwrap_or(0),
+        }))
+    }
+
+    async fn transition(
+        id: &str,
+        allowed: &[&str],
+        to: &str,
+        app_state: &AppState,
+    ) -> Result<bool, RedisManagerError> {
+        SignalingSession::transition_with(id, allowed, to, &[], app_state).await
+    }
+
+    // moves the session and writes the fields only if it was in one of the allowed states
+    async fn transition_with(
+        id: &str,
+        allowed: &[&str],
+        to: &str,
+        fields: &[(&str, String)],
+        app_state: &AppState,
+    ) -> Result<bool, RedisManagerError> {
+        let mut conn = app_state
+            .redis_pool
+            .get()
+            .await
+            .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+        let ttl = if to == SESSION_ENDED {
+            ENDED_SESSION_TTL
+        } else {
+            OPEN_SESSION_TTL
+        };
+        let script = redis::Script::new(TRANSITION_SCRIPT);
+        let mut invocation = script.key(session_key(id));
+        invocation
+            .arg(to)
+            .arg(format!("{}_at", to))
+            .arg(chrono::Utc::now().timestamp_millis())
+            .arg(ttl)
+            .arg(allowed.len());
+        for status in allowed {
+            invocation.arg(*status);
+        }
+        for (field, value) in fields {
+            invocation.arg(*field).arg(value);
+        }
+        let moved: i64 = invocation.invoke_async(&mut *conn).await?;
+        Ok(moved == 1)
+    }
+
+    // ends the session if it is in one of the allowed states
+    pub async fn end(
+        id: &str,
+        allowed: &[&str],
+        reason: &str,
+        ended_by: &str,
+        app_state: &AppState,
+    ) -> Result<bool, RedisManagerError> {
+        if !SignalingSession::transition(id, allowed, SESSION_ENDED, app_state).await? {
+            return Ok(false);
+        }
+        let mut conn = app_state
+            .redis_pool
+            .get()
+            .await
+            .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+        let _: () = conn
+            .hset_multiple(
+                session_key(id),
+                &[("end_reason", reason), ("ended_by", ended_by)],
+            )
+            .await?;
+        Ok(true)
+    }
+
+    pub fn is_requester(&self, email: &str, device: &str) -> bool {
+        self.from_email == email && self.from_device == device
+    }
+
+    pub fn is_target(&self, email: &str, device: &str) -> bool {
+        self.to_email == email && self.to_device == device
+    }
+
+    // the other device of the session, None when the device is not part of it
+    pub fn peer_of(&self, email: &str, device: &str) -> Option<(String, String)> {
+        if self.is_requester(email, device) {
+            Some((self.to_email.clone(), self.to_device.clone()))
+        } else if self.is_target(email, device) {
+            Some((self.from_email.clone(), self.from_device.clone()))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        OPEN_STATES.contains(&self.status.as_str())
+    }
+
+    // the message travels between the two devices of this session, in either direction
+    fn involves(&self, message: &SocketMessage) -> bool {
+        (self.is_requester(&message.from_email, &message.from_device)
+            && self.is_target(&message.to_email, &message.to_device))
+            || (self.is_target(&message.from_email, &message.from_device)
+                && self.is_requester(&message.to_email, &message.to_device))
+    }
+}
+
+pub async fn session_not_found(id: &str, message: &SocketMessage, tx: &mpsc::Sender<Message>) {
+    send_error(
+        tx,
+        "session_not_found",
+        format!("Session {} does not exist or has ended", id),
+        message,
+    )
+    .await;
+}
+
+async fn session_unavailable(
+    e: RedisManagerError,
+    message: &SocketMessage,
+    tx: &mpsc::Sender<Message>,
+) {
+    eprintln!("Signaling session error: {}", e);
+    send_error(
+        tx,
+        "error",
+        "Could not load the session".to_string(),
+        message,
+    )
+    .await;
+}
+
+// loads the session named by the message, answering the sender when there is none
+pub async fn load_session(
+    message: &SocketMessage,
+    state: &AppState,
+    tx: &mpsc::Sender<Message>,
+) -> Option<SignalingSession> {
+    let Some(id) = message.session_id.as_deref() else {
+        send_error(tx, "error", "session_id is required".to_string(), message).await;
+        return None;
+    };
+    match SignalingSession::get(id, state).await {
+        Ok(Some(session)) => Some(session),
+        Ok(None) => {
+            session_not_found(id, message, tx).await;
+            None
+        }
+        Err(e) => {
+            session_unavailable(e, message, tx).await;
+            None
+        }
+    }
+}
+
+// stores a requested session for a permitted try_connect and tags the message with its id;
+// the requester learns the id right away so it can match the target's answer
+pub async fn open_session(
+    message: &mut SocketMessage,
+    session_type: &str,
+    state: &AppState,
+    tx: &mpsc::Sender<Message>,
+) -> bool {
+    if is_locked_out(message, state).await {
+        send_error(
+            tx,
+            "pin_locked",
+            "Too many wrong PINs for this device, try again later".to_string(),
+            message,
+        )
+        .await;
+        return false;
+    }
+    let session = match SignalingSession::create(message, session_type, state).await {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Failed to create signaling session: {}", e);
+            send_error(
+                tx,
+                "error",
+                "Could not start a session".to_string(),
+                message,
+            )
+            .await;
+            return false;
+        }
+    };
+    message.session_id = Some(session.id.clone());
+
+    let response = json!({
+        "event": "try_connect",
+        "status": SESSION_REQUESTED,
+        "session_id": session.id,
+        "session_type": session.session_type,
+        "target_email": session.to_email,
+        "target_device": session.to_device,
+    });
+    let _ = tx.send(Message::Text(response.to_string().into())).await;
+
+    watch_consent_timeout(session, state.clone(), tx.clone());
+    true
+}
+
+// the requester's pod owns the timeout; whoever moves the status first wins
+fn watch_consent_timeout(session: SignalingSession, state: AppState, tx: mpsc::Sender<Message>) {
+    tokio::spawn(async move {
+        tokio::time::sleep(consent_timeout()).await;
+        let timed_out =
+            SignalingSession::end(&session.id, &[SESSION_REQUESTED], END_TIMEOUT, "", &state)
+                .await
+                .unwrap_or(false);
+        if !timed_out {
+            return;
+        }
+
+        let response = json!({
+            "event": SESSION_TIMEOUT_EVENT,
+            "session_id": session.id,
+            "target_email": session.to_email,
+            "target_device": session.to_device,
+        });
+        let _ = tx.send(Message::Text(response.to_string().into())).await;
+        // lets the target drop its prompt
+        let _ = notify_device(
+            &state,
+            &session.to_email,
+            &session.to_device,
+            SESSION_TIMEOUT_EVENT,
+            json!({"session_id": session.id, "email": session.from_email}),
+        )
+        .await;
+    });
+}
+
+// `session_accept` / `session_reject` from the target of a requested session
+pub async fn answer_session(
+    message: &mut SocketMessage,
+    state: &AppState,
+    tx: &mpsc::Sender<Message>,
+) -> bool {
+    let Some(session) = load_session(message, state, tx).await else {
+        return false;
+    };
+
+    // only the requested device answers, and only back to the requester
+    if !session.is_target(&message.from_email, &message.from_device)
+        || !session.is_requester(&message.to_email, &message.to_device)
+    {
+        session_not_found(&session.id, message, tx).await;
+        return false;
+    }
+
+    let answered = if message.event == "session_accept" {
+        // the PIN is stored with the accept itself, before the requester can see it, and
+        // not at all when the session was answered or ended meanwhile
+        let pin_fields = match pin_fields(&session, message, state).await {
+            Ok(pin_fields) => pin_fields,
+            Err(e) => {
+                session_unavailable(e, message, tx).await;
+                return false;
+            }
+        };
+        if !pin_fields.is_empty()
+            && let Some(payload) = message.payload.as_object_mut()
+        {
+            payload.insert("pin_required".to_string(), json!(true));
+        }
+        SignalingSession::transition_with(
+            &session.id,
+            &[SESSION_REQUESTED],
+            SESSION_ACCEPTED,
+            &pin_fields,
+            state,
+        )
+        .await
+    } else {
+        SignalingSession::end(
+            &session.id,
+            &[SESSION_REQUESTED],
+            END_REJECTED,
+            &session.to_email,
+            state,
+        )
+        .await
+    };
+    match answered {
+        Ok(true) => true,
+        Ok(false) => {
+            send_error(
+                tx,
+                "session_not_pending",
+                format!("Session {} was already answered or timed out", session.id),
+                message,
+            )
+            .await;
+            false
+        }
+        Err(e) => {
+            session_unavailable(e, message, tx).await;
+            false
+        }
+    }
+}
+
+// SDP and ICE only flow inside an accepted session between its two devices;
+// the first offer moves the session to negotiating
+pub async fn signal_allowed(
+    message: &SocketMessage,
+    state: &AppState,
+    tx: &mpsc::Sender<Message>,
+) -> bool {
+    let Some(session) = load_session(message, state, tx).await else {
+        return false;
+    };
+    if !session.involves(message) || !SIGNALING_STATES.contains(&session.status.as_str()) {
+        send_error(
+            tx,
+            "session_not_accepted",
+            format!("{} needs a session the target has accepted", message.event),
+            message,
+        )
+        .await;
+        return false;
+    }
+    if session.pin_required && !session.pin_verified {
+        send_error(
+            tx,
+            "pin_required",
+            format!("Session {} is waiting for the requester's PIN", session.id),
+            message,
+        )
+        .await;
+        return false;
+    }
+    if message.event == "sdp_offer" && session.status == SESSION_ACCEPTED {
+        let _ = SignalingSession::transition(
+            &session.id,
+            &[SESSION_ACCEPTED],
+            SESSION_NEGOTIATING,
+            state,
+        )
+        .await;
+    }
+    true
+}
+
+// `connect` with a session_id: one side reports that the peer connection is up
+pub async fn mark_connected(message: &SocketMessage, state: &AppState, tx: &mpsc::Sender<Message>) {
+    let Some(session) = load_session(message, state, tx).await else {
+        return;
+    };
+    if !session.is_requester(&message.from_email, &message.from_device)
+        && !session.is_target(&message.from_email, &message.from_device)
+    {
+        session_not_found(&session.id, message, tx).await;
+        return;
+    }
+    // both sides may report it, the second report is a no-op
+    let moved = SignalingSession::transition(
+        &session.id,
+        &[SESSION_ACCEPTED, SESSION_NEGOTIATING],
+        SESSION_CONNECTED,
+        state,
+    )
+    .await;
+    match moved {
+        Ok(true) => {}
+        Ok(false) if session.status == SESSION_CONNECTED => {}
+        Ok(false) => {
+            send_error(
+                tx,
+                "session_not_accepted",
+                format!("Session {} is {}", session.id, session.status),
+                message,
+            )
+            .await;
+            return;
+        }
+        Err(e) => {
+            session_unavailable(e, message, tx).await;
+            return;
+        }
+    }
+    let response = json!({
+        "event": "connected",
+        "status": "ok",
+        "session_id": session.id,
+    });
+    let _ = tx.send(Message::Text(response.to_string().into())).await;
+}
+
+// `session_end` from either device; the other one is told, wherever it is
+pub async fn end_session(mut message: SocketMessage, state: &AppState, tx: &mpsc::Sender<Message>) {
+    let Some(session) = load_session(&message, state, tx).await else {
+        return;
+    };
+    let Some((peer_email, peer_device)) =
+        session.peer_of(&message.from_email, &message.from_device)
+    else {
+        session_not_found(&session.id, &message, tx).await;
+        return;
+    };
+
+    match SignalingSession::end(
+        &session.id,
+        &OPEN_STATES,
+        END_HANGUP,
+        &message.from_email,
+        state,
+    )
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            session_not_found(&session.id, &message, tx).await;
+            return;
+        }
+        Err(e) => {
+            session_unavailable(e, &message, tx).await;
+            return;
+        }
+    }
+
+    let response = json!({
+        "event": "session_end",
+        "status": "ok",
+        "session_id": session.id,
+    });
+    let _ = tx.send(Message::Text(response.to_string().into())).await;
+
+    message.to_email = peer_email;
+    message.to_device = peer_device;
+    deliver_to_peer(message, state.clone(), tx).await;
+}
+
+// `session_status`: either device may look up where its session stands
+pub async fn session_status(message: &SocketMessage, state: &AppState, tx: &mpsc::Sender<Message>) {
+    let Some(session) = load_session(message, state, tx).await else {
+        return;
+    };
+    if !session.is_requester(&message.from_email, &message.from_device)
+        && !session.is_target(&message.from_email, &message.from_device)
+    {
+        session_not_found(&session.id, message, tx).await;
+        return;
+    }
+    let response = json!({"event": "session_status", "session": session});
+    let _ = tx.send(Message::Text(response.to_string().into())).await;
+}
+
+// the open sessions the user takes part in, on any of its devices
+async fn open_sessions_of(
+    email: &str,
+    state: &AppState,
+) -> Result<Vec<SignalingSession>, RedisManagerError> {
+    let ids: Vec<String> = {
+        let mut conn = state
+            .redis_pool
+            .get()
+            .await
+            .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+        conn.smembers(user_sessions_key(email)).await?
+    };
+    let mut open = Vec::new();
+    let mut stale = Vec::new();
+    for id in ids {
+        match SignalingSession::get(&id, state).await? {
+            Some(session) if session.is_open() => open.push(session),
+            _ => stale.push(id),
+        }
+    }
+    if !stale.is_empty() {
+        let mut conn = state
+            .redis_pool
+            .get()
+            .await
+            .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+        let _: () = conn.srem(user_sessions_key(email), stale).await?;
+    }
+    Ok(open)
+}
+
+// ended by the server: both devices are told, wherever they are
+async fn end_and_notify(session: &SignalingSession, reason: &str, state: &AppState) {
+    match SignalingSession::end(&session.id, &OPEN_STATES, reason, "", state).await {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            eprintln!("Failed to end session {}: {}", session.id, e);
+            return;
+        }
+    }
+    for (email, device) in [
+        (&session.from_email, &session.from_device),
+        (&session.to_email, &session.to_device),
+    ] {
+        let _ = notify_device(
+            state,
+            email,
+            device,
+            "session_end",
+            json!({"session_id": session.id, "reason": reason}),
+        )
+        .await;
+    }
+}
+
+// after a connection, its permissions or its device grants changed: sessions between
+// the two users whose requester could no longer start them are ended
+pub async fn end_unpermitted_sessions(email: &str, other_email: &str, state: &AppState) {
+    let sessions = match open_sessions_of(email, state).await {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            eprintln!("Failed to load sessions of {}: {}", email, e);
+            return;
+        }
+    };
+    for session in sessions {
+        if session.from_email != other_email && session.to_email != other_email {
+            continue;
+        }
+        let permitted = match reachable_permissions(
+            &session.from_email,
+            &session.to_email,
+            &session.to_device,
+            state,
+        )
+        .await
+        {
+            Ok(permissions) => permissions.is_some_and(|p| p.contains(&session.session_type)),
+            Err(e) => {
+                eprintln!("Failed to check session {}: {}", session.id, e);
+                continue;
+            }
+        };
+        if !permitted {
+            end_and_notify(&session, END_REVOKED, state).await;
+        }
+    }
+}
+
+// a device that went away for good takes its sessions with it
+pub async fn end_device_sessions(email: &str, device: &str, state: &AppState) {
+    match open_sessions_of(email, state).await {
+        Ok(sessions) => {
+            for session in sessions {
+                if session.peer_of(email, device).is_some() {
+                    end_and_notify(&session, END_DISCONNECTED, state).await;
+                }
+            }
+        }
+        Err(e) => eprintln!("Failed to load sessions of {}: {}", email, e),
+    }
+}
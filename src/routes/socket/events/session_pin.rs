@@ -0,0 +1,267 @@
+use axum::extract::ws::Message;
+use redis::AsyncCommands;
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::{
+    app_state::AppState,
+    db::models::{
+        audit_event::{AuditEntry, SESSION_PIN_FAILED, SESSION_PIN_LOCKOUT},
+        device_password::DevicePassword,
+    },
+    routes::socket::{
+        events::{
+            forwarder::send_error,
+            session::{
+                END_PIN_LOCKED, OPEN_STATES, SESSION_ACCEPTED, SignalingSession, load_session,
+                session_key, session_not_found,
+            },
+        },
+        redis_manager::{RedisManagerError, notify_device},
+        types::SocketMessage,
+    },
+    utils::{
+        client_info::ClientInfo,
+        hash_service::bcrypt::{hash_password, verify_password},
+    },
+};
+
+// wrong PINs a requester may enter for one device before it is locked out for a while
+const MAX_PIN_ATTEMPTS: i64 = 5;
+const PIN_LOCKOUT_SECS: i64 = 15 * 60;
+
+fn failures_key(target_email: &str, target_device: &str, requester_email: &str) -> String {
+    format!(
+        "signaling:pin_failures:{}:{}:{}",
+        target_email, target_device, requester_email
+    )
+}
+
+async fn failed_attempts(
+    target_email: &str,
+    target_device: &str,
+    requester_email: &str,
+    state: &AppState,
+) -> i64 {
+    let Ok(mut conn) = state.redis_pool.get().await else {
+        return 0;
+    };
+    conn.get(failures_key(target_email, target_device, requester_email))
+        .await
+        .unwrap_or(0)
+}
+
+async fn count_attempt(key: &str, state: &AppState) -> Result<i64, RedisManagerError> {
+    let mut conn = state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+    let (attempts,): (i64,) = redis::pipe()
+        .incr(key, 1)
+        .expire(key, PIN_LOCKOUT_SECS)
+        .ignore()
+        .query_async(&mut *conn)
+        .await?;
+    Ok(attempts)
+}
+
+pub async fn is_locked_out(message: &SocketMessage, state: &AppState) -> bool {
+    failed_attempts(
+        &message.to_email,
+        &message.to_device,
+        &message.from_email,
+        state,
+    )
+    .await
+        >= MAX_PIN_ATTEMPTS
+}
+
+// called while the target accepts: a `pin` in its payload is a one-off PIN shown on the
+// host, otherwise the device's standing access password applies if it has one.
+// The PIN is taken out of the payload so it never reaches the requester. The session
+// fields to store with the accept, none when no PIN is asked for
+pub async fn pin_fields(
+    session: &SignalingSession,
+    message: &mut SocketMessage,
+    state: &AppState,
+) -> Result<Vec<(&'static str, String)>, RedisManagerError> {
+    let pin = message
+        .payload
+        .as_object_mut()
+        .and_then(|payload| payload.remove("pin"))
+        .and_then(|pin| pin.as_str().map(str::to_string))
+        .filter(|pin| !pin.is_empty());
+    let required = match pin {
+        Some(_) => true,
+        None => DevicePassword::hash_for(&session.to_email, &session.to_device, state)
+            .await
+            .map_err(|e| RedisManagerError::PoolError(e.to_string()))?
+            .is_some(),
+    };
+    if !required {
+        return Ok(Vec::new());
+    }
+
+    let mut fields = vec![("pin_required", "1".to_string())];
+    if let Some(pin) = pin {
+        fields.push(("pin_hash", hash_password(pin).await));
+    }
+    Ok(fields)
+}
+
+// `session_pin` from the requester of an accepted session that asks for one
+pub async fn verify_pin(
+    message: &SocketMessage,
+    client: &ClientInfo,
+    state: &AppState,
+    tx: &mpsc::Sender<Message>,
+) {
+    let Some(session) = load_session(message, state, tx).await else {
+        return;
+    };
+    if !session.is_requester(&message.from_email, &message.from_device) {
+        session_not_found(&session.id, message, tx).await;
+        return;
+    }
+    if session.status != SESSION_ACCEPTED || !session.pin_required || session.pin_verified {
+        send_error(
+            tx,
+            "error",
+            format!("Session {} is not waiting for a PIN", session.id),
+            message,
+        )
+        .await;
+        return;
+    }
+
+    // the attempt is counted before the PIN is checked so parallel guesses can't get
+    // past the limit; the right PIN clears the count again
+    let key = failures_key(&session.to_email, &session.to_device, &session.from_email);
+    let attempts = match count_attempt(&key, state).await {
+        Ok(attempts) => attempts,
+        Err(e) => {
+            eprintln!("Failed to count PIN attempt: {}", e);
+            send_error(tx, "error", "Could not verify the PIN".to_string(), message).await;
+            return;
+        }
+    };
+    if attempts > MAX_PIN_ATTEMPTS {
+        send_error(
+            tx,
+            "pin_locked",
+            "Too many wrong PINs for this device, try again later".to_string(),
+            message,
+        )
+        .await;
+        return;
+    }
+
+    let pin = message
+        .payload
+        .get("pin")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let expected = match session_pin_hash(&session, state).await {
+        Ok(expected) => expected,
+        Err(e) => {
+            eprintln!("Failed to load session PIN: {}", e);
+            send_error(tx, "error", "Could not verify the PIN".to_string(), message).await;
+            return;
+        }
+    };
+    let verified = match expected {
+        Some(hash) => verify_password(pin, hash).await,
+        None => false,
+    };
+
+    if verified {
+        if let Ok(mut conn) = state.redis_pool.get().await {
+            let _: Result<(), _> = conn
+                .hset(session_key(&session.id), "pin_verified", "1")
+                .await;
+            let _: Result<(), _> = conn.del(&key).await;
+        }
+        let response = json!({"event": "session_pin", "status": "ok", "session_id": session.id});
+        let _ = tx.send(Message::Text(response.to_string().into())).await;
+        let _ = notify_device(
+            state,
+            &session.to_email,
+            &session.to_device,
+            "session_pin",
+            json!({"session_id": session.id, "status": "verified"}),
+        )
+        .await;
+        return;
+    }
+
+    let target = format!("{}/{}", session.to_email, session.to_device);
+    AuditEntry::new(SESSION_PIN_FAILED)
+        .actor_email(&session.from_email)
+        .target(target.clone())
+        .client(client)
+        .details(json!({"session_id": session.id, "attempts": attempts}))
+        .record(state);
+
+    if attempts < MAX_PIN_ATTEMPTS {
+        send_error(
+            tx,
+            "pin_invalid",
+            format!("Wrong PIN, {} attempts left", MAX_PIN_ATTEMPTS - attempts),
+            message,
+        )
+        .await;
+        return;
+    }
+
+    // out of attempts: the session is over and the host is told why
+    AuditEntry::new(SESSION_PIN_LOCKOUT)
+        .actor_email(&session.from_email)
+        .target(target)
+        .client(client)
+        .details(json!({"session_id": session.id}))
+        .record(state);
+    let _ = SignalingSession::end(
+        &session.id,
+        &OPEN_STATES,
+        END_PIN_LOCKED,
+        &session.from_email,
+        state,
+    )
+    .await;
+    send_error(
+        tx,
+        "pin_locked",
+        "Too many wrong PINs for this device, try again later".to_string(),
+        message,
+    )
+    .await;
+    let _ = notify_device(
+        state,
+        &session.to_email,
+        &session.to_device,
+        "session_end",
+        json!({"session_id": session.id, "reason": END_PIN_LOCKED}),
+    )
+    .await;
+}
+
+// the one-off PIN of the session if the host gave one, else the device password
+async fn session_pin_hash(
+    session: &SignalingSession,
+    state: &AppState,
+) -> Result<Option<String>, RedisManagerError> {
+    let mut conn = state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+    let pin_hash: Option<String> = conn.hget(session_key(&session.id), "pin_hash").await?;
+    if pin_hash.is_some() {
+        return Ok(pin_hash);
+    }
+    DevicePassword::hash_for(&session.to_email, &session.to_device, state)
+        .await
+        .map_err(|e| RedisManagerError::PoolError(e.to_string()))
+}
@@ -1,4 +1,6 @@
+pub mod backpressure;
 pub mod events;
 pub mod redis_manager;
+#[allow(clippy::module_inception)]
 pub mod socket;
 pub mod types;
@@ -1,13 +1,42 @@
-use axum::extract::ws::Message;
+use axum::extract::ws::{CloseFrame, Message, close_code};
 use futures_util::StreamExt;
 use redis::AsyncCommands;
 use serde_json;
 
 use crate::{
     app_state::AppState,
-    routes::socket::types::{DeviceInfo, RedisMessage},
+    routes::socket::{
+        backpressure::{Delivery, SocketHandle, deliver, droppable},
+        events::forwarder::confirm_message_delivery,
+        types::{DeviceInfo, RedisMessage},
+    },
 };
 
+// relay frames travel as raw bytes on a channel of the pod holding the peer, kept
+// out of the JSON bus
+pub fn relay_channel(pod_id: &str) -> String {
+    format!("socket:relay:{}", pod_id)
+}
+
+// presence goes only while the user's device set still maps the device to the socket
+// that is going away
+const REMOVE_PRESENCE_SCRIPT: &str = r#"
+if redis.call('HGET', KEYS[1], ARGV[1]) ~= ARGV[2] then
+    return 0
+end
+redis.call('HDEL', KEYS[1], ARGV[1])
+redis.call('DEL', KEYS[2], KEYS[3])
+return 1
+"#;
+
+// the pod a registered device is connected to
+fn device_pod_key(email: &str, device_id: &str) -> String {
+    format!("socket:pod:{}:{}", email, device_id)
+}
+// the pod that delivered a message tells the pod that published it
+pub const DELIVERED_CHANNEL: &str = "socket:delivered";
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum RedisManagerError {
     PoolError(String),
@@ -49,6 +78,118 @@ pub async fn publish_message(
     Ok(())
 }
 
+// [u16 email length][email][u16 device length][device][frame]; false when the pod
+// is not listening anymore
+pub async fn publish_relay_frame(
+    app_state: &AppState,
+    pod_id: &str,
+    email: &str,
+    device_id: &str,
+    frame: &[u8],
+) -> Result<bool, RedisManagerError> {
+    let mut conn = app_state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+
+    let mut payload = Vec::with_capacity(4 + email.len() + device_id.len() + frame.len());
+    for part in [email.as_bytes(), device_id.as_bytes()] {
+        payload.extend_from_slice(&(part.len() as u16).to_be_bytes());
+        payload.extend_from_slice(part);
+    }
+    payload.extend_from_slice(frame);
+
+    let receivers: i64 = conn.publish(relay_channel(pod_id), payload).await?;
+    Ok(receivers > 0)
+}
+
+fn split_relay_payload(payload: &[u8]) -> Option<(String, String, &[u8])> {
+    let mut parts = Vec::with_capacity(2);
+    let mut offset = 0;
+    for _ in 0..2 {
+        let length =
+            u16::from_be_bytes([*payload.get(offset)?, *payload.get(offset + 1)?]) as usize;
+        let part = payload.get(offset + 2..offset + 2 + length)?;
+        parts.push(String::from_utf8(part.to_vec()).ok()?);
+        offset += 2 + length;
+    }
+    let device_id = parts.pop()?;
+    let email = parts.pop()?;
+    Some((email, device_id, &payload[offset..]))
+}
+
+// the socket of a device connected to this pod
+pub async fn local_socket(
+    app_state: &AppState,
+    email: &str,
+    device_id: &str,
+) -> Option<SocketHandle> {
+    let socket_id = app_state
+        .email_device_to_socket
+        .read()
+        .await
+        .get(email)?
+        .get(device_id)?
+        .clone();
+    app_state
+        .socket_id_to_connection
+        .read()
+        .await
+        .get(&socket_id)
+        .cloned()
+}
+
+// the device is registered on some pod
+pub async fn device_online(
+    app_state: &AppState,
+    email: &str,
+    device_id: &str,
+) -> Result<bool, RedisManagerError> {
+    let mut conn = app_state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+
+    let online: bool = conn
+        .exists(AppState::get_redis_presence_key(email, device_id))
+        .await?;
+    Ok(online)
+}
+
+// the pod a device is registered on, None when it is offline
+pub async fn device_pod(
+    app_state: &AppState,
+    email: &str,
+    device_id: &str,
+) -> Result<Option<String>, RedisManagerError> {
+    let mut conn = app_state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+
+    let pod_id: Option<String> = conn.get(device_pod_key(email, device_id)).await?;
+    Ok(pod_id)
+}
+
+async fn publish_delivered(
+    app_state: &AppState,
+    message_id: &str,
+    sender_pod: &str,
+) -> Result<(), RedisManagerError> {
+    let mut conn = app_state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
+
+    let payload = serde_json::json!({"message_id": message_id, "pod_id": sender_pod});
+    let _: () = conn.publish(DELIVERED_CHANNEL, payload.to_string()).await?;
+    Ok(())
+}
+
 pub async fn store_device_presence(
     app_state: &AppState,
     email: &str,
@@ -69,6 +210,9 @@ pub async fn store_device_presence(
 
     // Store device presence
     let _: () = conn.set(&presence_key, &device_info_json).await?;
+    let _: () = conn
+        .set(device_pod_key(email, device_id), &app_state.pod_id)
+        .await?;
 
     // Add device to user's device set
     let _: () = conn
@@ -78,27 +222,46 @@ pub async fn store_device_presence(
     Ok(())
 }
 
+// false when the device registered again with another socket, whose presence stays
 pub async fn remove_device_presence(
     app_state: &AppState,
     email: &str,
     device_id: &str,
-) -> Result<(), RedisManagerError> {
+    socket_id: &str,
+) -> Result<bool, RedisManagerError> {
     let mut conn = app_state
         .redis_pool
         .get()
         .await
         .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
 
-    let presence_key = AppState::get_redis_presence_key(email, device_id);
-    let user_devices_key = AppState::get_redis_user_devices_key(email);
-
-    // Remove device presence
-    let _: () = conn.del(&presence_key).await?;
+    let removed: i64 = redis::Script::new(REMOVE_PRESENCE_SCRIPT)
+        .key(AppState::get_redis_user_devices_key(email))
+        .key(AppState::get_redis_presence_key(email, device_id))
+        .key(device_pod_key(email, device_id))
+        .arg(device_id)
+        .arg(socket_id)
+        .invoke_async(&mut *conn)
+        .await?;
+    Ok(removed == 1)
+}
 
-    // Remove device from user's device set
-    let _: () = conn.hdel(&user_devices_key, device_id).await?;
+// the socket a device is registered with, on any pod
+pub async fn registered_socket(
+    app_state: &AppState,
+    email: &str,
+    device_id: &str,
+) -> Result<Option<String>, RedisManagerError> {
+    let mut conn = app_state
+        .redis_pool
+        .get()
+        .await
+        .map_err(|e| RedisManagerError::PoolError(e.to_string()))?;
 
-    Ok(())
+    let socket_id: Option<String> = conn
+        .hget(AppState::get_redis_user_devices_key(email), device_id)
+        .await?;
+    Ok(socket_id)
 }
 
 pub async fn get_user_devices(
@@ -119,65 +282,140 @@ pub async fn get_user_devices(
     let mut devices = Vec::new();
     for device_id in device_ids {
         let presence_key = AppState::get_redis_presence_key(email, &device_id);
-        if let Ok(device_info_json) = conn.get::<_, String>(&presence_key).await {
-            if let Ok(device_info) = serde_json::from_str::<DeviceInfo>(&device_info_json) {
-                devices.push(device_info);
-            }
+        if let Ok(device_info_json) = conn.get::<_, String>(&presence_key).await
+            && let Ok(device_info) = serde_json::from_str::<DeviceInfo>(&device_info_json)
+        {
+            devices.push(device_info);
         }
     }
 
     Ok(devices)
 }
 
+// presence goes only to the users allowed to see this user's devices
+async fn broadcast_presence(
+    app_state: &AppState,
+    email: &str,
+    device_id: &str,
+    event: &str,
+    watchers: &[String],
+) -> Result<(), RedisManagerError> {
+    for watcher in watchers {
+        let message = RedisMessage {
+            target_email: watcher.clone(),
+            target_device: "*".to_string(),
+            socket_message: crate::routes::socket::types::SocketMessage {
+                from_email: email.to_string(),
+                from_token: String::new(),
+                from_device: device_id.to_string(),
+                to_email: watcher.clone(),
+                to_device: String::new(),
+                event: event.to_string(),
+                payload: serde_json::json!({"email": email, "device_id": device_id}),
+                session_id: None,
+            },
+            sender_pod: None,
+            timestamp: Some(chrono::Utc::now().timestamp_millis() as u64),
+            message_id: None,
+        };
+
+        publish_message(app_state, &message).await?;
+    }
+    Ok(())
+}
+
 pub async fn broadcast_user_joined(
     app_state: &AppState,
     email: &str,
     device_id: &str,
+    watchers: &[String],
+) -> Result<(), RedisManagerError> {
+    broadcast_presence(app_state, email, device_id, "user_joined", watchers).await
+}
+
+pub async fn broadcast_user_left(
+    app_state: &AppState,
+    email: &str,
+    device_id: &str,
+    watchers: &[String],
+) -> Result<(), RedisManagerError> {
+    broadcast_presence(app_state, email, device_id, "user_left", watchers).await
+}
+
+pub const FORCE_DISCONNECT_EVENT: &str = "force_disconnect";
+
+// closes the device's socket on whichever pod holds it; "*" targets every device of the user
+pub async fn force_disconnect(
+    app_state: &AppState,
+    email: &str,
+    device_id: &str,
+    reason: &str,
 ) -> Result<(), RedisManagerError> {
     let message = RedisMessage {
-        target_email: "*".to_string(),
-        target_device: "*".to_string(),
+        target_email: email.to_string(),
+        target_device: device_id.to_string(),
         socket_message: crate::routes::socket::types::SocketMessage {
-            from_email: email.to_string(),
+            from_email: String::new(),
             from_token: String::new(),
-            from_device: device_id.to_string(),
-            to_email: String::new(),
-            to_device: String::new(),
-            event: "user_joined".to_string(),
-            payload: serde_json::json!({"email": email, "device_id": device_id}),
+            from_device: String::new(),
+            to_email: email.to_string(),
+            to_device: device_id.to_string(),
+            event: FORCE_DISCONNECT_EVENT.to_string(),
+            payload: serde_json::json!({"reason": reason}),
+            session_id: None,
         },
         sender_pod: None,
         timestamp: Some(chrono::Utc::now().timestamp_millis() as u64),
+        message_id: None,
     };
 
     publish_message(app_state, &message).await
 }
 
-pub async fn broadcast_user_left(
+// a server event for every online device of the user, on whichever pod it is
+pub async fn notify_user(
+    app_state: &AppState,
+    email: &str,
+    event: &str,
+    payload: serde_json::Value,
+) -> Result<(), RedisManagerError> {
+    notify_device(app_state, email, "*", event, payload).await
+}
+
+// a server event for one device of the user, or all of them with "*"
+pub async fn notify_device(
     app_state: &AppState,
     email: &str,
     device_id: &str,
+    event: &str,
+    payload: serde_json::Value,
 ) -> Result<(), RedisManagerError> {
     let message = RedisMessage {
-        target_email: "*".to_string(),
-        target_device: "*".to_string(),
+        target_email: email.to_string(),
+        target_device: device_id.to_string(),
         socket_message: crate::routes::socket::types::SocketMessage {
-            from_email: email.to_string(),
+            from_email: String::new(),
             from_token: String::new(),
-            from_device: device_id.to_string(),
-            to_email: String::new(),
-            to_device: String::new(),
-            event: "user_left".to_string(),
-            payload: serde_json::json!({"email": email, "device_id": device_id}),
+            from_device: String::new(),
+            to_email: email.to_string(),
+            to_device: if device_id == "*" {
+                String::new()
+            } else {
+                device_id.to_string()
+            },
+            event: event.to_string(),
+            payload,
+            session_id: None,
         },
         sender_pod: None,
         timestamp: Some(chrono::Utc::now().timestamp_millis() as u64),
+        message_id: None,
     };
 
     publish_message(app_state, &message).await
 }
 
-pub async fn start_redis_subscriber(app_state: AppState) {
+pub fn start_redis_subscriber(app_state: AppState) {
     tokio::spawn(async move {
         loop {
             let result = subscribe_and_handle(&app_state).await;
@@ -192,16 +430,44 @@ pub async fn start_redis_subscriber(app_state: AppState) {
 async fn subscribe_and_handle(
     app_state: &AppState,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let client = redis::Client::open("redis://127.0.0.1/")?;
+    let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".into());
+    let client = redis::Client::open(redis_url)?;
     let mut pubsub = client.get_async_pubsub().await?;
     pubsub.subscribe("socket:messages").await?;
+    let relay_channel = relay_channel(&app_state.pod_id);
+    pubsub.subscribe(&relay_channel).await?;
+    pubsub.subscribe(DELIVERED_CHANNEL).await?;
 
     let mut msg_stream = pubsub.on_message();
 
     loop {
         if let Some(msg) = msg_stream.next().await {
+            if msg.get_channel_name() == relay_channel {
+                let payload: Vec<u8> = msg.get_payload()?;
+                if let Some((email, device_id, frame)) = split_relay_payload(&payload)
+                    && let Some(target) = local_socket(app_state, &email, &device_id).await
+                {
+                    deliver(&target, Message::Binary(frame.to_vec().into()), true);
+                }
+                continue;
+            }
             let payload: String = msg.get_payload()?;
 
+            if msg.get_channel_name() == DELIVERED_CHANNEL {
+                if let Ok(delivered) = serde_json::from_str::<serde_json::Value>(&payload)
+                    && delivered["pod_id"].as_str() == Some(app_state.pod_id.as_str())
+                    && let Some(message_id) = delivered["message_id"].as_str()
+                {
+                    confirm_message_delivery(
+                        &app_state.pending_deliveries,
+                        message_id.to_string(),
+                        true,
+                    )
+                    .await;
+                }
+                continue;
+            }
+
             if let Ok(redis_message) = serde_json::from_str::<RedisMessage>(&payload) {
                 handle_redis_message(app_state, redis_message).await;
             }
@@ -214,22 +480,64 @@ async fn handle_redis_message(app_state: &AppState, message: RedisMessage) {
     let target_device = message.target_device.clone();
 
     // Check if this message is for a user on this pod
-    let email_device_map = app_state.email_device_to_socket.read().await;
-
-    if let Some(device_map) = email_device_map.get(&target_email) {
-        if let Some(socket_id) = device_map.get(&target_device) {
-            // User is on this pod, forward the message
-            let socket_connections = app_state.socket_id_to_connection.read().await;
-
-            if let Some(tx) = socket_connections.get(socket_id) {
-                let msg_text = serde_json::to_string(&message.socket_message).unwrap_or_default();
-                let _ = tx.send(Message::Text(msg_text.into())).await;
+    let socket_ids: Vec<String> = {
+        let email_device_map = app_state.email_device_to_socket.read().await;
+        match email_device_map.get(&target_email) {
+            Some(device_map) if target_device == "*" => device_map.values().cloned().collect(),
+            Some(device_map) => device_map
+                .get(&target_device)
+                .cloned()
+                .into_iter()
+                .collect(),
+            None => Vec::new(),
+        }
+    };
 
-                // TODO: Send delivery confirmation back to originating pod
-                // This would require tracking pending messages and having a confirmation channel
+    // User is on this pod, forward the message
+    let mut delivered_here = false;
+    let socket_connections = app_state.socket_id_to_connection.read().await;
+    for socket_id in socket_ids {
+        if let Some(target) = socket_connections.get(&socket_id) {
+            // never awaited, a slow socket must not hold up the subscriber
+            let msg_text = serde_json::to_string(&message.socket_message).unwrap_or_default();
+            let delivered = deliver(
+                target,
+                Message::Text(msg_text.into()),
+                droppable(&message.socket_message.event),
+            );
+
+            if message.socket_message.event == FORCE_DISCONNECT_EVENT {
+                let reason = message.socket_message.payload["reason"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string();
+                deliver(
+                    target,
+                    Message::Close(Some(CloseFrame {
+                        code: close_code::POLICY,
+                        reason: reason.into(),
+                    })),
+                    false,
+                );
             }
+
+            // a dropped frame reached a live socket too, only a closing one counts as missed
+            delivered_here |= matches!(delivered, Delivery::Sent | Delivery::Dropped);
         }
     }
+    drop(socket_connections);
+
+    // confirmed off the subscriber loop, a pool checkout must not hold up other messages
+    if delivered_here
+        && let (Some(message_id), Some(sender_pod)) = (message.message_id, message.sender_pod)
+    {
+        let app_state = app_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = publish_delivered(&app_state, &message_id, &sender_pod).await {
+                eprintln!("Failed to confirm delivery: {}", e);
+            }
+        });
+    }
 
     // Handle broadcast messages (user_joined, user_left)
     if target_email == "*" && target_device == "*" {
@@ -2,31 +2,52 @@ use axum::{
     Router,
     extract::{
         State, WebSocketUpgrade,
-        ws::{Message, WebSocket},
+        ws::{CloseFrame, Message, WebSocket},
     },
+    http::HeaderMap,
     response::IntoResponse,
     routing::get,
 };
 use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
 use std::ops::ControlFlow;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
 use uuid::Uuid;
 
 use crate::{
     app_state::AppState,
+    db::models::audit_event::{
+        AuditEntry, GUEST_LINK_REDEEM, GUEST_LINK_REDEEM_FAILED, SIGNALING_SESSION,
+        SOCKET_REGISTER, SOCKET_REGISTER_FAILED,
+    },
     routes::socket::{
+        backpressure::{CLOSE_SLOW_CONSUMER, SOCKET_BUFFER, SocketHandle, SocketQueue},
         events::{
             check::check_users_response,
             connect::on_connect,
             disconnect::disconnect_user,
-            forwarder::{PendingMessages, forward_to_peer},
+            forwarder::{forward_to_peer, send_error},
+            guest::{
+                GUEST_DEVICE_ID, GuestSession, disconnect_when_expired, handle_guest_message,
+                register_guest,
+            },
             heartbeat::handle_heartbeat,
+            offline_queue::deliver_queued,
+            pairing::{handle_pair, handle_pairing_code},
             register::register_user,
+            relay::{RelayStreams, flush_relay_streams, is_relay_frame, relay_frame, set_relay},
+            session::{end_session, session_status},
+            session_pin::verify_pin,
         },
         redis_manager::start_redis_subscriber,
         types::SocketMessage,
     },
+    utils::{
+        client_info::ClientInfo,
+        ice_service::ice_config::{ice_config, ice_host},
+    },
 };
 
 pub fn ws_route(state: AppState) -> Router {
@@ -35,40 +56,103 @@ pub fn ws_route(state: AppState) -> Router {
     Router::new().route("/", get(ws_handler).with_state(state))
 }
 
-async fn ws_handler(State(state): State<AppState>, ws: WebSocketUpgrade) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| socket_handler(socket, state))
+// per-connection data that stays fixed for the lifetime of the socket
+struct SocketContext {
+    socket_id: String,
+    client: ClientInfo,
+    // host the ICE servers are advertised under
+    ice_host: Option<String>,
+    relay_streams: RelayStreams,
+    queue: Arc<SocketQueue>,
 }
 
-async fn socket_handler(socket: WebSocket, state: AppState) {
+async fn ws_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let ice_host = ice_host(&headers);
+    ws.on_upgrade(move |socket| socket_handler(socket, state, client, ice_host))
+}
+
+async fn socket_handler(
+    socket: WebSocket,
+    state: AppState,
+    client: ClientInfo,
+    ice_host: Option<String>,
+) {
     let (mut sender, mut receiver) = socket.split();
-    let (tx, mut rx) = mpsc::channel(100);
+    let (tx, mut rx) = mpsc::channel(SOCKET_BUFFER);
 
     // Generate unique socket ID for this connection
-    let socket_id = Uuid::new_v4().to_string();
+    let context = SocketContext {
+        socket_id: Uuid::new_v4().to_string(),
+        client,
+        ice_host,
+        relay_streams: Arc::new(tokio::sync::Mutex::new(std::collections::HashMap::new())),
+        queue: Arc::new(SocketQueue::default()),
+    };
 
     // Spawn task to send messages to the websocket
+    // a Close frame queued by the server (e.g. force_disconnect) ends the connection
+    let (closed_tx, mut closed_rx) = oneshot::channel::<()>();
+    let queue = context.queue.clone();
+    let socket_id = context.socket_id.clone();
     tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if sender.send(msg).await.is_err() {
+        loop {
+            let write_next = async {
+                match rx.recv().await {
+                    Some(msg) => {
+                        let is_close = matches!(msg, Message::Close(_));
+                        sender.send(msg).await.is_err() || is_close
+                    }
+                    None => true,
+                }
+            };
+            let overflowed = tokio::select! {
+                _ = queue.closed() => true,
+                done = write_next => {
+                    if done {
+                        break;
+                    }
+                    false
+                }
+            };
+            if overflowed {
+                // the buffer ran full, what is still in it is given up
+                eprintln!("Closing socket {} that fell behind", socket_id);
+                let close = Message::Close(Some(CloseFrame {
+                    code: CLOSE_SLOW_CONSUMER,
+                    reason: "slow_consumer".into(),
+                }));
+                let _ = tokio::time::timeout(Duration::from_secs(1), sender.send(close)).await;
                 break;
             }
         }
+        let _ = closed_tx.send(());
     });
 
     let mut user_email: Option<String> = None;
     let mut device_id: Option<String> = None;
-    let pending_messages: PendingMessages =
-        Arc::new(tokio::sync::Mutex::new(std::collections::HashMap::new()));
+    let mut guest: Option<GuestSession> = None;
 
-    while let Some(Ok(msg)) = receiver.next().await {
+    loop {
+        let msg = tokio::select! {
+            incoming = receiver.next() => match incoming {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            _ = &mut closed_rx => break,
+        };
         if process_message(
             msg,
             state.clone(),
             &tx,
             &mut user_email,
             &mut device_id,
-            &socket_id,
-            &pending_messages,
+            &mut guest,
+            &context,
         )
         .await
         .is_break()
@@ -78,8 +162,45 @@ async fn socket_handler(socket: WebSocket, state: AppState) {
     }
 
     // Cleanup logic on disconnect
+    flush_relay_streams(&context.relay_streams, &state).await;
     if let (Some(email), Some(device)) = (user_email, device_id) {
-        disconnect_user(email, device, socket_id, state).await;
+        disconnect_user(email, device, context.socket_id, &tx, state).await;
+    }
+}
+
+// makes the socket reachable for messages addressed to this email and device
+async fn store_local_connection(
+    state: &AppState,
+    email: &str,
+    device: &str,
+    socket_id: &str,
+    tx: &mpsc::Sender<Message>,
+    queue: &Arc<SocketQueue>,
+) {
+    // Store local mappings
+    let key = format!("{}{}", email, device);
+    state
+        .socket_connections
+        .write()
+        .await
+        .insert(key, tx.clone());
+
+    // Store in new mappings
+    state.socket_id_to_connection.write().await.insert(
+        socket_id.to_string(),
+        SocketHandle {
+            tx: tx.clone(),
+            queue: queue.clone(),
+        },
+    );
+
+    let mut email_device_map = state.email_device_to_socket.write().await;
+    if let Some(device_map) = email_device_map.get_mut(email) {
+        device_map.insert(device.to_string(), socket_id.to_string());
+    } else {
+        let mut device_map = std::collections::HashMap::new();
+        device_map.insert(device.to_string(), socket_id.to_string());
+        email_device_map.insert(email.to_string(), device_map);
     }
 }
 
@@ -89,9 +210,23 @@ async fn process_message(
     tx: &mpsc::Sender<Message>,
     user_email: &mut Option<String>,
     device_id: &mut Option<String>,
-    socket_id: &str,
-    pending_messages: &PendingMessages,
+    guest: &mut Option<GuestSession>,
+    context: &SocketContext,
 ) -> ControlFlow<(), ()> {
+    let socket_id = context.socket_id.as_str();
+    // relay data bypasses the JSON handling, guests relay as their guest identity
+    if let Message::Binary(data) = &msg
+        && is_relay_frame(data)
+    {
+        let sender = match guest.as_ref() {
+            Some(session) => Some((session.email(), GUEST_DEVICE_ID.to_string())),
+            None => user_email.clone().zip(device_id.clone()),
+        };
+        if let Some((email, device)) = sender {
+            relay_frame(data, &email, &device, &state, tx, &context.relay_streams).await;
+        }
+        return ControlFlow::Continue(());
+    }
     match SocketMessage::parse_message(msg.clone()) {
         Ok(socket_message) => {
             // Validate the message
@@ -106,7 +241,86 @@ async fn process_message(
                 return ControlFlow::Continue(());
             }
 
+            if let Some(session) = guest.as_ref() {
+                return handle_guest_message(socket_message, session, state, tx, &context.client)
+                    .await;
+            }
+
             match socket_message.event.as_str() {
+                "guest_register" => {
+                    if user_email.is_some() {
+                        let error_response = serde_json::json!({
+                            "event": "error",
+                            "error": "Socket is already registered"
+                        });
+                        let _ = tx
+                            .send(Message::Text(error_response.to_string().into()))
+                            .await;
+                        return ControlFlow::Continue(());
+                    }
+
+                    match register_guest(&socket_message, &context.client, &state).await {
+                        Ok(session) => {
+                            AuditEntry::new(GUEST_LINK_REDEEM)
+                                .actor_email(&session.owner_email)
+                                .target(session.link_id.to_string())
+                                .client(&context.client)
+                                .details(json!({"socket_id": socket_id}))
+                                .record(&state);
+                            let email = session.email();
+                            store_local_connection(
+                                &state,
+                                &email,
+                                GUEST_DEVICE_ID,
+                                socket_id,
+                                tx,
+                                &context.queue,
+                            )
+                            .await;
+                            *user_email = Some(email.clone());
+                            *device_id = Some(GUEST_DEVICE_ID.to_string());
+                            disconnect_when_expired(&session, &state);
+
+                            let response = Message::Text(
+                                serde_json::json!({
+                                    "event": "guest_register",
+                                    "status": "ok",
+                                    "socket_id": socket_id,
+                                    "email": email,
+                                    "device_id": GUEST_DEVICE_ID,
+                                    "target": {
+                                        "email": session.owner_email,
+                                        "device_id": session.owner_device,
+                                        "permissions": session.permissions,
+                                    },
+                                    "ice": ice_config(&email, context.ice_host.as_deref()),
+                                })
+                                .to_string()
+                                .into(),
+                            );
+                            let _ = tx.send(response).await;
+                            deliver_queued(&state, &email, GUEST_DEVICE_ID, tx).await;
+                            *guest = Some(session);
+                        }
+                        Err(e) => {
+                            AuditEntry::new(GUEST_LINK_REDEEM_FAILED)
+                                .client(&context.client)
+                                .details(json!({"error": e}))
+                                .record(&state);
+                            let response = Message::Text(
+                                serde_json::json!({
+                                    "event": "guest_register",
+                                    "status": "error",
+                                    "error": e
+                                })
+                                .to_string()
+                                .into(),
+                            );
+                            let _ = tx.send(response).await;
+                            return ControlFlow::Break(());
+                        }
+                    }
+                }
                 "register" => {
                     // Only allow registration if not already registered
                     // Clone necessary fields before moving socket_message
@@ -122,46 +336,45 @@ async fn process_message(
                     .await
                     {
                         Ok(_) => {
+                            AuditEntry::new(SOCKET_REGISTER)
+                                .actor_email(&from_email)
+                                .target(from_device.clone())
+                                .client(&context.client)
+                                .details(json!({"socket_id": socket_id}))
+                                .record(&state);
                             *user_email = Some(from_email.clone());
                             *device_id = Some(from_device.clone());
 
-                            // Store local mappings
-                            let key = format!("{}{}", from_email, from_device);
-                            state
-                                .socket_connections
-                                .write()
-                                .await
-                                .insert(key, tx.clone());
-
-                            // Store in new mappings
-                            state
-                                .socket_id_to_connection
-                                .write()
-                                .await
-                                .insert(socket_id.to_string(), tx.clone());
-
-                            let mut email_device_map = state.email_device_to_socket.write().await;
-                            if let Some(device_map) = email_device_map.get_mut(&from_email) {
-                                device_map.insert(from_device.clone(), socket_id.to_string());
-                            } else {
-                                let mut device_map = std::collections::HashMap::new();
-                                device_map.insert(from_device.clone(), socket_id.to_string());
-                                email_device_map.insert(from_email, device_map);
-                            }
+                            store_local_connection(
+                                &state,
+                                &from_email,
+                                &from_device,
+                                socket_id,
+                                tx,
+                                &context.queue,
+                            )
+                            .await;
 
                             // Send success response
                             let response = Message::Text(
                                 serde_json::json!({
                                     "event": "register",
                                     "status": "ok",
-                                    "socket_id": socket_id
+                                    "socket_id": socket_id,
+                                    "ice": ice_config(&from_email, context.ice_host.as_deref()),
                                 })
                                 .to_string()
                                 .into(),
                             );
                             let _ = tx.send(response).await;
+                            deliver_queued(&state, &from_email, &from_device, tx).await;
                         }
                         Err(e) => {
+                            AuditEntry::new(SOCKET_REGISTER_FAILED)
+                                .target(from_email.clone())
+                                .client(&context.client)
+                                .details(json!({"device_id": from_device, "error": e}))
+                                .record(&state);
                             // Send error response
                             let response = Message::Text(
                                 serde_json::json!({
@@ -200,14 +413,76 @@ async fn process_message(
                         on_connect(socket_message, state.clone(), tx).await;
                     }
                 }
+                "pairing_code" => {
+                    if let (Some(email), Some(device)) =
+                        (user_email.as_deref(), device_id.as_deref())
+                    {
+                        handle_pairing_code(
+                            socket_message,
+                            email,
+                            device,
+                            &context.client,
+                            &state,
+                            tx,
+                        )
+                        .await;
+                    }
+                }
+                "pair" => {
+                    if let Some(email) = user_email.as_deref() {
+                        handle_pair(socket_message, email, &context.client, &state, tx).await;
+                    }
+                }
                 "ping" => {
                     if user_email.is_some() {
                         handle_heartbeat(socket_message, state.clone(), tx).await;
                     }
                 }
-                "try_connect" | "sdp_offer" | "sdp_answer" | "ice_candidate" => {
+                "try_connect" | "session_accept" | "session_reject" | "sdp_offer"
+                | "sdp_answer" | "ice_candidate" => {
                     if user_email.is_some() {
-                        forward_to_peer(socket_message, state.clone(), tx, pending_messages).await;
+                        // a socket may only speak for the device it registered
+                        if user_email.as_deref() != Some(socket_message.from_email.as_str())
+                            || device_id.as_deref() != Some(socket_message.from_device.as_str())
+                        {
+                            send_error(
+                                tx,
+                                "error",
+                                "from_email and from_device must match the registered device"
+                                    .to_string(),
+                                &socket_message,
+                            )
+                            .await;
+                            return ControlFlow::Continue(());
+                        }
+                        if socket_message.event == "try_connect" {
+                            AuditEntry::new(SIGNALING_SESSION)
+                                .actor_email(&socket_message.from_email)
+                                .target(format!(
+                                    "{}/{}",
+                                    socket_message.to_email, socket_message.to_device
+                                ))
+                                .client(&context.client)
+                                .details(json!({"from_device": socket_message.from_device}))
+                                .record(&state);
+                        }
+                        forward_to_peer(socket_message, state.clone(), tx).await;
+                    }
+                }
+                "session_end" | "session_status" | "session_pin" | "relay_open" | "relay_close" => {
+                    if user_email.as_deref() == Some(socket_message.from_email.as_str())
+                        && device_id.as_deref() == Some(socket_message.from_device.as_str())
+                    {
+                        match socket_message.event.as_str() {
+                            "session_end" => end_session(socket_message, &state, tx).await,
+                            "session_pin" => {
+                                verify_pin(&socket_message, &context.client, &state, tx).await
+                            }
+                            "relay_open" | "relay_close" => {
+                                set_relay(&socket_message, &state, tx).await
+                            }
+                            _ => session_status(&socket_message, &state, tx).await,
+                        }
                     }
                 }
                 "disconnect" => {
@@ -1,6 +1,7 @@
 use axum::extract::ws::Message;
 use serde::{Deserialize, Serialize, de::Error};
 use serde_json::Value;
+use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SocketMessage {
@@ -11,6 +12,9 @@ pub struct SocketMessage {
     pub to_device: String,
     pub event: String,
     pub payload: Value,
+    // the signaling session this message belongs to, assigned by the server on try_connect
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
 }
 
 impl SocketMessage {
@@ -51,7 +55,8 @@ impl SocketMessage {
                     return Err("from_email is required for connect".to_string());
                 }
             }
-            "try_connect" | "sdp_offer" | "sdp_answer" | "ice_candidate" => {
+            "try_connect" | "session_accept" | "session_reject" | "sdp_offer" | "sdp_answer"
+            | "ice_candidate" => {
                 if self.from_email.is_empty() {
                     return Err("from_email is required".to_string());
                 }
@@ -64,8 +69,35 @@ impl SocketMessage {
                 if self.from_device.is_empty() {
                     return Err("from_device is required".to_string());
                 }
+                if self.event != "try_connect" && self.session_id.is_none() {
+                    return Err("session_id is required".to_string());
+                }
+            }
+            "session_end" | "session_status" | "session_pin" | "relay_open" | "relay_close" => {
+                if self.from_email.is_empty() {
+                    return Err("from_email is required".to_string());
+                }
+                if self.from_device.is_empty() {
+                    return Err("from_device is required".to_string());
+                }
+                if self.session_id.is_none() {
+                    return Err("session_id is required".to_string());
+                }
+                if self.event == "session_pin" && self.payload.get("pin").is_none() {
+                    return Err("pin is required for session_pin".to_string());
+                }
+            }
+            "guest_register" => {
+                if self.payload.get("token").is_none() && self.payload.get("code").is_none() {
+                    return Err("token or code is required for guest_register".to_string());
+                }
+            }
+            "pair" => {
+                if self.payload.get("code").is_none() {
+                    return Err("code is required for pair".to_string());
+                }
             }
-            "ping" | "pong" | "disconnect" => {
+            "ping" | "pong" | "disconnect" | "pairing_code" => {
                 // No validation needed
             }
             _ => {
@@ -82,6 +114,9 @@ pub struct DeviceInfo {
     pub device_name: Option<String>,
     pub device_type: Option<String>,
     pub device_id: String,
+    // the checking user's own label for this device, filled in by check
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -91,12 +126,33 @@ pub struct RedisMessage {
     pub socket_message: SocketMessage,
     pub sender_pod: Option<String>,
     pub timestamp: Option<u64>,
+    // set when the sender waits for the receiving pod to confirm delivery
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+}
+
+pub const SOURCE_DIRECT: &str = "direct";
+pub const SOURCE_TEAM: &str = "team";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TeamRef {
+    pub id: Uuid,
+    pub name: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserDevicesResponse {
     pub email: String,
+    // the checking user's own label for this contact
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
     pub devices: Vec<DeviceInfo>,
+    // what the checking user may do on these devices
+    pub permissions: Vec<String>,
+    // "direct" for a user connection, "team" when shared through `team`
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<TeamRef>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,488 @@
+use axum::{
+    Extension, Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    db::models::{
+        audit_event::{AuditEntry, INVITE_EMAIL_FAILED, TEAM_ACTION},
+        team::{TEAM_ADMIN, TEAM_MEMBER, TEAM_OWNER, Team, can_manage},
+        team_device_grant::TeamDeviceGrant,
+        team_invitation::TeamInvitation,
+        user::User,
+        user_connection::{ALL_PERMISSIONS, is_permission},
+    },
+    routes::auth::signup::{create_signup_link, send_mail_in_background},
+    utils::{
+        auth_middleware::AuthUser, client_info::ClientInfo, error_status::error_status,
+        mail_service::mail_data::MailData, resolve_base_url::BaseUrl,
+    },
+};
+
+// same lifetime as connection invitations sent to new users
+const TEAM_INVITE_LINK_TTL: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Deserialize, Debug)]
+pub struct CreateTeamBody {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InviteBody {
+    email: String,
+    role: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MemberRoleBody {
+    role: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TeamDeviceGrantBody {
+    device_id: String,
+    // everything when omitted
+    permissions: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RemoveTeamDeviceGrantBody {
+    device_id: String,
+    // another member's device, only for owners and admins
+    owner_email: Option<String>,
+}
+
+pub fn team(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/",
+            get(list_teams).post(create_team).with_state(state.clone()),
+        )
+        .route(
+            "/invitations",
+            get(list_invitations).with_state(state.clone()),
+        )
+        .route(
+            "/{team_id}/members",
+            get(list_members).with_state(state.clone()),
+        )
+        .route(
+            "/{team_id}/invite",
+            post(invite_member).with_state(state.clone()),
+        )
+        .route(
+            "/{team_id}/accept",
+            post(accept_invitation).with_state(state.clone()),
+        )
+        .route(
+            "/{team_id}/decline",
+            post(decline_invitation).with_state(state.clone()),
+        )
+        .route(
+            "/{team_id}/leave",
+            post(leave_team).with_state(state.clone()),
+        )
+        .route(
+            "/{team_id}/members/{user_id}/role",
+            post(set_member_role).with_state(state.clone()),
+        )
+        .route(
+            "/{team_id}/members/{user_id}/remove",
+            post(remove_member).with_state(state.clone()),
+        )
+        .route(
+            "/{team_id}/device_grants",
+            get(list_device_grants)
+                .post(grant_device)
+                .with_state(state.clone()),
+        )
+        .route(
+            "/{team_id}/device_grants/remove",
+            post(revoke_device).with_state(state.clone()),
+        )
+}
+
+fn record_team_action(
+    state: &AppState,
+    auth_user: &AuthUser,
+    client: &ClientInfo,
+    team_id: Uuid,
+    operation: &str,
+    details: Value,
+) {
+    let mut details = details;
+    details["operation"] = json!(operation);
+    AuditEntry::new(TEAM_ACTION)
+        .actor(auth_user.id)
+        .target(team_id.to_string())
+        .client(client)
+        .details(details)
+        .record(state);
+}
+
+// the caller's role in the team; teams the caller is not in are reported as missing
+async fn member_role(state: &AppState, team_id: Uuid, user_id: Uuid) -> Result<String, StatusCode> {
+    match Team::member_role(team_id, user_id, state).await {
+        Ok(Some(role)) => Ok(role),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => Err(error_status(e)),
+    }
+}
+
+async fn manager_role(
+    state: &AppState,
+    team_id: Uuid,
+    user_id: Uuid,
+) -> Result<String, StatusCode> {
+    let role = member_role(state, team_id, user_id).await?;
+    if !can_manage(&role) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(role)
+}
+
+fn list_response<T: serde::Serialize>(
+    result: Result<Vec<T>, sqlx::Error>,
+) -> (StatusCode, Json<Value>) {
+    match result {
+        Ok(rows) => (StatusCode::OK, Json(json!({"res": rows}))),
+        Err(e) => (error_status(e), Json(json!({"res": []}))),
+    }
+}
+
+pub async fn create_team(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<CreateTeamBody>,
+) -> impl IntoResponse {
+    let name = payload.name.trim().to_string();
+    if name.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(json!({"res": null})));
+    }
+    match Team::create(auth_user.id, name, state.clone()).await {
+        Ok(team) => {
+            let details = json!({"name": team.name});
+            record_team_action(&state, &auth_user, &client, team.id, "create", details);
+            (StatusCode::CREATED, Json(json!({"res": team})))
+        }
+        Err(e) => (error_status(e), Json(json!({"res": null}))),
+    }
+}
+
+pub async fn list_teams(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    list_response(Team::list_for_user(auth_user.id, state).await)
+}
+
+pub async fn list_invitations(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    list_response(TeamInvitation::list_for_email(auth_user.email, state).await)
+}
+
+pub async fn list_members(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    Path(team_id): Path<Uuid>,
+) -> impl IntoResponse {
+    if let Err(status) = member_role(&state, team_id, auth_user.id).await {
+        return (status, Json(json!({"res": []})));
+    }
+    list_response(Team::members(team_id, state).await)
+}
+
+pub async fn invite_member(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    base_url: BaseUrl,
+    client: ClientInfo,
+    Path(team_id): Path<Uuid>,
+    Json(payload): Json<InviteBody>,
+) -> impl IntoResponse {
+    let role = payload.role.unwrap_or(TEAM_MEMBER.to_string());
+    if ![TEAM_ADMIN, TEAM_MEMBER].contains(&role.as_str()) || payload.email == auth_user.email {
+        return StatusCode::BAD_REQUEST;
+    }
+    if let Err(status) = manager_role(&state, team_id, auth_user.id).await {
+        return status;
+    }
+    let team_name = match Team::get_name(team_id, &state).await {
+        Ok(team_name) => team_name,
+        Err(e) => return error_status(e),
+    };
+    if let Err(e) = TeamInvitation::create(
+        team_id,
+        payload.email.clone(),
+        role.clone(),
+        auth_user.id,
+        state.clone(),
+    )
+    .await
+    {
+        return error_status(e);
+    }
+
+    // people without an account get a signup link along with the invitation
+    let signup_url = match User::get_user_id(payload.email.clone(), state.clone()).await {
+        Ok(_) => None,
+        Err(sqlx::Error::RowNotFound) => {
+            match create_signup_link(&state, &base_url, &payload.email, TEAM_INVITE_LINK_TTL).await
+            {
+                Ok(signup_url) => Some(signup_url),
+                Err(status) => return status,
+            }
+        }
+        Err(e) => return error_status(e),
+    };
+    let details = json!({"email": payload.email, "role": role});
+    record_team_action(&state, &auth_user, &client, team_id, "invite", details);
+    let mail = MailData::with_template(
+        payload.email,
+        format!("You have been invited to {}", team_name),
+        "mails/team_invite.html".into(),
+        json!({
+            "team_name": team_name,
+            "inviter_email": auth_user.email,
+            "signup_url": signup_url,
+        }),
+    );
+    send_mail_in_background(state, mail, client, INVITE_EMAIL_FAILED);
+    StatusCode::ACCEPTED
+}
+
+pub async fn accept_invitation(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path(team_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match TeamInvitation::accept(
+        team_id,
+        auth_user.id,
+        auth_user.email.clone(),
+        state.clone(),
+    )
+    .await
+    {
+        Ok(role) => {
+            let details = json!({"role": role});
+            record_team_action(&state, &auth_user, &client, team_id, "join", details);
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn decline_invitation(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    Path(team_id): Path<Uuid>,
+) -> impl IntoResponse {
+    match TeamInvitation::decline(team_id, auth_user.email, state).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn leave_team(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path(team_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let role = match member_role(&state, team_id, auth_user.id).await {
+        Ok(role) => role,
+        Err(status) => return status,
+    };
+    // a team always keeps at least one owner
+    if role == TEAM_OWNER {
+        match Team::owner_count(team_id, &state).await {
+            Ok(count) if count <= 1 => return StatusCode::CONFLICT,
+            Ok(_) => {}
+            Err(e) => return error_status(e),
+        }
+    }
+    match Team::remove_member(team_id, auth_user.id, state.clone()).await {
+        Ok(_) => {
+            record_team_action(&state, &auth_user, &client, team_id, "leave", json!({}));
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn set_member_role(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path((team_id, user_id)): Path<(Uuid, Uuid)>,
+    Json(payload): Json<MemberRoleBody>,
+) -> impl IntoResponse {
+    if ![TEAM_OWNER, TEAM_ADMIN, TEAM_MEMBER].contains(&payload.role.as_str()) {
+        return StatusCode::BAD_REQUEST;
+    }
+    // only owners hand out roles, and they can't demote themselves away
+    match member_role(&state, team_id, auth_user.id).await {
+        Ok(role) if role == TEAM_OWNER => {}
+        Ok(_) => return StatusCode::FORBIDDEN,
+        Err(status) => return status,
+    }
+    if user_id == auth_user.id {
+        return StatusCode::BAD_REQUEST;
+    }
+    // owners stay owners, so a team can't lose its last one
+    match Team::member_role(team_id, user_id, &state).await {
+        Ok(Some(role)) if role == TEAM_OWNER => return StatusCode::FORBIDDEN,
+        Ok(Some(_)) => {}
+        Ok(None) => return StatusCode::NOT_FOUND,
+        Err(e) => return error_status(e),
+    }
+    match Team::set_member_role(team_id, user_id, &payload.role, state.clone()).await {
+        Ok(_) => {
+            let details = json!({"user_id": user_id, "role": payload.role});
+            record_team_action(&state, &auth_user, &client, team_id, "set_role", details);
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn remove_member(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path((team_id, user_id)): Path<(Uuid, Uuid)>,
+) -> impl IntoResponse {
+    if user_id == auth_user.id {
+        return StatusCode::BAD_REQUEST;
+    }
+    let role = match manager_role(&state, team_id, auth_user.id).await {
+        Ok(role) => role,
+        Err(status) => return status,
+    };
+    // admins can remove members, owners can remove anyone else
+    match Team::member_role(team_id, user_id, &state).await {
+        Ok(Some(target_role)) if role != TEAM_OWNER && target_role != TEAM_MEMBER => {
+            return StatusCode::FORBIDDEN;
+        }
+        Ok(Some(_)) => {}
+        Ok(None) => return StatusCode::NOT_FOUND,
+        Err(e) => return error_status(e),
+    }
+    match Team::remove_member(team_id, user_id, state.clone()).await {
+        Ok(_) => {
+            let details = json!({"user_id": user_id});
+            record_team_action(&state, &auth_user, &client, team_id, "remove", details);
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn list_device_grants(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    Path(team_id): Path<Uuid>,
+) -> impl IntoResponse {
+    if let Err(status) = member_role(&state, team_id, auth_user.id).await {
+        return (status, Json(json!({"res": []})));
+    }
+    list_response(TeamDeviceGrant::list(team_id, state).await)
+}
+
+pub async fn grant_device(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path(team_id): Path<Uuid>,
+    Json(payload): Json<TeamDeviceGrantBody>,
+) -> impl IntoResponse {
+    let permissions = payload
+        .permissions
+        .unwrap_or_else(|| ALL_PERMISSIONS.iter().map(|p| p.to_string()).collect());
+    if payload.device_id.is_empty()
+        || permissions.is_empty()
+        || !permissions.iter().all(|p| is_permission(p))
+    {
+        return StatusCode::BAD_REQUEST;
+    }
+    if let Err(status) = member_role(&state, team_id, auth_user.id).await {
+        return status;
+    }
+    match TeamDeviceGrant::set(
+        team_id,
+        auth_user.id,
+        payload.device_id.clone(),
+        permissions.clone(),
+        state.clone(),
+    )
+    .await
+    {
+        Ok(_) => {
+            let details = json!({"device_id": payload.device_id, "permissions": permissions});
+            record_team_action(
+                &state,
+                &auth_user,
+                &client,
+                team_id,
+                "grant_device",
+                details,
+            );
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn revoke_device(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path(team_id): Path<Uuid>,
+    Json(payload): Json<RemoveTeamDeviceGrantBody>,
+) -> impl IntoResponse {
+    let owner_email = payload
+        .owner_email
+        .unwrap_or_else(|| auth_user.email.clone());
+    let access = if owner_email == auth_user.email {
+        member_role(&state, team_id, auth_user.id).await
+    } else {
+        manager_role(&state, team_id, auth_user.id).await
+    };
+    if let Err(status) = access {
+        return status;
+    }
+    match TeamDeviceGrant::remove(
+        team_id,
+        owner_email.clone(),
+        payload.device_id.clone(),
+        state.clone(),
+    )
+    .await
+    {
+        Ok(_) => {
+            let details = json!({"owner_email": owner_email, "device_id": payload.device_id});
+            record_team_action(
+                &state,
+                &auth_user,
+                &client,
+                team_id,
+                "revoke_device",
+                details,
+            );
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::{
+    app_state::AppState,
+    db::models::{
+        audit_event::{AuditEntry, CONNECTION_EXPIRED},
+        connection_invitation::ConnectionInvitation,
+        user_connection::UserConnection,
+    },
+    routes::socket::{events::session::end_unpermitted_sessions, redis_manager::notify_user},
+};
+
+pub const CONNECTION_EXPIRED_EVENT: &str = "connection_expired";
+pub const CONNECTION_WINDOW_CLOSED_EVENT: &str = "connection_window_closed";
+
+fn sweep_interval() -> Duration {
+    let seconds = std::env::var("CONNECTION_EXPIRY_SWEEP_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60);
+    Duration::from_secs(seconds)
+}
+
+// every pod sweeps; the DELETE / UPDATE .. RETURNING makes sure each expiry and each
+// closing window is reported once
+pub fn start_expiry_sweeper(app_state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(sweep_interval());
+        loop {
+            interval.tick().await;
+            sweep_expired(&app_state).await;
+            sweep_closed_windows(&app_state).await;
+        }
+    });
+}
+
+async fn sweep_expired(app_state: &AppState) {
+    if let Err(e) = ConnectionInvitation::delete_expired(app_state).await {
+        eprintln!("Failed to delete expired invitations: {}", e);
+    }
+
+    let expired = match UserConnection::delete_expired(app_state).await {
+        Ok(expired) => expired,
+        Err(e) => {
+            eprintln!("Failed to delete expired connections: {}", e);
+            return;
+        }
+    };
+
+    for connection in expired {
+        AuditEntry::new(CONNECTION_EXPIRED)
+            .actor_email(&connection.from_email)
+            .target(connection.to_email.clone())
+            .record(app_state);
+        end_unpermitted_sessions(&connection.from_email, &connection.to_email, app_state).await;
+        for (email, other_email) in [
+            (&connection.from_email, &connection.to_email),
+            (&connection.to_email, &connection.from_email),
+        ] {
+            let payload = json!({"email": other_email});
+            if let Err(e) = notify_user(app_state, email, CONNECTION_EXPIRED_EVENT, payload).await {
+                eprintln!("Failed to notify {} about expired connection: {}", email, e);
+            }
+        }
+    }
+}
+
+// access stops at the end of the daily window until it opens again
+async fn sweep_closed_windows(app_state: &AppState) {
+    let closed = match UserConnection::close_windows(app_state).await {
+        Ok(closed) => closed,
+        Err(e) => {
+            eprintln!("Failed to check connection windows: {}", e);
+            return;
+        }
+    };
+
+    for connection in closed {
+        end_unpermitted_sessions(&connection.from_email, &connection.to_email, app_state).await;
+        for (email, other_email) in [
+            (&connection.from_email, &connection.to_email),
+            (&connection.to_email, &connection.from_email),
+        ] {
+            let payload = json!({"email": other_email});
+            if let Err(e) =
+                notify_user(app_state, email, CONNECTION_WINDOW_CLOSED_EVENT, payload).await
+            {
+                eprintln!("Failed to notify {} about a closed window: {}", email, e);
+            }
+        }
+    }
+}
@@ -1 +1,4 @@
+pub mod expiry;
+pub mod notifications;
+#[allow(clippy::module_inception)]
 pub mod user_connection;
@@ -0,0 +1,38 @@
+use serde_json::Value;
+
+use crate::{
+    app_state::AppState,
+    routes::socket::{events::session::end_unpermitted_sessions, redis_manager::notify_user},
+};
+
+// socket events telling the other user's devices about a change made over REST;
+// the payload always carries the email of the user who made it
+pub const CONNECTION_REQUEST_EVENT: &str = "connection_request";
+pub const CONNECTION_ACCEPTED_EVENT: &str = "connection_accepted";
+pub const CONNECTION_REJECTED_EVENT: &str = "connection_rejected";
+pub const CONNECTION_CANCELLED_EVENT: &str = "connection_cancelled";
+pub const CONNECTION_REMOVED_EVENT: &str = "connection_removed";
+pub const CONNECTION_PERMISSIONS_EVENT: &str = "connection_permissions";
+
+// published in the background so a redis hiccup never fails the request itself
+pub fn notify_in_background(
+    app_state: &AppState,
+    email: String,
+    event: &'static str,
+    payload: Value,
+) {
+    let app_state = app_state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = notify_user(&app_state, &email, event, payload).await {
+            eprintln!("Failed to notify {} of {}: {:?}", email, event, e);
+        }
+    });
+}
+
+// after access between the two users shrank, their sessions it no longer covers end
+pub fn end_sessions_in_background(app_state: &AppState, email: String, other_email: String) {
+    let app_state = app_state.clone();
+    tokio::spawn(async move {
+        end_unpermitted_sessions(&email, &other_email, &app_state).await;
+    });
+}
@@ -1,22 +1,196 @@
-use std::str::FromStr;
-
 use axum::{
     Extension, Json, Router,
-    extract::State,
+    extract::{Query, State},
     http::StatusCode,
     response::IntoResponse,
     routing::{get, post},
 };
+use chrono::{DateTime, NaiveTime, Utc};
 use serde::Deserialize;
-use uuid::Uuid;
 
-use crate::{app_state::AppState, db::models::user_connection::UserConnection};
+use crate::{
+    app_state::AppState,
+    db::models::{
+        audit_event::{
+            AuditEntry, CONNECTION_ACCEPT, CONNECTION_CANCEL, CONNECTION_INVITE,
+            CONNECTION_PERMISSIONS, CONNECTION_REJECT, CONNECTION_REMOVE, CONNECTION_REQUEST,
+            DEVICE_GRANTS, DEVICE_PASSWORD, INVITE_EMAIL_FAILED, USER_BLOCK, USER_UNBLOCK,
+        },
+        device_grant::DeviceGrant,
+        device_password::{DevicePassword, MIN_DEVICE_PASSWORD_LENGTH},
+        nickname::{MAX_NICKNAME_LENGTH, Nickname},
+        pairing_code::{PairingError, PairingOutcome},
+        user_block::UserBlock,
+        user_connection::{
+            ALL_PERMISSIONS, ConnectionCursor, ConnectionListFilter, ConnectionPage,
+            ConnectionSort, MODE_SHARE, RequestOutcome, RequestTerms, UserConnection, is_mode,
+            is_permission,
+        },
+    },
+    routes::{
+        auth::signup::{create_signup_link, send_mail_in_background},
+        socket::events::pairing::{
+            PairingRequest, issue_pairing_code, pair_with_code, pairing_error_message,
+        },
+        user_connection::notifications::{
+            CONNECTION_ACCEPTED_EVENT, CONNECTION_CANCELLED_EVENT, CONNECTION_PERMISSIONS_EVENT,
+            CONNECTION_REJECTED_EVENT, CONNECTION_REMOVED_EVENT, CONNECTION_REQUEST_EVENT,
+            end_sessions_in_background, notify_in_background,
+        },
+    },
+    utils::{
+        auth_middleware::AuthUser, client_info::ClientInfo, error_status::error_status,
+        hash_service::bcrypt::hash_password, mail_service::mail_data::MailData,
+        resolve_base_url::BaseUrl,
+    },
+};
+
+// invitees get longer than the regular signup link to act on the email
+const INVITE_LINK_TTL: u64 = 7 * 24 * 60 * 60;
 
 #[derive(Deserialize, Debug)]
 pub struct SentRequestBody {
     to_email: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct NewRequestBody {
+    to_email: String,
+    // share (default), access or mutual
+    mode: Option<String>,
+    // what the recipient may do on the sender's devices, everything when omitted
+    permissions: Option<Vec<String>>,
+    expires_at: Option<DateTime<Utc>>,
+    access_window: Option<AccessWindow>,
+}
+
+// daily window in UTC; an end before the start runs past midnight
+#[derive(Deserialize, Debug)]
+pub struct AccessWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AcceptRequestBody {
+    to_email: String,
+    // what the requester may do on this user's devices for access and mutual
+    // connections, everything when omitted
+    permissions: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PermissionsBody {
+    to_email: String,
+    permissions: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeviceGrantsBody {
+    to_email: String,
+    // shares every device again; device_ids has to be empty then
+    #[serde(default)]
+    all_devices: bool,
+    // the only devices shared, an empty list shares none
+    #[serde(default)]
+    device_ids: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PairingCodeBody {
+    // the device showing the code, if it has one
+    device_id: Option<String>,
+    mode: Option<String>,
+    permissions: Option<Vec<String>>,
+    require_confirmation: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PairBody {
+    code: String,
+    // what the code's owner may do on this user's devices with an access or mutual code
+    reverse_permissions: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ContactNicknameBody {
+    email: String,
+    // empty or missing clears the nickname
+    nickname: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeviceNicknameBody {
+    email: String,
+    device_id: String,
+    // empty or missing clears the nickname
+    nickname: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DevicePasswordBody {
+    device_id: String,
+    // empty or missing removes the password
+    password: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConnectionListQuery {
+    search: Option<String>,
+    // created (default) or accepted
+    sort: Option<String>,
+    // desc (default) or asc
+    order: Option<String>,
+    // next_cursor from the previous page
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+impl ConnectionListQuery {
+    // None when the sort, order or cursor can't be understood
+    fn into_filter(self) -> Option<ConnectionListFilter> {
+        let sort = match self.sort.as_deref() {
+            None | Some("created") => ConnectionSort::Created,
+            Some("accepted") => ConnectionSort::Accepted,
+            Some(_) => return None,
+        };
+        let ascending = match self.order.as_deref() {
+            None | Some("desc") => false,
+            Some("asc") => true,
+            Some(_) => return None,
+        };
+        let after = match self.cursor.filter(|c| !c.is_empty()) {
+            Some(cursor) => Some(ConnectionCursor::decode(&cursor)?),
+            None => None,
+        };
+        Some(ConnectionListFilter {
+            search: self
+                .search
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+            sort,
+            ascending,
+            after,
+            limit: self.limit.unwrap_or(50).clamp(1, 200),
+        })
+    }
+}
+
+// Some(None) clears the nickname, None means it is too long
+fn clean_nickname(nickname: Option<String>) -> Option<Option<String>> {
+    let nickname = nickname
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty());
+    match nickname {
+        Some(n) if n.chars().count() > MAX_NICKNAME_LENGTH => None,
+        n => Some(n),
+    }
+}
+
+fn valid_permissions(permissions: &[String]) -> bool {
+    !permissions.is_empty() && permissions.iter().all(|p| is_permission(p))
+}
+
 pub fn user_connection(state: AppState) -> Router {
     Router::new()
         .route(
@@ -27,6 +201,53 @@ pub fn user_connection(state: AppState) -> Router {
             "/accept_request",
             post(accept_request).with_state(state.clone()),
         )
+        .route(
+            "/reject_request",
+            post(reject_request).with_state(state.clone()),
+        )
+        .route(
+            "/cancel_request",
+            post(cancel_request).with_state(state.clone()),
+        )
+        .route(
+            "/remove_connection",
+            post(remove_connection).with_state(state.clone()),
+        )
+        .route(
+            "/update_permissions",
+            post(update_permissions).with_state(state.clone()),
+        )
+        .route(
+            "/device_grants",
+            get(device_grants)
+                .post(set_device_grants)
+                .with_state(state.clone()),
+        )
+        .route(
+            "/pairing_code",
+            post(create_pairing_code).with_state(state.clone()),
+        )
+        .route("/pair", post(pair).with_state(state.clone()))
+        .route("/nicknames", get(nicknames).with_state(state.clone()))
+        .route(
+            "/nicknames/contact",
+            post(set_contact_nickname).with_state(state.clone()),
+        )
+        .route(
+            "/nicknames/device",
+            post(set_device_nickname).with_state(state.clone()),
+        )
+        .route(
+            "/device_passwords",
+            get(device_passwords).with_state(state.clone()),
+        )
+        .route(
+            "/device_password",
+            post(set_device_password).with_state(state.clone()),
+        )
+        .route("/block", post(block_user).with_state(state.clone()))
+        .route("/unblock", post(unblock_user).with_state(state.clone()))
+        .route("/blocked", get(blocked_users).with_state(state.clone()))
         .route(
             "/sent_requests",
             get(sent_requests).with_state(state.clone()),
@@ -40,48 +261,378 @@ pub fn user_connection(state: AppState) -> Router {
             "/connected_from",
             get(connected_from).with_state(state.clone()),
         )
+        .route("/connections", get(connections).with_state(state.clone()))
 }
 
 pub async fn send_request(
-    Extension(user_id): Extension<String>,
+    Extension(auth_user): Extension<AuthUser>,
     State(state): State<AppState>,
-    Json(payload): Json<SentRequestBody>,
+    base_url: BaseUrl,
+    client: ClientInfo,
+    Json(payload): Json<NewRequestBody>,
 ) -> impl IntoResponse {
-    let u_id = Uuid::from_str(user_id.as_str()).unwrap();
-    match UserConnection::add_request(u_id, payload.to_email, state).await {
-        Ok(_) => StatusCode::CREATED,
-        Err(e) => {
-            println!("{e}");
-            StatusCode::INTERNAL_SERVER_ERROR
+    if payload
+        .to_email
+        .trim()
+        .eq_ignore_ascii_case(&auth_user.email)
+    {
+        return StatusCode::BAD_REQUEST;
+    }
+    let terms = RequestTerms {
+        mode: payload.mode.unwrap_or(MODE_SHARE.to_string()),
+        permissions: payload
+            .permissions
+            .unwrap_or_else(|| ALL_PERMISSIONS.iter().map(|p| p.to_string()).collect()),
+        expires_at: payload.expires_at,
+        window_start: payload.access_window.as_ref().map(|w| w.start),
+        window_end: payload.access_window.as_ref().map(|w| w.end),
+    };
+    if !is_mode(&terms.mode)
+        || !valid_permissions(&terms.permissions)
+        || terms.expires_at.is_some_and(|at| at <= Utc::now())
+        || payload.access_window.is_some_and(|w| w.start == w.end)
+    {
+        return StatusCode::BAD_REQUEST;
+    }
+    match UserConnection::add_request(
+        auth_user.id,
+        payload.to_email.clone(),
+        terms.clone(),
+        state.clone(),
+    )
+    .await
+    {
+        Ok(RequestOutcome::Requested) => {
+            AuditEntry::new(CONNECTION_REQUEST)
+                .actor(auth_user.id)
+                .target(payload.to_email.clone())
+                .client(&client)
+                .details(serde_json::json!({
+                    "mode": terms.mode,
+                    "permissions": terms.permissions,
+                    "expires_at": terms.expires_at,
+                    "window_start": terms.window_start,
+                    "window_end": terms.window_end,
+                }))
+                .record(&state);
+            notify_in_background(
+                &state,
+                payload.to_email,
+                CONNECTION_REQUEST_EVENT,
+                serde_json::json!({
+                    "email": auth_user.email,
+                    "mode": terms.mode,
+                    "permissions": terms.permissions,
+                    "expires_at": terms.expires_at,
+                    "window_start": terms.window_start,
+                    "window_end": terms.window_end,
+                }),
+            );
+            StatusCode::CREATED
+        }
+        Ok(RequestOutcome::Invited) => {
+            let signup_url =
+                match create_signup_link(&state, &base_url, &payload.to_email, INVITE_LINK_TTL)
+                    .await
+                {
+                    Ok(signup_url) => signup_url,
+                    Err(status) => return status,
+                };
+            AuditEntry::new(CONNECTION_INVITE)
+                .actor(auth_user.id)
+                .target(payload.to_email.clone())
+                .client(&client)
+                .record(&state);
+            let mail = MailData::with_template(
+                payload.to_email,
+                "You have been invited".into(),
+                "mails/invite.html".into(),
+                serde_json::json!({
+                    "signup_url": signup_url,
+                    "inviter_email": auth_user.email,
+                }),
+            );
+            send_mail_in_background(state, mail, client, INVITE_EMAIL_FAILED);
+            StatusCode::ACCEPTED
         }
+        Ok(RequestOutcome::CoolingDown) => StatusCode::TOO_MANY_REQUESTS,
+        // the sender is not told that they were blocked: answering differently would let
+        // anyone probe who blocked them, so nothing is stored and it looks sent
+        Ok(RequestOutcome::Blocked) => StatusCode::CREATED,
+        Ok(RequestOutcome::ToSelf) => StatusCode::BAD_REQUEST,
+        Err(e) => error_status(e),
     }
 }
 
 pub async fn accept_request(
-    Extension(user_id): Extension<String>,
+    Extension(auth_user): Extension<AuthUser>,
     State(state): State<AppState>,
-    Json(payload): Json<SentRequestBody>,
+    client: ClientInfo,
+    Json(payload): Json<AcceptRequestBody>,
 ) -> impl IntoResponse {
-    let u_id = Uuid::from_str(user_id.as_str()).unwrap();
-    match UserConnection::add_connection(u_id, payload.to_email, state).await {
-        Ok(_) => StatusCode::CREATED,
+    if let Some(permissions) = &payload.permissions
+        && !valid_permissions(permissions)
+    {
+        return StatusCode::BAD_REQUEST;
+    }
+    match UserConnection::add_connection(
+        auth_user.id,
+        payload.to_email.clone(),
+        payload.permissions,
+        state.clone(),
+    )
+    .await
+    {
+        Ok(true) => {
+            AuditEntry::new(CONNECTION_ACCEPT)
+                .actor(auth_user.id)
+                .target(payload.to_email.clone())
+                .client(&client)
+                .record(&state);
+            notify_in_background(
+                &state,
+                payload.to_email,
+                CONNECTION_ACCEPTED_EVENT,
+                serde_json::json!({"email": auth_user.email}),
+            );
+            StatusCode::CREATED
+        }
+        Ok(false) => StatusCode::CONFLICT,
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn create_pairing_code(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<PairingCodeBody>,
+) -> impl IntoResponse {
+    let request = PairingRequest {
+        device_id: payload.device_id.unwrap_or_default(),
+        mode: payload.mode,
+        permissions: payload.permissions,
+        require_confirmation: payload.require_confirmation.unwrap_or(true),
+    };
+    match issue_pairing_code(auth_user.id, &auth_user.email, request, &client, &state).await {
+        Ok(issued) => (
+            StatusCode::CREATED,
+            Json(serde_json::json!({"res": issued})),
+        ),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(serde_json::json!({"res": e}))),
+    }
+}
+
+pub async fn pair(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<PairBody>,
+) -> impl IntoResponse {
+    match pair_with_code(
+        &payload.code,
+        auth_user.id,
+        &auth_user.email,
+        payload.reverse_permissions,
+        &client,
+        &state,
+    )
+    .await
+    {
+        Ok((pairing, outcome)) => {
+            let status = match outcome {
+                PairingOutcome::Connected => StatusCode::CREATED,
+                PairingOutcome::Pending => StatusCode::ACCEPTED,
+            };
+            (
+                status,
+                Json(serde_json::json!({"res": pairing.owner_email})),
+            )
+        }
         Err(e) => {
-            println!("{e}");
-            StatusCode::INTERNAL_SERVER_ERROR
+            let status = match &e {
+                PairingError::InvalidCode => StatusCode::NOT_FOUND,
+                PairingError::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+                PairingError::AlreadyConnected => StatusCode::CONFLICT,
+                PairingError::InvalidPermissions => StatusCode::BAD_REQUEST,
+                PairingError::Database(db_error) => {
+                    println!("{db_error}");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            };
+            (
+                status,
+                Json(serde_json::json!({"res": pairing_error_message(&e)})),
+            )
         }
     }
 }
 
-pub async fn sent_requests(
-    Extension(user_id): Extension<String>,
+pub async fn reject_request(
+    Extension(auth_user): Extension<AuthUser>,
     State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<SentRequestBody>,
 ) -> impl IntoResponse {
-    let u_id = Uuid::from_str(user_id.as_str()).unwrap();
-    if let Ok(user_connections) = UserConnection::get_sent_requests(u_id, state).await {
-        (
-            StatusCode::OK,
-            Json(serde_json::json!({"res": user_connections})),
-        )
+    match UserConnection::reject_request(auth_user.id, payload.to_email.clone(), state.clone())
+        .await
+    {
+        Ok(_) => {
+            AuditEntry::new(CONNECTION_REJECT)
+                .actor(auth_user.id)
+                .target(payload.to_email.clone())
+                .client(&client)
+                .record(&state);
+            notify_in_background(
+                &state,
+                payload.to_email,
+                CONNECTION_REJECTED_EVENT,
+                serde_json::json!({"email": auth_user.email}),
+            );
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn cancel_request(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<SentRequestBody>,
+) -> impl IntoResponse {
+    match UserConnection::cancel_request(auth_user.id, payload.to_email.clone(), state.clone())
+        .await
+    {
+        Ok(_) => {
+            AuditEntry::new(CONNECTION_CANCEL)
+                .actor(auth_user.id)
+                .target(payload.to_email.clone())
+                .client(&client)
+                .record(&state);
+            notify_in_background(
+                &state,
+                payload.to_email,
+                CONNECTION_CANCELLED_EVENT,
+                serde_json::json!({"email": auth_user.email}),
+            );
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn remove_connection(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<SentRequestBody>,
+) -> impl IntoResponse {
+    match UserConnection::remove_connection(auth_user.id, payload.to_email.clone(), state.clone())
+        .await
+    {
+        Ok(_) => {
+            AuditEntry::new(CONNECTION_REMOVE)
+                .actor(auth_user.id)
+                .target(payload.to_email.clone())
+                .client(&client)
+                .record(&state);
+            end_sessions_in_background(&state, auth_user.email.clone(), payload.to_email.clone());
+            notify_in_background(
+                &state,
+                payload.to_email,
+                CONNECTION_REMOVED_EVENT,
+                serde_json::json!({"email": auth_user.email}),
+            );
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn update_permissions(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<PermissionsBody>,
+) -> impl IntoResponse {
+    if !valid_permissions(&payload.permissions) {
+        return StatusCode::BAD_REQUEST;
+    }
+    match UserConnection::update_permissions(
+        auth_user.id,
+        payload.to_email.clone(),
+        payload.permissions.clone(),
+        state.clone(),
+    )
+    .await
+    {
+        Ok(_) => {
+            AuditEntry::new(CONNECTION_PERMISSIONS)
+                .actor(auth_user.id)
+                .target(payload.to_email.clone())
+                .client(&client)
+                .details(serde_json::json!({"permissions": payload.permissions}))
+                .record(&state);
+            end_sessions_in_background(&state, auth_user.email.clone(), payload.to_email.clone());
+            notify_in_background(
+                &state,
+                payload.to_email,
+                CONNECTION_PERMISSIONS_EVENT,
+                serde_json::json!({
+                    "email": auth_user.email,
+                    "permissions": payload.permissions,
+                }),
+            );
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn set_device_grants(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<DeviceGrantsBody>,
+) -> impl IntoResponse {
+    if payload.device_ids.iter().any(|id| id.is_empty())
+        || (payload.all_devices && !payload.device_ids.is_empty())
+    {
+        return StatusCode::BAD_REQUEST;
+    }
+    match DeviceGrant::set(
+        auth_user.id,
+        payload.to_email.clone(),
+        payload.all_devices,
+        payload.device_ids.clone(),
+        state.clone(),
+    )
+    .await
+    {
+        Ok(_) => {
+            end_sessions_in_background(&state, auth_user.email, payload.to_email.clone());
+            AuditEntry::new(DEVICE_GRANTS)
+                .actor(auth_user.id)
+                .target(payload.to_email)
+                .client(&client)
+                .details(serde_json::json!({
+                    "all_devices": payload.all_devices,
+                    "device_ids": payload.device_ids,
+                }))
+                .record(&state);
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn device_grants(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    if let Ok(grants) = DeviceGrant::list(auth_user.id, state).await {
+        (StatusCode::OK, Json(serde_json::json!({"res": grants})))
     } else {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -90,16 +641,55 @@ pub async fn sent_requests(
     }
 }
 
-pub async fn recieved_requests(
-    Extension(user_id): Extension<String>,
+pub async fn block_user(
+    Extension(auth_user): Extension<AuthUser>,
     State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<SentRequestBody>,
 ) -> impl IntoResponse {
-    let u_id = Uuid::from_str(user_id.as_str()).unwrap();
-    if let Ok(user_connections) = UserConnection::get_recieved_requests(u_id, state).await {
-        (
-            StatusCode::OK,
-            Json(serde_json::json!({"res": user_connections})),
-        )
+    if payload.to_email == auth_user.email {
+        return StatusCode::BAD_REQUEST;
+    }
+    match UserBlock::block(auth_user.id, payload.to_email.clone(), state.clone()).await {
+        Ok(_) => {
+            // the blocked user is told the same as for any other lost access
+            end_sessions_in_background(&state, auth_user.email, payload.to_email.clone());
+            AuditEntry::new(USER_BLOCK)
+                .actor(auth_user.id)
+                .target(payload.to_email)
+                .client(&client)
+                .record(&state);
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn unblock_user(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<SentRequestBody>,
+) -> impl IntoResponse {
+    match UserBlock::unblock(auth_user.id, payload.to_email.clone(), state.clone()).await {
+        Ok(_) => {
+            AuditEntry::new(USER_UNBLOCK)
+                .actor(auth_user.id)
+                .target(payload.to_email)
+                .client(&client)
+                .record(&state);
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn blocked_users(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    if let Ok(blocked) = UserBlock::list(auth_user.id, state).await {
+        (StatusCode::OK, Json(serde_json::json!({"res": blocked})))
     } else {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -108,33 +698,201 @@ pub async fn recieved_requests(
     }
 }
 
-pub async fn connected_from(
-    Extension(user_id): Extension<String>,
+pub async fn nicknames(
+    Extension(auth_user): Extension<AuthUser>,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    let u_id = Uuid::from_str(user_id.as_str()).unwrap();
-    if let Ok(user_connections) = UserConnection::connected_from(u_id, state).await {
-        (
+    let contacts = Nickname::list_contacts(auth_user.id, &state).await;
+    let devices = Nickname::list_devices(auth_user.id, &state).await;
+    match (contacts, devices) {
+        (Ok(contacts), Ok(devices)) => (
             StatusCode::OK,
-            Json(serde_json::json!({"res": user_connections})),
-        )
-    } else {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(serde_json::json!({"res": []})),
-        )
+            Json(serde_json::json!({"res": {"contacts": contacts, "devices": devices}})),
+        ),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("{e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({"res": null})),
+            )
+        }
     }
 }
 
+pub async fn set_contact_nickname(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    Json(payload): Json<ContactNicknameBody>,
+) -> impl IntoResponse {
+    let Some(nickname) = clean_nickname(payload.nickname) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    match Nickname::set_contact(auth_user.id, payload.email, nickname, state).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn set_device_nickname(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    Json(payload): Json<DeviceNicknameBody>,
+) -> impl IntoResponse {
+    if payload.device_id.is_empty() {
+        return StatusCode::BAD_REQUEST;
+    }
+    let Some(nickname) = clean_nickname(payload.nickname) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    match Nickname::set_device(
+        auth_user.id,
+        payload.email,
+        payload.device_id,
+        nickname,
+        state,
+    )
+    .await
+    {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn device_passwords(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match DevicePassword::list(auth_user.id, state).await {
+        Ok(passwords) => (StatusCode::OK, Json(serde_json::json!({"res": passwords}))),
+        Err(e) => {
+            println!("{e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({"res": []})),
+            )
+        }
+    }
+}
+
+pub async fn set_device_password(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<DevicePasswordBody>,
+) -> impl IntoResponse {
+    if payload.device_id.is_empty() {
+        return StatusCode::BAD_REQUEST;
+    }
+    let password = payload.password.filter(|password| !password.is_empty());
+    if password
+        .as_ref()
+        .is_some_and(|password| password.chars().count() < MIN_DEVICE_PASSWORD_LENGTH)
+    {
+        return StatusCode::BAD_REQUEST;
+    }
+    let is_set = password.is_some();
+    let password_hash = match password {
+        Some(password) => Some(hash_password(password).await),
+        None => None,
+    };
+    match DevicePassword::set(
+        auth_user.id,
+        payload.device_id.clone(),
+        password_hash,
+        state.clone(),
+    )
+    .await
+    {
+        Ok(_) => {
+            AuditEntry::new(DEVICE_PASSWORD)
+                .actor(auth_user.id)
+                .target(payload.device_id)
+                .client(&client)
+                .details(serde_json::json!({"set": is_set}))
+                .record(&state);
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+pub async fn sent_requests(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    Query(query): Query<ConnectionListQuery>,
+) -> impl IntoResponse {
+    let Some(filter) = query.into_filter() else {
+        return invalid_listing_query();
+    };
+    page_response(UserConnection::get_sent_requests(auth_user.id, filter, state).await)
+}
+
+pub async fn recieved_requests(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    Query(query): Query<ConnectionListQuery>,
+) -> impl IntoResponse {
+    let Some(filter) = query.into_filter() else {
+        return invalid_listing_query();
+    };
+    page_response(UserConnection::get_recieved_requests(auth_user.id, filter, state).await)
+}
+
+pub async fn connected_from(
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    Query(query): Query<ConnectionListQuery>,
+) -> impl IntoResponse {
+    let Some(filter) = query.into_filter() else {
+        return invalid_listing_query();
+    };
+    page_response(UserConnection::connected_from(auth_user.id, filter, state).await)
+}
+
 pub async fn connected_to(
-    Extension(user_id): Extension<String>,
+    Extension(auth_user): Extension<AuthUser>,
+    State(state): State<AppState>,
+    Query(query): Query<ConnectionListQuery>,
+) -> impl IntoResponse {
+    let Some(filter) = query.into_filter() else {
+        return invalid_listing_query();
+    };
+    page_response(UserConnection::connected_to(auth_user.id, filter, state).await)
+}
+
+fn invalid_listing_query() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(serde_json::json!({"res": []})),
+    )
+}
+
+fn page_response(
+    page: Result<ConnectionPage, sqlx::Error>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    match page {
+        Ok(page) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"res": page.items, "next_cursor": page.next_cursor})),
+        ),
+        Err(e) => {
+            println!("{e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({"res": []})),
+            )
+        }
+    }
+}
+
+pub async fn connections(
+    Extension(auth_user): Extension<AuthUser>,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    let u_id = Uuid::from_str(user_id.as_str()).unwrap();
-    if let Ok(user_connections) = UserConnection::connected_to(u_id, state).await {
+    if let Ok(connections) = UserConnection::connections(auth_user.id, state).await {
         (
             StatusCode::OK,
-            Json(serde_json::json!({"res": user_connections})),
+            Json(serde_json::json!({"res": connections})),
         )
     } else {
         (
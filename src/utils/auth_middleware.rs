@@ -1,30 +1,88 @@
 use axum::{
     extract::{Request, State},
-    http::{StatusCode, header},
+    http::{HeaderMap, StatusCode, header},
     middleware::Next,
     response::Response,
 };
 use uuid::Uuid;
 
 use crate::app_state::AppState;
-use crate::db::models::login_token::LoginToken;
+use crate::db::models::{
+    login_token::LoginToken,
+    user::{ROLE_ADMIN, User},
+};
+
+pub const SESSION_COOKIE: &str = "session";
+
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: Uuid,
+    pub email: String,
+    pub role: String,
+}
+
+impl AuthUser {
+    pub fn is_admin(&self) -> bool {
+        self.role == ROLE_ADMIN
+    }
+}
+
+// accepts `Bearer <token>` as well as the bare token older clients still send
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?.trim();
+    match value.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+        Some(_) => None,
+        None => Some(value),
+    }
+}
+
+fn cookie_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .find_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            (name == SESSION_COOKIE).then_some(value)
+        })
+}
 
 pub async fn auth_middleware(
     State(app_state): State<AppState>,
     mut req: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    let auth_header = req
-        .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|h| h.to_str().ok());
-    if let Some(token) = auth_header {
-        if let Ok(user_id) =
-            LoginToken::get_user_id(Uuid::parse_str(token).unwrap(), app_state).await
-        {
-            req.extensions_mut().insert(user_id);
-            return Ok(next.run(req).await);
-        }
+    let token = bearer_token(req.headers())
+        .or_else(|| cookie_token(req.headers()))
+        .and_then(|token| Uuid::parse_str(token).ok())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let user_id = LoginToken::get_user_id(token, app_state.clone())
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let id = Uuid::parse_str(&user_id).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let account = User::get_account(id, app_state)
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    if account.is_suspended() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    req.extensions_mut().insert(AuthUser {
+        id,
+        email: account.email,
+        role: account.role,
+    });
+    Ok(next.run(req).await)
+}
+
+// must be layered inside auth_middleware so that AuthUser is already present
+pub async fn admin_middleware(req: Request, next: Next) -> Result<Response, StatusCode> {
+    match req.extensions().get::<AuthUser>() {
+        Some(auth_user) if auth_user.is_admin() => Ok(next.run(req).await),
+        Some(_) => Err(StatusCode::FORBIDDEN),
+        None => Err(StatusCode::UNAUTHORIZED),
     }
-    Err(StatusCode::UNAUTHORIZED)
 }
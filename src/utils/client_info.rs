@@ -0,0 +1,74 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts},
+};
+
+use crate::utils::ip_range::{IpRange, ranges_from_env};
+
+// caller ip and user agent, used for audit records
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+// TRUSTED_PROXIES: comma separated addresses or CIDR ranges of the ingress; only
+// their x-forwarded-for is believed
+fn trusted_proxies() -> &'static [IpRange] {
+    static PROXIES: OnceLock<Vec<IpRange>> = OnceLock::new();
+    PROXIES.get_or_init(|| ranges_from_env("TRUSTED_PROXIES"))
+}
+
+pub fn is_trusted(ip: IpAddr) -> bool {
+    trusted_proxies().iter().any(|range| range.contains(ip))
+}
+
+// walks x-forwarded-for from the nearest hop back and stops at the first address not
+// added by a trusted proxy; anything before that could have been made up by the client
+fn client_ip(peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+    if !is_trusted(peer) {
+        return peer;
+    }
+    let mut client = peer;
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = hop;
+        if !is_trusted(hop) {
+            break;
+        }
+    }
+    client
+}
+
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let forwarded_for = parts
+            .headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok());
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| client_ip(addr.ip(), forwarded_for).to_string());
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        Ok(ClientInfo { ip, user_agent })
+    }
+}
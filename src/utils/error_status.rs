@@ -0,0 +1,13 @@
+use axum::http::StatusCode;
+
+// the status a REST handler answers with for a failed model call
+pub fn error_status(e: sqlx::Error) -> StatusCode {
+    match e {
+        sqlx::Error::RowNotFound => StatusCode::NOT_FOUND,
+        sqlx::Error::Database(db_error) if db_error.is_unique_violation() => StatusCode::CONFLICT,
+        e => {
+            println!("{e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
@@ -1,9 +1,15 @@
 use bcrypt::{DEFAULT_COST, hash, verify};
 
-pub fn hash_password(password: String) -> String {
-    hash(password, DEFAULT_COST).unwrap()
+// bcrypt is slow on purpose, so it runs on the blocking pool instead of an async worker
+
+pub async fn hash_password(password: String) -> String {
+    tokio::task::spawn_blocking(move || hash(password, DEFAULT_COST).unwrap())
+        .await
+        .unwrap()
 }
 
-pub fn verify_password(password: String, hash: String) -> bool {
-    verify(password, hash.as_str()).unwrap_or(false)
+pub async fn verify_password(password: String, hash: String) -> bool {
+    tokio::task::spawn_blocking(move || verify(password, hash.as_str()).unwrap_or(false))
+        .await
+        .unwrap_or(false)
 }
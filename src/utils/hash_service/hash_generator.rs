@@ -8,3 +8,13 @@ pub fn generate_hash() -> String {
     OsRng.try_fill_bytes(&mut bytes).unwrap();
     URL_SAFE_NO_PAD.encode(bytes)
 }
+
+// digits only, for codes people read out or type in by hand
+pub fn generate_numeric_code(length: usize) -> String {
+    let mut bytes = vec![0u8; length];
+    OsRng.try_fill_bytes(&mut bytes).unwrap();
+    bytes
+        .iter()
+        .map(|b| char::from(b'0' + (u32::from(*b) * 10 / 256) as u8))
+        .collect()
+}
@@ -0,0 +1,90 @@
+use axum::http::HeaderMap;
+use serde::Serialize;
+
+use crate::utils::ice_service::{
+    stun_server::stun_port,
+    turn_credential::{TurnCredential, credential_ttl},
+    turn_server::turn_port,
+};
+
+// shaped like RTCIceServer so clients can hand the list to RTCPeerConnection as is
+#[derive(Debug, Serialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IceConfig {
+    pub ice_servers: Vec<IceServer>,
+    // seconds until the TURN credentials have to be fetched again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+fn urls_from_env(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+// the host clients reach this server at: ICE_PUBLIC_HOST, else the host of the request
+pub fn ice_host(headers: &HeaderMap) -> Option<String> {
+    if let Ok(host) = std::env::var("ICE_PUBLIC_HOST")
+        && !host.is_empty()
+    {
+        return Some(host);
+    }
+    let host = headers
+        .get("x-forwarded-host")
+        .or_else(|| headers.get("host"))
+        .and_then(|v| v.to_str().ok())?;
+    // drop the http port, keeping IPv6 literals in their brackets
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    Some(host.to_string()).filter(|h| !h.is_empty())
+}
+
+// STUN_URLS and TURN_URLS are comma separated, e.g. `turn:turn.example.com:3478?transport=udp`;
+// TURN urls are left out while no TURN secret is configured.
+// The embedded STUN and TURN servers are added in front when they run and the host is known
+pub fn ice_config(user: &str, host: Option<&str>) -> IceConfig {
+    let mut ice_servers = Vec::new();
+    let mut stun_urls = urls_from_env("STUN_URLS");
+    if let (Some(port), Some(host)) = (stun_port(), host) {
+        stun_urls.insert(0, format!("stun:{}:{}", host, port));
+    }
+    if !stun_urls.is_empty() {
+        ice_servers.push(IceServer {
+            urls: stun_urls,
+            username: None,
+            credential: None,
+        });
+    }
+
+    let mut turn_urls = urls_from_env("TURN_URLS");
+    if let (Some(port), Some(host)) = (turn_port(), host) {
+        turn_urls.insert(0, format!("turn:{}:{}?transport=tcp", host, port));
+        turn_urls.insert(0, format!("turn:{}:{}?transport=udp", host, port));
+    }
+    let mut ttl = None;
+    if !turn_urls.is_empty()
+        && let Some(turn) = TurnCredential::issue(user)
+    {
+        ice_servers.push(IceServer {
+            urls: turn_urls,
+            username: Some(turn.username),
+            credential: Some(turn.credential),
+        });
+        ttl = Some(credential_ttl());
+    }
+    IceConfig { ice_servers, ttl }
+}
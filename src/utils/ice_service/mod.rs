@@ -0,0 +1,6 @@
+pub mod ice_config;
+pub mod stun_message;
+pub mod stun_server;
+pub mod turn_allocation;
+pub mod turn_credential;
+pub mod turn_server;
@@ -0,0 +1,425 @@
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// RFC 5389 framing: 20 byte header, then type-length-value attributes padded to 4 bytes
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+pub const HEADER_LENGTH: usize = 20;
+
+pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_SUCCESS: u16 = 0x0101;
+
+// RFC 5766 methods; success and error responses set the class bits on top
+pub const ALLOCATE_REQUEST: u16 = 0x0003;
+pub const REFRESH_REQUEST: u16 = 0x0004;
+pub const SEND_INDICATION: u16 = 0x0016;
+pub const DATA_INDICATION: u16 = 0x0017;
+pub const CREATE_PERMISSION_REQUEST: u16 = 0x0008;
+pub const CHANNEL_BIND_REQUEST: u16 = 0x0009;
+const SUCCESS_CLASS: u16 = 0x0100;
+const ERROR_CLASS: u16 = 0x0110;
+
+pub const ATTR_USERNAME: u16 = 0x0006;
+pub const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+pub const ATTR_ERROR_CODE: u16 = 0x0009;
+pub const ATTR_CHANNEL_NUMBER: u16 = 0x000C;
+pub const ATTR_LIFETIME: u16 = 0x000D;
+pub const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+pub const ATTR_DATA: u16 = 0x0013;
+pub const ATTR_REALM: u16 = 0x0014;
+pub const ATTR_NONCE: u16 = 0x0015;
+pub const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+pub const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+pub const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const ATTR_SOFTWARE: u16 = 0x8022;
+
+const INTEGRITY_LENGTH: usize = 20;
+
+pub const SOFTWARE: &str = "project1_rust";
+
+#[derive(Debug, Clone)]
+pub struct StunMessage {
+    pub message_type: u16,
+    pub transaction_id: [u8; 12],
+    pub attributes: Vec<(u16, Vec<u8>)>,
+}
+
+pub fn padded(length: usize) -> usize {
+    length.div_ceil(4) * 4
+}
+
+impl StunMessage {
+    pub fn new(message_type: u16, transaction_id: [u8; 12]) -> Self {
+        StunMessage {
+            message_type,
+            transaction_id,
+            attributes: Vec::new(),
+        }
+    }
+
+    // None for anything that isn't a well-formed STUN message
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_LENGTH || buf[0] & 0xC0 != 0 {
+            return None;
+        }
+        let message_type = u16::from_be_bytes([buf[0], buf[1]]);
+        let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        let cookie = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if cookie != MAGIC_COOKIE
+            || !length.is_multiple_of(4)
+            || buf.len() != HEADER_LENGTH + length
+        {
+            return None;
+        }
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&buf[8..HEADER_LENGTH]);
+
+        let mut attributes = Vec::new();
+        let mut offset = HEADER_LENGTH;
+        while offset < buf.len() {
+            if buf.len() - offset < 4 {
+                return None;
+            }
+            let attr_type = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+            let attr_length = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+            let start = offset + 4;
+            if start + attr_length > buf.len() {
+                return None;
+            }
+            attributes.push((attr_type, buf[start..start + attr_length].to_vec()));
+            offset = start + padded(attr_length);
+        }
+        Some(StunMessage {
+            message_type,
+            transaction_id,
+            attributes,
+        })
+    }
+
+    pub fn attribute(&self, attr_type: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(t, _)| *t == attr_type)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn attribute_str(&self, attr_type: u16) -> Option<&str> {
+        self.attribute(attr_type)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    pub fn success(&self) -> Self {
+        StunMessage::new(self.message_type | SUCCESS_CLASS, self.transaction_id)
+    }
+
+    // ERROR-CODE carries the hundreds and the rest of the code separately
+    pub fn error(&self, code: u16, reason: &str) -> Self {
+        let mut response = StunMessage::new(self.message_type | ERROR_CLASS, self.transaction_id);
+        let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+        value.extend_from_slice(reason.as_bytes());
+        response.add_attribute(ATTR_ERROR_CODE, value);
+        response
+    }
+
+    pub fn add_attribute(&mut self, attr_type: u16, value: Vec<u8>) {
+        self.attributes.push((attr_type, value));
+    }
+
+    // the address xor'ed with the cookie (and the transaction id for IPv6)
+    pub fn add_xor_address(&mut self, attr_type: u16, addr: SocketAddr) {
+        let cookie = MAGIC_COOKIE.to_be_bytes();
+        let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+        let mut value = vec![0];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                value.push(0x01);
+                value.extend_from_slice(&port.to_be_bytes());
+                value.extend(ip.octets().iter().zip(cookie).map(|(b, k)| b ^ k));
+            }
+            IpAddr::V6(ip) => {
+                value.push(0x02);
+                value.extend_from_slice(&port.to_be_bytes());
+                let key = cookie.iter().chain(self.transaction_id.iter());
+                value.extend(ip.octets().iter().zip(key).map(|(b, k)| b ^ k));
+            }
+        }
+        self.add_attribute(attr_type, value);
+    }
+
+    pub fn xor_address(&self, attr_type: u16) -> Option<SocketAddr> {
+        let value = self.attribute(attr_type)?;
+        xor_decode(value, &self.transaction_id)
+    }
+
+    // every XOR-PEER-ADDRESS of a CreatePermission request
+    pub fn xor_addresses(&self, attr_type: u16) -> Vec<SocketAddr> {
+        self.attributes
+            .iter()
+            .filter(|(t, _)| *t == attr_type)
+            .filter_map(|(_, value)| xor_decode(value, &self.transaction_id))
+            .collect()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let body_length: usize = self
+            .attributes
+            .iter()
+            .map(|(_, value)| 4 + padded(value.len()))
+            .sum();
+        let mut buf = Vec::with_capacity(HEADER_LENGTH + body_length);
+        buf.extend_from_slice(&self.message_type.to_be_bytes());
+        buf.extend_from_slice(&(body_length as u16).to_be_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id);
+        for (attr_type, value) in &self.attributes {
+            buf.extend_from_slice(&attr_type.to_be_bytes());
+            buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            buf.extend_from_slice(value);
+            buf.resize(buf.len() + padded(value.len()) - value.len(), 0);
+        }
+        buf
+    }
+
+    // MESSAGE-INTEGRITY goes last and covers everything before it, with the header
+    // length already counting the attribute itself
+    pub fn encode_with_integrity(&self, key: &[u8]) -> Vec<u8> {
+        let mut buf = self.encode();
+        let length = (buf.len() - HEADER_LENGTH + 4 + INTEGRITY_LENGTH) as u16;
+        buf[2..4].copy_from_slice(&length.to_be_bytes());
+        let digest = integrity(key, &buf);
+        buf.extend_from_slice(&ATTR_MESSAGE_INTEGRITY.to_be_bytes());
+        buf.extend_from_slice(&(INTEGRITY_LENGTH as u16).to_be_bytes());
+        buf.extend_from_slice(&digest);
+        buf
+    }
+}
+
+fn integrity(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// checks MESSAGE-INTEGRITY of a raw message; attributes after it (FINGERPRINT) are ignored
+pub fn check_integrity(raw: &[u8], key: &[u8]) -> bool {
+    let mut offset = HEADER_LENGTH;
+    while offset + 4 <= raw.len() {
+        let attr_type = u16::from_be_bytes([raw[offset], raw[offset + 1]]);
+        let attr_length = u16::from_be_bytes([raw[offset + 2], raw[offset + 3]]) as usize;
+        if attr_type == ATTR_MESSAGE_INTEGRITY {
+            if attr_length != INTEGRITY_LENGTH || offset + 4 + attr_length > raw.len() {
+                return false;
+            }
+            let mut covered = raw[..offset].to_vec();
+            let length = (offset - HEADER_LENGTH + 4 + INTEGRITY_LENGTH) as u16;
+            covered[2..4].copy_from_slice(&length.to_be_bytes());
+            let mut mac =
+                Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+            mac.update(&covered);
+            return mac
+                .verify_slice(&raw[offset + 4..offset + 4 + INTEGRITY_LENGTH])
+                .is_ok();
+        }
+        offset += 4 + padded(attr_length);
+    }
+    false
+}
+
+fn xor_decode(value: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match (value[1], value.len()) {
+        (0x01, 8) => {
+            let mut octets = [0u8; 4];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = value[4 + i] ^ cookie[i];
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        (0x02, 20) => {
+            let key: Vec<u8> = cookie.iter().chain(transaction_id).copied().collect();
+            let mut octets = [0u8; 16];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = value[4 + i] ^ key[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+// answers a binding request with the address it came from; anything else gets no reply
+pub fn binding_response(request: &[u8], source: SocketAddr) -> Option<Vec<u8>> {
+    let request = StunMessage::parse(request)?;
+    if request.message_type != BINDING_REQUEST {
+        return None;
+    }
+    let mut response = StunMessage::new(BINDING_SUCCESS, request.transaction_id);
+    response.add_xor_address(ATTR_XOR_MAPPED_ADDRESS, source);
+    response.add_attribute(ATTR_SOFTWARE, SOFTWARE.as_bytes().to_vec());
+    Some(response.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 5769 section 2: short-term credential vectors
+    const PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+    const TRANSACTION_ID: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    // 2.1 sample request
+    const REQUEST: [u8; 108] = [
+        0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e, 0x20, 0x74,
+        0x65, 0x73, 0x74, 0x20, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x00, 0x24, 0x00, 0x04, 0x6e,
+        0x00, 0x01, 0xff, 0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36,
+        0x00, 0x06, 0x00, 0x09, 0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76, 0x59, 0x20, 0x20,
+        0x20, 0x00, 0x08, 0x00, 0x14, 0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e,
+        0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49, 0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5,
+        0x7a, 0x3b, 0xcf,
+    ];
+
+    // 2.2 sample IPv4 response
+    const IPV4_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    // 2.3 sample IPv6 response
+    const IPV6_RESPONSE: [u8; 92] = [
+        0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01,
+        0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        0x00, 0x08, 0x00, 0x14, 0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84, 0xc9,
+        0x7c, 0x82, 0x92, 0xc2, 0x75, 0xbf, 0xe3, 0xed, 0x41, 0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb,
+        0x0b, 0x4c,
+    ];
+
+    fn ipv4_mapped() -> SocketAddr {
+        "192.0.2.1:32853".parse().unwrap()
+    }
+
+    fn ipv6_mapped() -> SocketAddr {
+        "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_sample_request() {
+        let request = StunMessage::parse(&REQUEST).unwrap();
+        assert_eq!(request.message_type, BINDING_REQUEST);
+        assert_eq!(request.transaction_id, TRANSACTION_ID);
+        assert_eq!(
+            request.attribute_str(ATTR_SOFTWARE),
+            Some("STUN test client")
+        );
+        assert_eq!(request.attribute_str(ATTR_USERNAME), Some("evtj:h6vY"));
+        assert_eq!(request.attributes.len(), 6);
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(StunMessage::parse(&REQUEST[..HEADER_LENGTH - 1]).is_none());
+        assert!(StunMessage::parse(&REQUEST[..REQUEST.len() - 4]).is_none());
+        let mut bad_cookie = REQUEST;
+        bad_cookie[4] = 0;
+        assert!(StunMessage::parse(&bad_cookie).is_none());
+        let mut channel_data = REQUEST;
+        channel_data[0] = 0x40;
+        assert!(StunMessage::parse(&channel_data).is_none());
+    }
+
+    #[test]
+    fn checks_sample_integrity() {
+        for raw in [&REQUEST[..], &IPV4_RESPONSE, &IPV6_RESPONSE] {
+            assert!(check_integrity(raw, PASSWORD));
+            assert!(!check_integrity(raw, b"wrong password"));
+        }
+        let mut tampered = REQUEST;
+        tampered[30] ^= 1;
+        assert!(!check_integrity(&tampered, PASSWORD));
+        assert!(!check_integrity(&REQUEST[..HEADER_LENGTH], PASSWORD));
+    }
+
+    #[test]
+    fn decodes_sample_xor_addresses() {
+        let ipv4 = StunMessage::parse(&IPV4_RESPONSE).unwrap();
+        assert_eq!(ipv4.message_type, BINDING_SUCCESS);
+        assert_eq!(
+            ipv4.xor_address(ATTR_XOR_MAPPED_ADDRESS),
+            Some(ipv4_mapped())
+        );
+        let ipv6 = StunMessage::parse(&IPV6_RESPONSE).unwrap();
+        assert_eq!(
+            ipv6.xor_address(ATTR_XOR_MAPPED_ADDRESS),
+            Some(ipv6_mapped())
+        );
+    }
+
+    #[test]
+    fn encodes_sample_xor_addresses() {
+        let ipv4 = StunMessage::parse(&IPV4_RESPONSE).unwrap();
+        let ipv6 = StunMessage::parse(&IPV6_RESPONSE).unwrap();
+        for (sample, addr) in [(ipv4, ipv4_mapped()), (ipv6, ipv6_mapped())] {
+            let mut response = StunMessage::new(BINDING_SUCCESS, TRANSACTION_ID);
+            response.add_xor_address(ATTR_XOR_MAPPED_ADDRESS, addr);
+            assert_eq!(
+                response.attribute(ATTR_XOR_MAPPED_ADDRESS),
+                sample.attribute(ATTR_XOR_MAPPED_ADDRESS)
+            );
+        }
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let mut response = StunMessage::new(BINDING_SUCCESS, TRANSACTION_ID);
+        response.add_attribute(ATTR_SOFTWARE, b"test vector".to_vec());
+        response.add_xor_address(ATTR_XOR_MAPPED_ADDRESS, ipv6_mapped());
+        let raw = response.encode();
+        assert_eq!(raw.len(), HEADER_LENGTH + 16 + 24);
+        assert_eq!(&raw[..2], &IPV6_RESPONSE[..2]);
+        assert_eq!(&raw[4..HEADER_LENGTH], &IPV6_RESPONSE[4..HEADER_LENGTH]);
+
+        let parsed = StunMessage::parse(&raw).unwrap();
+        assert_eq!(parsed.attributes, response.attributes);
+        assert_eq!(
+            parsed.xor_address(ATTR_XOR_MAPPED_ADDRESS),
+            Some(ipv6_mapped())
+        );
+    }
+
+    #[test]
+    fn integrity_round_trips() {
+        let mut response = StunMessage::new(BINDING_SUCCESS, TRANSACTION_ID);
+        response.add_attribute(ATTR_SOFTWARE, b"test vector".to_vec());
+        response.add_xor_address(ATTR_XOR_MAPPED_ADDRESS, ipv4_mapped());
+        let raw = response.encode_with_integrity(PASSWORD);
+        assert!(StunMessage::parse(&raw).is_some());
+        assert!(check_integrity(&raw, PASSWORD));
+        assert!(!check_integrity(&raw, b"wrong password"));
+    }
+
+    #[test]
+    fn answers_binding_requests_only() {
+        let source = ipv6_mapped();
+        let raw = binding_response(&REQUEST, source).unwrap();
+        let response = StunMessage::parse(&raw).unwrap();
+        assert_eq!(response.message_type, BINDING_SUCCESS);
+        assert_eq!(response.transaction_id, TRANSACTION_ID);
+        assert_eq!(response.xor_address(ATTR_XOR_MAPPED_ADDRESS), Some(source));
+        assert!(binding_response(&IPV4_RESPONSE, source).is_none());
+    }
+}
@@ -0,0 +1,39 @@
+use tokio::net::UdpSocket;
+
+use crate::utils::ice_service::stun_message::binding_response;
+
+// the embedded STUN responder only runs when STUN_PORT is set
+pub fn stun_port() -> Option<u16> {
+    std::env::var("STUN_PORT").ok().and_then(|v| v.parse().ok())
+}
+
+pub async fn start_stun_server() {
+    let Some(port) = stun_port() else {
+        return;
+    };
+    let socket = match UdpSocket::bind(("0.0.0.0", port)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Failed to bind STUN server on udp port {}: {}", port, e);
+            return;
+        }
+    };
+    eprintln!("STUN server listening on udp port {}", port);
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (length, source) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    // e.g. ICMP port unreachable surfacing on some platforms
+                    eprintln!("STUN receive error: {}", e);
+                    continue;
+                }
+            };
+            if let Some(response) = binding_response(&buf[..length], source) {
+                let _ = socket.send_to(&response, source).await;
+            }
+        }
+    });
+}
@@ -0,0 +1,419 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{Mutex, mpsc},
+    task::JoinHandle,
+};
+
+use crate::utils::{
+    ice_service::stun_message::{
+        ATTR_DATA, ATTR_XOR_PEER_ADDRESS, DATA_INDICATION, StunMessage, padded,
+    },
+    ip_range::IpRange,
+};
+
+pub const DEFAULT_LIFETIME: u64 = 10 * 60;
+pub const MAX_LIFETIME: u64 = 60 * 60;
+pub const PERMISSION_LIFETIME: Duration = Duration::from_secs(5 * 60);
+pub const CHANNEL_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+// peers the relay never sends to unless allowed explicitly: this host, private and
+// link-local networks (cloud metadata included), unspecified, multicast and reserved
+pub const DEFAULT_DENIED_PEERS: [&str; 14] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+// where responses and relayed data for a client go
+#[derive(Clone)]
+pub enum ClientLink {
+    Udp(Arc<UdpSocket>, SocketAddr),
+    // frames for the connection's writer task
+    Tcp(mpsc::Sender<Vec<u8>>),
+}
+
+impl ClientLink {
+    pub fn is_tcp(&self) -> bool {
+        matches!(self, ClientLink::Tcp(_))
+    }
+
+    pub async fn send(&self, data: Vec<u8>) {
+        match self {
+            ClientLink::Udp(socket, addr) => {
+                let _ = socket.send_to(&data, addr).await;
+            }
+            ClientLink::Tcp(tx) => {
+                let _ = tx.send(data).await;
+            }
+        }
+    }
+}
+
+// a client is its address plus the transport it came over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientKey {
+    pub addr: SocketAddr,
+    pub tcp: bool,
+}
+
+pub struct Allocation {
+    pub user: String,
+    pub link: ClientLink,
+    pub relay: Arc<UdpSocket>,
+    pub expires_at: Instant,
+    pub permissions: HashMap<IpAddr, Instant>,
+    pub channels: HashMap<u16, (SocketAddr, Instant)>,
+    relay_task: JoinHandle<()>,
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.relay_task.abort();
+    }
+}
+
+impl Allocation {
+    pub fn has_permission(&self, ip: IpAddr, now: Instant) -> bool {
+        self.permissions.get(&ip).is_some_and(|until| *until > now)
+    }
+
+    pub fn channel_for(&self, peer: SocketAddr, now: Instant) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, (addr, until))| *addr == peer && *until > now)
+            .map(|(channel, _)| *channel)
+    }
+
+    pub fn channel_peer(&self, channel: u16, now: Instant) -> Option<SocketAddr> {
+        self.channels
+            .get(&channel)
+            .filter(|(_, until)| *until > now)
+            .map(|(addr, _)| *addr)
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.permissions.retain(|_, until| *until > now);
+        self.channels.retain(|_, (_, until)| *until > now);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RelayUsage {
+    pub bytes_to_peer: i64,
+    pub bytes_from_peer: i64,
+    pub allocations: i64,
+}
+
+pub struct TurnConfig {
+    pub realm: String,
+    // the address peers send to, put in XOR-RELAYED-ADDRESS
+    pub relay_ip: IpAddr,
+    pub max_allocations_per_user: usize,
+    // shared by all allocations of a user, both directions; 0 is unlimited
+    pub bandwidth_bytes_per_sec: u64,
+    pub denied_peers: Vec<IpRange>,
+    // exceptions to denied_peers
+    pub allowed_peers: Vec<IpRange>,
+}
+
+impl TurnConfig {
+    pub fn peer_allowed(&self, ip: IpAddr) -> bool {
+        self.allowed_peers.iter().any(|range| range.contains(ip))
+            || !self.denied_peers.iter().any(|range| range.contains(ip))
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct TurnState {
+    pub config: TurnConfig,
+    pub allocations: Mutex<HashMap<ClientKey, Allocation>>,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+    usage: Mutex<HashMap<String, RelayUsage>>,
+    // signs nonces so they need no storage
+    pub nonce_key: String,
+}
+
+impl TurnState {
+    pub fn new(config: TurnConfig, nonce_key: String) -> Self {
+        TurnState {
+            config,
+            allocations: Mutex::new(HashMap::new()),
+            buckets: Mutex::new(HashMap::new()),
+            usage: Mutex::new(HashMap::new()),
+            nonce_key,
+        }
+    }
+
+    pub async fn allocation_count(&self, user: &str) -> usize {
+        self.allocations
+            .lock()
+            .await
+            .values()
+            .filter(|allocation| allocation.user == user)
+            .count()
+    }
+
+    // one second worth of burst; packets over the rate are dropped like on a full link
+    pub async fn allow_bytes(&self, user: &str, bytes: usize) -> bool {
+        let rate = self.config.bandwidth_bytes_per_sec as f64;
+        if rate == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().await;
+        let bucket = buckets.entry(user.to_string()).or_insert(TokenBucket {
+            tokens: rate,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+        if bucket.tokens < bytes as f64 {
+            return false;
+        }
+        bucket.tokens -= bytes as f64;
+        true
+    }
+
+    pub async fn record(&self, user: &str, usage: RelayUsage) {
+        let mut totals = self.usage.lock().await;
+        let total = totals.entry(user.to_string()).or_default();
+        total.bytes_to_peer += usage.bytes_to_peer;
+        total.bytes_from_peer += usage.bytes_from_peer;
+        total.allocations += usage.allocations;
+    }
+
+    pub async fn take_usage(&self) -> HashMap<String, RelayUsage> {
+        std::mem::take(&mut *self.usage.lock().await)
+    }
+
+    // drops expired allocations, permissions and channels, and idle buckets
+    pub async fn sweep(&self) {
+        let now = Instant::now();
+        let mut allocations = self.allocations.lock().await;
+        allocations.retain(|_, allocation| allocation.expires_at > now);
+        for allocation in allocations.values_mut() {
+            allocation.prune(now);
+        }
+        let active: Vec<String> = allocations.values().map(|a| a.user.clone()).collect();
+        drop(allocations);
+        self.buckets
+            .lock()
+            .await
+            .retain(|user, _| active.contains(user));
+    }
+
+    // binds the relay socket and starts forwarding what peers send to it
+    pub async fn allocate(
+        self: &Arc<Self>,
+        client: ClientKey,
+        user: String,
+        link: ClientLink,
+        lifetime: u64,
+    ) -> std::io::Result<SocketAddr> {
+        let bind_ip: IpAddr = if self.config.relay_ip.is_ipv6() {
+            "::".parse().expect("valid address")
+        } else {
+            "0.0.0.0".parse().expect("valid address")
+        };
+        let relay = Arc::new(UdpSocket::bind((bind_ip, 0)).await?);
+        let relay_addr = SocketAddr::new(self.config.relay_ip, relay.local_addr()?.port());
+        let relay_task = tokio::spawn(relay_from_peers(self.clone(), client, relay.clone()));
+        let allocation = Allocation {
+            user: user.clone(),
+            link,
+            relay,
+            expires_at: Instant::now() + Duration::from_secs(lifetime),
+            permissions: HashMap::new(),
+            channels: HashMap::new(),
+            relay_task,
+        };
+        self.allocations.lock().await.insert(client, allocation);
+        self.record(
+            &user,
+            RelayUsage {
+                allocations: 1,
+                ..Default::default()
+            },
+        )
+        .await;
+        Ok(relay_addr)
+    }
+}
+
+// ChannelData: channel number, length, data; padded to 4 bytes over TCP
+pub fn channel_data(channel: u16, data: &[u8], tcp: bool) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + padded(data.len()));
+    buf.extend_from_slice(&channel.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+    if tcp {
+        buf.resize(4 + padded(data.len()), 0);
+    }
+    buf
+}
+
+fn data_indication(peer: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut transaction_id = [0u8; 12];
+    rand::fill(&mut transaction_id);
+    let mut indication = StunMessage::new(DATA_INDICATION, transaction_id);
+    indication.add_xor_address(ATTR_XOR_PEER_ADDRESS, peer);
+    indication.add_attribute(ATTR_DATA, data.to_vec());
+    indication.encode()
+}
+
+// peer -> client; only peers the client gave a permission to get through
+async fn relay_from_peers(state: Arc<TurnState>, client: ClientKey, relay: Arc<UdpSocket>) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (length, peer) = match relay.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let now = Instant::now();
+        let (link, user, channel) = {
+            let allocations = state.allocations.lock().await;
+            let Some(allocation) = allocations.get(&client) else {
+                return;
+            };
+            if !allocation.has_permission(peer.ip(), now) {
+                continue;
+            }
+            (
+                allocation.link.clone(),
+                allocation.user.clone(),
+                allocation.channel_for(peer, now),
+            )
+        };
+        if !state.allow_bytes(&user, length).await {
+            continue;
+        }
+        let data = &buf[..length];
+        let frame = match channel {
+            Some(channel) => channel_data(channel, data, link.is_tcp()),
+            None => data_indication(peer, data),
+        };
+        link.send(frame).await;
+        state
+            .record(
+                &user,
+                RelayUsage {
+                    bytes_from_peer: length as i64,
+                    ..Default::default()
+                },
+            )
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(bandwidth_bytes_per_sec: u64, allowed_peers: &[&str]) -> TurnState {
+        let ranges = |values: &[&str]| values.iter().filter_map(|v| IpRange::parse(v)).collect();
+        TurnState::new(
+            TurnConfig {
+                realm: "example.com".to_string(),
+                relay_ip: "203.0.113.1".parse().unwrap(),
+                max_allocations_per_user: 2,
+                bandwidth_bytes_per_sec,
+                denied_peers: ranges(&DEFAULT_DENIED_PEERS),
+                allowed_peers: ranges(allowed_peers),
+            },
+            "nonce key".to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn unlimited_without_a_rate() {
+        let state = state(0, &[]);
+        assert!(state.allow_bytes("alice", usize::MAX).await);
+        assert!(state.allow_bytes("alice", usize::MAX).await);
+    }
+
+    #[tokio::test]
+    async fn allows_a_second_of_burst_per_user() {
+        let state = state(1000, &[]);
+        assert!(state.allow_bytes("alice", 600).await);
+        assert!(state.allow_bytes("alice", 400).await);
+        assert!(!state.allow_bytes("alice", 100).await);
+        // a dropped packet takes nothing from the bucket
+        assert!(!state.allow_bytes("alice", 100).await);
+        assert!(state.allow_bytes("bob", 1000).await);
+        assert!(!state.allow_bytes("bob", 1001).await);
+    }
+
+    #[tokio::test]
+    async fn refills_over_time() {
+        let state = state(1000, &[]);
+        assert!(state.allow_bytes("alice", 1000).await);
+        assert!(!state.allow_bytes("alice", 50).await);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(state.allow_bytes("alice", 50).await);
+        // never more than a second of burst
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert!(!state.allow_bytes("alice", 1001).await);
+        assert!(state.allow_bytes("alice", 1000).await);
+    }
+
+    #[test]
+    fn denies_internal_peers_by_default() {
+        let state = state(0, &["10.1.0.0/16"]);
+        for denied in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "169.254.169.254",
+            "192.168.1.1",
+            "224.0.0.1",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(
+                !state.config.peer_allowed(denied.parse().unwrap()),
+                "{denied}"
+            );
+        }
+        for allowed in ["8.8.8.8", "10.1.2.3", "2001:db8::1"] {
+            assert!(
+                state.config.peer_allowed(allowed.parse().unwrap()),
+                "{allowed}"
+            );
+        }
+    }
+
+    #[test]
+    fn pads_channel_data_over_tcp_only() {
+        assert_eq!(
+            channel_data(0x4000, b"abcde", false),
+            b"\x40\x00\x00\x05abcde"
+        );
+        assert_eq!(
+            channel_data(0x4000, b"abcde", true),
+            b"\x40\x00\x00\x05abcde\x00\x00\x00"
+        );
+    }
+}
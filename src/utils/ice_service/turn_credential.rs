@@ -0,0 +1,120 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha1::Sha1;
+
+type HmacSha1 = Hmac<Sha1>;
+
+// time-limited TURN credentials (the TURN REST API scheme coturn calls use-auth-secret):
+// username is `expiry:user`, the password is base64(HMAC-SHA1(secret, username))
+#[derive(Debug, Serialize)]
+pub struct TurnCredential {
+    pub username: String,
+    pub credential: String,
+    // unix seconds
+    pub expires_at: i64,
+}
+
+// comma separated; the first secret signs, the rest only stay valid on the TURN side.
+// To rotate, put the new secret first and drop the old one once its credentials expired
+pub fn turn_secrets() -> Vec<String> {
+    std::env::var("TURN_SECRETS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+pub fn credential_ttl() -> u64 {
+    std::env::var("TURN_CREDENTIAL_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(24 * 60 * 60)
+}
+
+fn sign(secret: &str, username: &str) -> String {
+    let mut mac =
+        HmacSha1::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(username.as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
+impl TurnCredential {
+    // None when the deployment has no TURN secret
+    pub fn issue(user: &str) -> Option<Self> {
+        let secret = turn_secrets().into_iter().next()?;
+        let expires_at = chrono::Utc::now().timestamp() + credential_ttl() as i64;
+        let username = format!("{}:{}", expires_at, user);
+        Some(TurnCredential {
+            credential: sign(&secret, &username),
+            username,
+            expires_at,
+        })
+    }
+}
+
+// the user a credential was issued to, `user` in `expiry:user`
+pub fn credential_user(username: &str) -> Option<&str> {
+    username
+        .split_once(':')
+        .map(|(_, user)| user)
+        .filter(|user| !user.is_empty())
+}
+
+// the passwords an unexpired username may carry, one per configured secret
+pub fn valid_passwords(username: &str) -> Vec<String> {
+    let expires_at = username
+        .split_once(':')
+        .and_then(|(expiry, _)| expiry.parse::<i64>().ok());
+    match expires_at {
+        Some(expires_at) if expires_at > chrono::Utc::now().timestamp() => turn_secrets()
+            .iter()
+            .map(|secret| sign(secret, username))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_like_the_turn_rest_api() {
+        // base64(HMAC-SHA1("secret", username)), what coturn's use-auth-secret checks
+        assert_eq!(
+            sign("secret", "1700000000:alice@example.com"),
+            "L5olFG2UP+M/FNvy2TuO/2B5+cM="
+        );
+    }
+
+    #[test]
+    fn reads_the_user_of_a_credential() {
+        assert_eq!(
+            credential_user("1700000000:alice@example.com"),
+            Some("alice@example.com")
+        );
+        assert_eq!(credential_user("1700000000:a:b"), Some("a:b"));
+        assert_eq!(credential_user("1700000000:"), None);
+        assert_eq!(credential_user("alice@example.com"), None);
+    }
+
+    #[test]
+    fn accepts_every_secret_until_expiry() {
+        // the only test reading TURN_SECRETS, so nothing races the change
+        unsafe { std::env::set_var("TURN_SECRETS", "new, old") };
+        let future = chrono::Utc::now().timestamp() + 60;
+        let username = format!("{}:alice@example.com", future);
+        assert_eq!(
+            valid_passwords(&username),
+            vec![sign("new", &username), sign("old", &username)]
+        );
+
+        let past = chrono::Utc::now().timestamp() - 1;
+        assert!(valid_passwords(&format!("{}:alice@example.com", past)).is_empty());
+        assert!(valid_passwords("never:alice@example.com").is_empty());
+        assert!(valid_passwords("alice@example.com").is_empty());
+    }
+}
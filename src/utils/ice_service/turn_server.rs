@@ -0,0 +1,509 @@
+use md5::{Digest, Md5};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket, tcp::OwnedReadHalf},
+    sync::mpsc,
+};
+
+use crate::{
+    app_state::AppState,
+    db::models::turn_usage::TurnUsage,
+    utils::{
+        hash_service::hash_generator::generate_hash,
+        ice_service::{
+            stun_message::{
+                ALLOCATE_REQUEST, ATTR_CHANNEL_NUMBER, ATTR_DATA, ATTR_LIFETIME,
+                ATTR_MESSAGE_INTEGRITY, ATTR_NONCE, ATTR_REALM, ATTR_REQUESTED_TRANSPORT,
+                ATTR_SOFTWARE, ATTR_USERNAME, ATTR_XOR_MAPPED_ADDRESS, ATTR_XOR_PEER_ADDRESS,
+                ATTR_XOR_RELAYED_ADDRESS, BINDING_REQUEST, CHANNEL_BIND_REQUEST,
+                CREATE_PERMISSION_REQUEST, HEADER_LENGTH, REFRESH_REQUEST, SEND_INDICATION,
+                SOFTWARE, StunMessage, binding_response, check_integrity, padded,
+            },
+            turn_allocation::{
+                Allocation, CHANNEL_LIFETIME, ClientKey, ClientLink, DEFAULT_DENIED_PEERS,
+                DEFAULT_LIFETIME, MAX_LIFETIME, PERMISSION_LIFETIME, RelayUsage, TurnConfig,
+                TurnState,
+            },
+            turn_credential::{credential_user, turn_secrets, valid_passwords},
+        },
+        ip_range::{IpRange, ranges_from_env},
+    },
+};
+
+const UDP_TRANSPORT: u8 = 17;
+const NONCE_LIFETIME_SECS: i64 = 60 * 60;
+// how often expired allocations are dropped and relayed bytes written out
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+// the embedded TURN relay only runs when TURN_PORT is set, on UDP and TCP
+pub fn turn_port() -> Option<u16> {
+    std::env::var("TURN_PORT").ok().and_then(|v| v.parse().ok())
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+// TURN_RELAY_IP, else the address this host routes outgoing traffic from
+async fn relay_ip() -> IpAddr {
+    if let Some(ip) = std::env::var("TURN_RELAY_IP")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        return ip;
+    }
+    // connecting a udp socket sends nothing, it only picks the outgoing interface
+    let probe = async {
+        let socket = UdpSocket::bind("0.0.0.0:0").await.ok()?;
+        socket.connect("8.8.8.8:80").await.ok()?;
+        socket.local_addr().ok().map(|addr| addr.ip())
+    };
+    probe
+        .await
+        .unwrap_or_else(|| "127.0.0.1".parse().expect("valid address"))
+}
+
+pub async fn start_turn_server(app_state: AppState) {
+    let Some(port) = turn_port() else {
+        return;
+    };
+    if turn_secrets().is_empty() {
+        eprintln!("TURN_PORT is set without TURN_SECRETS, the TURN server is not started");
+        return;
+    }
+    let config = TurnConfig {
+        realm: std::env::var("TURN_REALM").unwrap_or_else(|_| "turn".to_string()),
+        relay_ip: relay_ip().await,
+        max_allocations_per_user: env_or("TURN_MAX_ALLOCATIONS_PER_USER", 10),
+        bandwidth_bytes_per_sec: env_or::<u64>("TURN_USER_BANDWIDTH_KBPS", 10_000) * 1000 / 8,
+        denied_peers: DEFAULT_DENIED_PEERS
+            .iter()
+            .filter_map(|range| IpRange::parse(range))
+            .chain(ranges_from_env("TURN_DENIED_PEER_IPS"))
+            .collect(),
+        allowed_peers: ranges_from_env("TURN_ALLOWED_PEER_IPS"),
+    };
+    let state = Arc::new(TurnState::new(config, generate_hash()));
+
+    let udp = match UdpSocket::bind(("0.0.0.0", port)).await {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            eprintln!("Failed to bind TURN server on udp port {}: {}", port, e);
+            return;
+        }
+    };
+    eprintln!(
+        "TURN server listening on port {}, relaying from {}",
+        port, state.config.relay_ip
+    );
+    tokio::spawn(serve_udp(state.clone(), udp));
+
+    match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => {
+            tokio::spawn(serve_tcp(state.clone(), listener));
+        }
+        Err(e) => eprintln!("TURN over TCP unavailable on port {}: {}", port, e),
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            state.sweep().await;
+            for (user, usage) in state.take_usage().await {
+                if let Err(e) = TurnUsage::record(
+                    &user,
+                    usage.bytes_to_peer,
+                    usage.bytes_from_peer,
+                    usage.allocations,
+                    &app_state,
+                )
+                .await
+                {
+                    eprintln!("Failed to record TURN usage for {}: {}", user, e);
+                }
+            }
+        }
+    });
+}
+
+async fn serve_udp(state: Arc<TurnState>, socket: Arc<UdpSocket>) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (length, source) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let client = ClientKey {
+            addr: source,
+            tcp: false,
+        };
+        let link = ClientLink::Udp(socket.clone(), source);
+        handle_packet(&state, client, link, &buf[..length]).await;
+    }
+}
+
+async fn serve_tcp(state: Arc<TurnState>, listener: TcpListener) {
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(serve_tcp_connection(state.clone(), stream, addr));
+    }
+}
+
+// one allocation per connection at most, it goes away with the connection
+async fn serve_tcp_connection(state: Arc<TurnState>, stream: TcpStream, addr: SocketAddr) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(256);
+    tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let client = ClientKey { addr, tcp: true };
+    let link = ClientLink::Tcp(tx);
+    while let Some(frame) = read_frame(&mut reader).await {
+        handle_packet(&state, client, link.clone(), &frame).await;
+    }
+    state.allocations.lock().await.remove(&client);
+}
+
+// STUN messages carry their length in the header, ChannelData is padded to 4 bytes
+async fn read_frame(reader: &mut OwnedReadHalf) -> Option<Vec<u8>> {
+    let mut header = [0u8; 4];
+    reader.read_exact(&mut header).await.ok()?;
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let rest = match header[0] & 0xC0 {
+        0x00 => HEADER_LENGTH - 4 + length,
+        0x40 => padded(length),
+        _ => return None,
+    };
+    let mut frame = header.to_vec();
+    frame.resize(4 + rest, 0);
+    reader.read_exact(&mut frame[4..]).await.ok()?;
+    Some(frame)
+}
+
+async fn handle_packet(state: &Arc<TurnState>, client: ClientKey, link: ClientLink, packet: &[u8]) {
+    if packet.first().is_some_and(|b| b & 0xC0 == 0x40) {
+        relay_channel_data(state, client, packet).await;
+        return;
+    }
+    let Some(message) = StunMessage::parse(packet) else {
+        return;
+    };
+    match message.message_type {
+        BINDING_REQUEST => {
+            if let Some(response) = binding_response(packet, client.addr) {
+                link.send(response).await;
+            }
+        }
+        SEND_INDICATION => relay_send_indication(state, client, &message).await,
+        ALLOCATE_REQUEST | REFRESH_REQUEST | CREATE_PERMISSION_REQUEST | CHANNEL_BIND_REQUEST => {
+            let response = match authenticate(state, &message, packet) {
+                Ok((user, key)) => {
+                    let response = match message.message_type {
+                        ALLOCATE_REQUEST => allocate(state, client, &link, &message, user).await,
+                        REFRESH_REQUEST => refresh(state, client, &message, &user).await,
+                        CREATE_PERMISSION_REQUEST => {
+                            create_permission(state, client, &message, &user).await
+                        }
+                        _ => channel_bind(state, client, &message, &user).await,
+                    };
+                    signed(response, &key)
+                }
+                Err(response) => response,
+            };
+            link.send(response).await;
+        }
+        _ => {}
+    }
+}
+
+fn signed(mut response: StunMessage, key: &[u8]) -> Vec<u8> {
+    response.add_attribute(ATTR_SOFTWARE, SOFTWARE.as_bytes().to_vec());
+    response.encode_with_integrity(key)
+}
+
+fn issue_nonce(state: &TurnState) -> String {
+    let issued_at = chrono::Utc::now().timestamp();
+    format!("{}-{}", issued_at, nonce_mac(state, issued_at))
+}
+
+fn nonce_mac(state: &TurnState, issued_at: i64) -> String {
+    let digest = Md5::digest(format!("{}:{}", state.nonce_key, issued_at));
+    digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn nonce_is_fresh(state: &TurnState, nonce: &str) -> bool {
+    let Some((issued_at, mac)) = nonce.split_once('-') else {
+        return false;
+    };
+    let Ok(issued_at) = issued_at.parse::<i64>() else {
+        return false;
+    };
+    mac == nonce_mac(state, issued_at)
+        && issued_at + NONCE_LIFETIME_SECS > chrono::Utc::now().timestamp()
+}
+
+// 401/438 carry a fresh nonce so the client can retry right away
+fn challenge(state: &TurnState, request: &StunMessage, code: u16, reason: &str) -> Vec<u8> {
+    let mut response = request.error(code, reason);
+    response.add_attribute(ATTR_REALM, state.config.realm.as_bytes().to_vec());
+    response.add_attribute(ATTR_NONCE, issue_nonce(state).into_bytes());
+    response.add_attribute(ATTR_SOFTWARE, SOFTWARE.as_bytes().to_vec());
+    response.encode()
+}
+
+// long-term credentials: the key is MD5(username:realm:password), and the password is
+// the HMAC the signaling server handed out for this username
+fn authenticate(
+    state: &TurnState,
+    request: &StunMessage,
+    raw: &[u8],
+) -> Result<(String, Vec<u8>), Vec<u8>> {
+    if request.attribute(ATTR_MESSAGE_INTEGRITY).is_none() {
+        return Err(challenge(state, request, 401, "Unauthorized"));
+    }
+    let (Some(username), Some(_), Some(nonce)) = (
+        request.attribute_str(ATTR_USERNAME),
+        request.attribute(ATTR_REALM),
+        request.attribute_str(ATTR_NONCE),
+    ) else {
+        return Err(request.error(400, "Bad Request").encode());
+    };
+    if !nonce_is_fresh(state, nonce) {
+        return Err(challenge(state, request, 438, "Stale Nonce"));
+    }
+    let Some(user) = credential_user(username) else {
+        return Err(challenge(state, request, 401, "Unauthorized"));
+    };
+    for password in valid_passwords(username) {
+        let key = Md5::digest(format!("{}:{}:{}", username, state.config.realm, password)).to_vec();
+        if check_integrity(raw, &key) {
+            return Ok((user.to_string(), key));
+        }
+    }
+    Err(challenge(state, request, 401, "Unauthorized"))
+}
+
+fn requested_lifetime(request: &StunMessage) -> u64 {
+    request
+        .attribute(ATTR_LIFETIME)
+        .filter(|value| value.len() == 4)
+        .map(|value| u32::from_be_bytes([value[0], value[1], value[2], value[3]]) as u64)
+        .map(|lifetime| lifetime.min(MAX_LIFETIME))
+        .unwrap_or(DEFAULT_LIFETIME)
+}
+
+async fn allocate(
+    state: &Arc<TurnState>,
+    client: ClientKey,
+    link: &ClientLink,
+    request: &StunMessage,
+    user: String,
+) -> StunMessage {
+    if state.allocations.lock().await.contains_key(&client) {
+        return request.error(437, "Allocation Mismatch");
+    }
+    match request.attribute(ATTR_REQUESTED_TRANSPORT) {
+        Some(value) if value.first() == Some(&UDP_TRANSPORT) => {}
+        Some(_) => return request.error(442, "Unsupported Transport Protocol"),
+        None => return request.error(400, "Bad Request"),
+    }
+    if state.allocation_count(&user).await >= state.config.max_allocations_per_user {
+        return request.error(486, "Allocation Quota Reached");
+    }
+
+    let lifetime = requested_lifetime(request).max(DEFAULT_LIFETIME);
+    let relay_addr = match state.allocate(client, user, link.clone(), lifetime).await {
+        Ok(relay_addr) => relay_addr,
+        Err(e) => {
+            eprintln!("Failed to open a TURN relay socket: {}", e);
+            return request.error(508, "Insufficient Capacity");
+        }
+    };
+    let mut response = request.success();
+    response.add_xor_address(ATTR_XOR_RELAYED_ADDRESS, relay_addr);
+    response.add_attribute(ATTR_LIFETIME, (lifetime as u32).to_be_bytes().to_vec());
+    response.add_xor_address(ATTR_XOR_MAPPED_ADDRESS, client.addr);
+    response
+}
+
+// a lifetime of 0 deletes the allocation
+async fn refresh(
+    state: &TurnState,
+    client: ClientKey,
+    request: &StunMessage,
+    user: &str,
+) -> StunMessage {
+    let lifetime = requested_lifetime(request);
+    let mut allocations = state.allocations.lock().await;
+    match allocations.get_mut(&client) {
+        Some(allocation) if allocation.user == user => {
+            if lifetime == 0 {
+                allocations.remove(&client);
+            } else {
+                allocation.expires_at = Instant::now() + Duration::from_secs(lifetime);
+            }
+        }
+        Some(_) => return request.error(441, "Wrong Credentials"),
+        None => return request.error(437, "Allocation Mismatch"),
+    }
+    let mut response = request.success();
+    response.add_attribute(ATTR_LIFETIME, (lifetime as u32).to_be_bytes().to_vec());
+    response
+}
+
+async fn create_permission(
+    state: &TurnState,
+    client: ClientKey,
+    request: &StunMessage,
+    user: &str,
+) -> StunMessage {
+    let peers = request.xor_addresses(ATTR_XOR_PEER_ADDRESS);
+    if peers.is_empty() {
+        return request.error(400, "Bad Request");
+    }
+    if !peers
+        .iter()
+        .all(|peer| state.config.peer_allowed(peer.ip()))
+    {
+        return request.error(403, "Forbidden");
+    }
+    let mut allocations = state.allocations.lock().await;
+    let Some(allocation) = allocations.get_mut(&client) else {
+        return request.error(437, "Allocation Mismatch");
+    };
+    if allocation.user != user {
+        return request.error(441, "Wrong Credentials");
+    }
+    let until = Instant::now() + PERMISSION_LIFETIME;
+    for peer in peers {
+        allocation.permissions.insert(peer.ip(), until);
+    }
+    request.success()
+}
+
+// a channel stays bound to one peer and a peer to one channel
+async fn channel_bind(
+    state: &TurnState,
+    client: ClientKey,
+    request: &StunMessage,
+    user: &str,
+) -> StunMessage {
+    let channel = request
+        .attribute(ATTR_CHANNEL_NUMBER)
+        .filter(|value| value.len() == 4)
+        .map(|value| u16::from_be_bytes([value[0], value[1]]));
+    let (Some(channel), Some(peer)) = (channel, request.xor_address(ATTR_XOR_PEER_ADDRESS)) else {
+        return request.error(400, "Bad Request");
+    };
+    if !(0x4000..=0x7FFF).contains(&channel) {
+        return request.error(400, "Bad Request");
+    }
+    if !state.config.peer_allowed(peer.ip()) {
+        return request.error(403, "Forbidden");
+    }
+    let mut allocations = state.allocations.lock().await;
+    let Some(allocation) = allocations.get_mut(&client) else {
+        return request.error(437, "Allocation Mismatch");
+    };
+    if allocation.user != user {
+        return request.error(441, "Wrong Credentials");
+    }
+    let now = Instant::now();
+    let bound_elsewhere = allocation
+        .channel_peer(channel, now)
+        .is_some_and(|bound| bound != peer)
+        || allocation
+            .channel_for(peer, now)
+            .is_some_and(|bound| bound != channel);
+    if bound_elsewhere {
+        return request.error(400, "Bad Request");
+    }
+    allocation
+        .channels
+        .insert(channel, (peer, now + CHANNEL_LIFETIME));
+    allocation
+        .permissions
+        .insert(peer.ip(), now + PERMISSION_LIFETIME);
+    request.success()
+}
+
+// client -> peer over a Send indication; indications are never answered
+async fn relay_send_indication(state: &TurnState, client: ClientKey, message: &StunMessage) {
+    let (Some(peer), Some(data)) = (
+        message.xor_address(ATTR_XOR_PEER_ADDRESS),
+        message.attribute(ATTR_DATA),
+    ) else {
+        return;
+    };
+    if !state.config.peer_allowed(peer.ip()) {
+        return;
+    }
+    relay_to_peer(state, client, data, |allocation, now| {
+        allocation.has_permission(peer.ip(), now).then_some(peer)
+    })
+    .await;
+}
+
+// client -> peer over a bound channel
+async fn relay_channel_data(state: &TurnState, client: ClientKey, packet: &[u8]) {
+    if packet.len() < 4 {
+        return;
+    }
+    let channel = u16::from_be_bytes([packet[0], packet[1]]);
+    let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    let Some(data) = packet.get(4..4 + length) else {
+        return;
+    };
+    relay_to_peer(state, client, data, |allocation, now| {
+        allocation.channel_peer(channel, now)
+    })
+    .await;
+}
+
+async fn relay_to_peer(
+    state: &TurnState,
+    client: ClientKey,
+    data: &[u8],
+    peer_for: impl Fn(&Allocation, Instant) -> Option<SocketAddr>,
+) {
+    let (relay, user, peer) = {
+        let allocations = state.allocations.lock().await;
+        let Some(allocation) = allocations.get(&client) else {
+            return;
+        };
+        let Some(peer) = peer_for(allocation, Instant::now()) else {
+            return;
+        };
+        (allocation.relay.clone(), allocation.user.clone(), peer)
+    };
+    if !state.allow_bytes(&user, data.len()).await {
+        return;
+    }
+    if relay.send_to(data, peer).await.is_ok() {
+        state
+            .record(
+                &user,
+                RelayUsage {
+                    bytes_to_peer: data.len() as i64,
+                    ..Default::default()
+                },
+            )
+            .await;
+    }
+}
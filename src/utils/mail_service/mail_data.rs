@@ -3,6 +3,7 @@ use serde_json::Value;
 #[derive(Debug, Clone)]
 pub struct MailData {
     pub to: String,
+    #[allow(dead_code)]
     pub cc: Vec<String>,
     pub subject: String,
     pub context: Value,
@@ -22,6 +23,7 @@ impl MailData {
         }
     }
 
+    #[allow(dead_code)]
     pub fn with_html(to: String, subject: String, html: String) -> Self {
         Self {
             to,
@@ -1,5 +1,9 @@
 pub mod auth_middleware;
+pub mod client_info;
+pub mod error_status;
 pub mod hash_service;
+pub mod ice_service;
+pub mod ip_range;
 pub mod mail_service;
 pub mod resolve_base_url;
 pub mod tera_service;
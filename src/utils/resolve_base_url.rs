@@ -1,16 +1,51 @@
-use axum::http::HeaderMap;
+use std::{convert::Infallible, net::SocketAddr};
 
-pub fn resolve_base_url(headers: &HeaderMap) -> String {
-    let host = headers
-        .get("x-forwarded-host")
-        .or_else(|| headers.get("host"))
-        .and_then(|v| v.to_str().ok())
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{HeaderMap, request::Parts},
+};
+
+use crate::utils::client_info::is_trusted;
+
+// the base of links the server hands out. PUBLIC_BASE_URL when configured, which is what
+// emailed links should use; otherwise the request's host, with x-forwarded-host and
+// x-forwarded-proto believed only from TRUSTED_PROXIES
+#[derive(Debug, Clone)]
+pub struct BaseUrl(pub String);
+
+fn resolve_base_url(headers: &HeaderMap, forwarded: bool) -> String {
+    if let Ok(base_url) = std::env::var("PUBLIC_BASE_URL")
+        && !base_url.trim().is_empty()
+    {
+        return base_url.trim().trim_end_matches('/').to_string();
+    }
+
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let host = forwarded
+        .then(|| header("x-forwarded-host"))
+        .flatten()
+        .or_else(|| header("host"))
         .unwrap_or("0.0.0.0");
 
-    let scheme = headers
-        .get("x-forwarded-proto")
-        .and_then(|v| v.to_str().ok())
+    let scheme = forwarded
+        .then(|| header("x-forwarded-proto"))
+        .flatten()
         .unwrap_or("http");
 
     format!("{}://{}", scheme, host)
 }
+
+impl<S> FromRequestParts<S> for BaseUrl
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let forwarded = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|ConnectInfo(addr)| is_trusted(addr.ip()));
+        Ok(BaseUrl(resolve_base_url(&parts.headers, forwarded)))
+    }
+}